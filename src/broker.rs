@@ -0,0 +1,335 @@
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use rumqttc::v5::mqttbytes::v5::{ConnectProperties, Packet as V5Packet, Publish as V5Publish};
+use rumqttc::{v5, AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, Publish, QoS, TlsConfiguration, Transport};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use ruuvi_gateway_listener::ruuvi::metrics::Metrics;
+use ruuvi_gateway_listener::ruuvi::mqtt::{BrokerMetrics, MqttClient};
+
+use crate::config::{MqttConfig, MqttProtocol};
+use crate::http::Health;
+
+// Reconnect delays, doubled after every failed attempt
+const MIN_BACKOFF : Duration = Duration::from_secs(1);
+const MAX_BACKOFF : Duration = Duration::from_secs(30);
+
+// A publish received from one of the brokers
+pub struct Received {
+    pub site: String,
+    pub topic: String,
+    pub payload: Bytes,
+    pub received_at: SystemTime,
+}
+
+// What the broker task cares about, from either protocol version
+#[derive(Debug)]
+pub enum BrokerEvent {
+    Connected { code: String, session_present: bool },
+    Publish { topic: String, payload: Bytes, packet: PublishPacket },
+    Subscribed { return_codes: String },
+    Disconnected,
+    Other,
+}
+
+// Kept to acknowledge the publish once it has been handed on
+#[derive(Debug)]
+pub enum PublishPacket {
+    V4(Publish),
+    V5(Box<V5Publish>),
+}
+
+fn ack(client : &MqttClient, packet : &PublishPacket) -> Result<(), String> {
+    match (client, packet) {
+        (MqttClient::V4(client), PublishPacket::V4(publish)) => client.try_ack(publish).map_err(|e| e.to_string()),
+        (MqttClient::V5(client), PublishPacket::V5(publish)) => client.try_ack(publish).map_err(|e| e.to_string()),
+        _ => Err("publish from another protocol version".to_string()),
+    }
+}
+
+pub enum BrokerEventLoop {
+    V4(Box<rumqttc::EventLoop>),
+    V5(Box<v5::EventLoop>),
+}
+
+impl BrokerEventLoop {
+    pub async fn poll(&mut self) -> Result<BrokerEvent, String> {
+        match self {
+            BrokerEventLoop::V4(eventloop) => Ok(match eventloop.poll().await.map_err(|e| e.to_string())? {
+                Event::Incoming(Packet::ConnAck(connack)) => BrokerEvent::Connected { code: format!("{:?}", connack.code), session_present: connack.session_present },
+                Event::Incoming(Packet::Publish(publish)) => BrokerEvent::Publish {
+                    topic: publish.topic.clone(),
+                    payload: publish.payload.clone(),
+                    packet: PublishPacket::V4(publish),
+                },
+                Event::Incoming(Packet::SubAck(suback)) => BrokerEvent::Subscribed { return_codes: format!("{:?}", suback.return_codes) },
+                Event::Outgoing(Outgoing::Disconnect) => BrokerEvent::Disconnected,
+                _ => BrokerEvent::Other,
+            }),
+            BrokerEventLoop::V5(eventloop) => Ok(match eventloop.poll().await.map_err(|e| e.to_string())? {
+                v5::Event::Incoming(V5Packet::ConnAck(connack)) => BrokerEvent::Connected { code: format!("{:?}", connack.code), session_present: connack.session_present },
+                v5::Event::Incoming(V5Packet::Publish(publish)) => BrokerEvent::Publish {
+                    topic: String::from_utf8_lossy(&publish.topic).into_owned(),
+                    payload: publish.payload.clone(),
+                    packet: PublishPacket::V5(Box::new(publish)),
+                },
+                v5::Event::Incoming(V5Packet::SubAck(suback)) => BrokerEvent::Subscribed { return_codes: format!("{:?}", suback.return_codes) },
+                v5::Event::Outgoing(Outgoing::Disconnect) => BrokerEvent::Disconnected,
+                _ => BrokerEvent::Other,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BrokerControl {
+    Run,
+    // Unsubscribe but keep the connection for the output sinks
+    Drain,
+    Disconnect,
+}
+
+fn transport(config : &MqttConfig) -> Result<Option<Transport>, String> {
+    let Some(tls) = &config.tls else { return Ok(None) };
+    let read = |path : &str| std::fs::read(path).map_err(|e| format!("couldn't read {}: {}", path, e));
+    let client_auth = match (&tls.client_cert_file, &tls.client_key_file) {
+        (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+        (None, None) => None,
+        _ => return Err(format!("site {}: client_cert_file and client_key_file must be given together", config.site)),
+    };
+    Ok(Some(Transport::Tls(TlsConfiguration::Simple {
+        ca: read(&tls.ca_file)?,
+        alpn: None,
+        client_auth,
+    })))
+}
+
+// Creates the client and event loop for the configured protocol version.
+// Leave room in the request queue for bursts of discovery messages when new
+// tags appear. QoS 1 publishes are acknowledged by the broker task only once
+// they have been handed to the decoding loop, so that what arrives while
+// shutting down is delivered again to a persistent session. The broker
+// publishes "offline" retained to last_will_topic when the connection is lost.
+pub fn connect(config : &MqttConfig, client_id : &str, last_will_topic : Option<&str>) -> Result<(MqttClient, BrokerEventLoop), String> {
+    let transport = transport(config)?;
+    let keep_alive = Duration::from_secs(config.keep_alive_secs);
+    match config.protocol {
+        MqttProtocol::V311 => {
+            let mut options = MqttOptions::new(client_id, &config.host, config.port);
+            options.set_keep_alive(keep_alive);
+            options.set_clean_session(config.clean_session);
+            options.set_manual_acks(true);
+            if let Some(topic) = last_will_topic {
+                options.set_last_will(LastWill::new(topic, "offline", QoS::AtLeastOnce, true));
+            }
+            if let Some(username) = &config.username {
+                options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+            }
+            if let Some(transport) = transport {
+                options.set_transport(transport);
+            }
+            let (client, eventloop) = AsyncClient::new(options, 100);
+            Ok((client.into(), BrokerEventLoop::V4(Box::new(eventloop))))
+        }
+        MqttProtocol::V5 => {
+            let mut options = v5::MqttOptions::new(client_id, &config.host, config.port);
+            options.set_keep_alive(keep_alive);
+            options.set_clean_start(config.clean_session);
+            options.set_manual_acks(true);
+            if let Some(session_expiry) = config.session_expiry_secs {
+                let mut properties = ConnectProperties::new();
+                properties.session_expiry_interval = Some(session_expiry);
+                options.set_connect_properties(properties);
+            }
+            if let Some(topic) = last_will_topic {
+                options.set_last_will(v5::mqttbytes::v5::LastWill::new(topic, "offline", v5::mqttbytes::QoS::AtLeastOnce, true, None));
+            }
+            if let Some(username) = &config.username {
+                options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+            }
+            if let Some(transport) = transport {
+                options.set_transport(transport);
+            }
+            let (client, eventloop) = v5::AsyncClient::new(options, 100);
+            Ok((client.into(), BrokerEventLoop::V5(Box::new(eventloop))))
+        }
+    }
+}
+
+// Connection to one broker. Its task subscribes on every connect, forwards
+// the publishes to the shared decoding loop and reconnects with a backoff
+// when the connection fails.
+pub struct Broker {
+    pub site: String,
+    pub client: MqttClient,
+    control: watch::Sender<BrokerControl>,
+    task: JoinHandle<()>,
+}
+
+impl Broker {
+    pub fn spawn(config : &MqttConfig, output_topic_prefixes : Vec<String>, last_will_topic : Option<&str>, messages : mpsc::Sender<Received>, metrics : &Metrics, health : Health) -> Result<Self, String> {
+        let (client, eventloop) = connect(config, &config.client_id, last_will_topic)?;
+        let (control, control_rx) = watch::channel(BrokerControl::Run);
+
+        let connection = Connection {
+            site: config.site.clone(),
+            topics: config.topics(),
+            qos: config.qos(),
+            clean_session: config.clean_session,
+            output_topic_prefixes,
+            client: client.clone(),
+            messages,
+            metrics: metrics.brokers().clone(),
+            health,
+        };
+        connection.set_connected(false);
+        info!(site = %config.site, host = %config.host, port = config.port, protocol = ?config.protocol, "Connecting to MQTT broker");
+        let task = tokio::spawn(connection.run(eventloop, control_rx));
+
+        Ok(Self { site: config.site.clone(), client, control, task })
+    }
+
+    // Stops forwarding messages, the connection stays up
+    pub fn drain(&self) {
+        let _ = self.control.send(BrokerControl::Drain);
+    }
+
+    pub fn disconnect(&self) {
+        let _ = self.control.send(BrokerControl::Disconnect);
+    }
+
+    // Waits until the DISCONNECT has gone out
+    pub async fn join(self, timeout : Duration) {
+        if tokio::time::timeout(timeout, self.task).await.is_err() {
+            warn!(site = %self.site, "MQTT disconnect timed out");
+        }
+    }
+}
+
+struct Connection {
+    site: String,
+    topics: Vec<String>,
+    qos: rumqttc::QoS,
+    clean_session: bool,
+    output_topic_prefixes: Vec<String>,
+    client: MqttClient,
+    messages: mpsc::Sender<Received>,
+    metrics: BrokerMetrics,
+    health: Health,
+}
+
+impl Connection {
+    fn set_connected(&self, connected : bool) {
+        self.metrics.connected.with_label_values(&[&self.site]).set(connected as i64);
+        self.health.set_mqtt_connected(&self.site, connected);
+    }
+
+    async fn run(self, mut eventloop : BrokerEventLoop, mut control : watch::Receiver<BrokerControl>) {
+        let mut state = BrokerControl::Run;
+        let mut connected = false;
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            let wanted = *control.borrow_and_update();
+            if wanted != state {
+                match wanted {
+                    BrokerControl::Drain => {
+                        // Stop taking traffic while shutting down. A persistent
+                        // session keeps its subscriptions so that the broker
+                        // queues messages until the listener is back.
+                        self.health.set_mqtt_connected(&self.site, false);
+                        for topic in self.topics.iter().filter(|_| self.clean_session) {
+                            if let Err(e) = self.client.try_unsubscribe(topic) {
+                                warn!(site = %self.site, error = %e, "couldn't unsubscribe");
+                            }
+                        }
+                    }
+                    BrokerControl::Disconnect if !connected => return,
+                    BrokerControl::Disconnect => {
+                        if let Err(e) = self.client.try_disconnect() {
+                            warn!(site = %self.site, error = %e, "couldn't disconnect from MQTT broker");
+                            return;
+                        }
+                    }
+                    BrokerControl::Run => {}
+                }
+                state = wanted;
+            }
+
+            let event = tokio::select! {
+                event = eventloop.poll() => event,
+                changed = control.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+
+            match event {
+                Ok(BrokerEvent::Connected { code, session_present }) => {
+                    info!(site = %self.site, %code, session_present, "Connection acknowledged");
+                    connected = true;
+                    backoff = MIN_BACKOFF;
+                    self.metrics.connects.with_label_values(&[&self.site]).inc();
+                    if state == BrokerControl::Run {
+                        self.set_connected(true);
+                        // Clean sessions lose their subscriptions on reconnect,
+                        // subscribing again to a kept session is harmless
+                        for topic in &self.topics {
+                            if let Err(e) = self.client.try_subscribe(topic, self.qos) {
+                                error!(site = %self.site, %topic, error = %e, "couldn't subscribe");
+                            }
+                        }
+                    }
+                }
+                Ok(BrokerEvent::Publish { topic, payload, packet }) => {
+                    // Left unacknowledged for redelivery
+                    if state != BrokerControl::Run {
+                        continue;
+                    }
+                    if !self.output_topic_prefixes.iter().any(|prefix| topic.starts_with(prefix)) {
+                        self.metrics.received.with_label_values(&[&self.site]).inc();
+                        let received = Received { site: self.site.clone(), topic, payload, received_at: SystemTime::now() };
+                        // Not waited for, the connection has to keep being
+                        // polled for its keep-alive
+                        match self.messages.try_send(received) {
+                            Ok(()) => {}
+                            Err(mpsc::error::TrySendError::Full(_)) => {
+                                self.metrics.dropped.with_label_values(&[&self.site]).inc();
+                            }
+                            // Only once the decoding loop has stopped
+                            Err(mpsc::error::TrySendError::Closed(_)) => continue,
+                        }
+                    }
+                    if let Err(e) = ack(&self.client, &packet) {
+                        warn!(site = %self.site, error = %e, "couldn't acknowledge publish");
+                    }
+                }
+                Ok(BrokerEvent::Subscribed { return_codes }) => {
+                    info!(site = %self.site, %return_codes, "Subscription acknowledged");
+                }
+                Ok(BrokerEvent::Disconnected) => return,
+                Ok(BrokerEvent::Other) => {}
+                Err(e) => {
+                    if state == BrokerControl::Disconnect {
+                        return;
+                    }
+                    error!(site = %self.site, error = %e, retry_in = ?backoff, "MQTT connection error");
+                    connected = false;
+                    self.set_connected(false);
+                    self.metrics.errors.with_label_values(&[&self.site]).inc();
+                    // Shutting down doesn't wait for the backoff
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = control.changed() => {}
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
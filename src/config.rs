@@ -0,0 +1,658 @@
+use rumqttc::QoS;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use ruuvi_gateway_listener::ruuvi::alerts::AlertsConfig;
+use ruuvi_gateway_listener::ruuvi::homeassistant::HomeAssistantConfig;
+use ruuvi_gateway_listener::ruuvi::influxdb::InfluxDbConfig;
+use ruuvi_gateway_listener::ruuvi::mqtt::MqttOutputConfig;
+use ruuvi_gateway_listener::ruuvi::pipeline::OverflowPolicy;
+use ruuvi_gateway_listener::ruuvi::sqlite::SqliteConfig;
+
+// Listener configuration, read from a TOML file. Every value has a default so
+// the listener also starts without any configuration file.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    // The broker to listen to, unless brokers are configured
+    pub mqtt: MqttConfig,
+    // Several brokers feeding the same sinks, each with its own site
+    pub brokers: Vec<MqttConfig>,
+    pub http: HttpConfig,
+    pub log: LogConfig,
+    pub shutdown: ShutdownConfig,
+    pub dashboard: DashboardConfig,
+    pub clock: ClockConfig,
+    pub state: StateConfig,
+    pub encryption: EncryptionConfig,
+    pub sinks: Vec<SinkConfig>,
+
+    // Human readable tag names keyed by tag MAC
+    pub tags: HashMap<String, String>,
+}
+
+// Site of a broker configured without one
+pub const DEFAULT_SITE : &str = "default";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MqttConfig {
+    // Label of the broker in metrics and measurements
+    pub site: String,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub topic: String,
+    // Further topic filters in addition to topic
+    pub topics: Vec<String>,
+    pub keep_alive_secs: u64,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<TlsConfig>,
+    pub protocol: MqttProtocol,
+    // Listeners in the same group share the subscriptions, the broker hands
+    // every message to one of them
+    pub shared_group: Option<String>,
+    // QoS of the subscriptions
+    pub qos: u8,
+    // With false the broker keeps the subscriptions and queues QoS 1
+    // messages while the listener is disconnected
+    pub clean_session: bool,
+    // MQTT 5 only, how long the broker keeps the session after a disconnect
+    pub session_expiry_secs: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum MqttProtocol {
+    #[default]
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+impl std::default::Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            site: DEFAULT_SITE.to_string(),
+            host: "mqtt.juhonkoti.net".to_string(),
+            port: 1883,
+            client_id: "rumqtt-async".to_string(),
+            topic: "ruuvi/#".to_string(),
+            topics: Vec::new(),
+            keep_alive_secs: 5,
+            username: None,
+            password: None,
+            tls: None,
+            protocol: MqttProtocol::V311,
+            shared_group: None,
+            qos: 0,
+            clean_session: true,
+            session_expiry_secs: None,
+        }
+    }
+}
+
+impl MqttConfig {
+    // Topic filters to subscribe, as shared subscriptions with a group
+    pub fn topics(&self) -> Vec<String> {
+        std::iter::once(&self.topic)
+            .chain(self.topics.iter())
+            .filter(|topic| !topic.is_empty())
+            .map(|topic| match &self.shared_group {
+                Some(group) => format!("$share/{}/{}", group, topic),
+                None => topic.clone(),
+            })
+            .collect()
+    }
+
+    pub fn qos(&self) -> QoS {
+        match self.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.qos > 2 {
+            return Err(format!("site {}: qos must be 0, 1 or 2", self.site));
+        }
+        if self.protocol == MqttProtocol::V5 && self.keep_alive_secs < 5 {
+            return Err(format!("site {}: keep_alive_secs must be at least 5 with MQTT 5", self.site));
+        }
+        if self.session_expiry_secs.is_some() && self.protocol != MqttProtocol::V5 {
+            return Err(format!("site {}: session_expiry_secs needs protocol = \"5\"", self.site));
+        }
+        if let Some(group) = &self.shared_group {
+            if group.is_empty() || group.contains(['/', '+', '#']) {
+                return Err(format!("site {}: invalid shared_group {:?}", self.site, group));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    // PEM file with the CA certificates to trust
+    pub ca_file: String,
+    // PEM files for client certificate authentication
+    pub client_cert_file: Option<String>,
+    pub client_key_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub listen: SocketAddr,
+    // /readyz fails when no gateway message has arrived for this long
+    pub ready_max_age_secs: u64,
+    // Measurements buffered per /api/stream client before a slow client is
+    // disconnected
+    pub stream_buffer: usize,
+}
+
+impl std::default::Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            listen: ([0, 0, 0, 0], 9898).into(),
+            ready_max_age_secs: 300,
+            stream_buffer: 100,
+        }
+    }
+}
+
+impl HttpConfig {
+    pub fn ready_max_age(&self) -> Duration {
+        Duration::from_secs(self.ready_max_age_secs)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DashboardConfig {
+    // Serve the dashboard at /, otherwise / lists the endpoints
+    pub enabled: bool,
+    // Sparklines show up to recent_samples readings per tag, one every
+    // sample_interval_secs
+    pub recent_samples: usize,
+    pub sample_interval_secs: u64,
+}
+
+impl std::default::Default for DashboardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            recent_samples: 60,
+            sample_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ClockConfig {
+    // Remove the estimated gateway clock offset from the gateway timestamps
+    // the sinks use
+    pub correct_timestamps: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StateConfig {
+    // File keeping the latest state of every tag across restarts, none
+    // keeps it only in memory
+    pub snapshot_path: Option<String>,
+    pub snapshot_interval_secs: u64,
+}
+
+impl std::default::Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            snapshot_path: None,
+            snapshot_interval_secs: 60,
+        }
+    }
+}
+
+impl StateConfig {
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_secs(self.snapshot_interval_secs)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    // Keys of tags sending encrypted data format 8, one "MAC key" pair per
+    // line. Only the owner may read it.
+    pub key_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    // tracing-subscriber filter directives such as "info,ruuvi_gateway_listener=debug",
+    // RUST_LOG overrides this when set
+    pub filter: String,
+    pub format: LogFormat,
+}
+
+impl std::default::Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    // How long each shutdown step, such as draining the sink queues, may take
+    pub timeout_secs: u64,
+}
+
+impl std::default::Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SinkConfig {
+    // Name used in the per-sink metrics, defaults to the sink type
+    pub name: Option<String>,
+
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+
+    #[serde(flatten)]
+    pub kind: SinkKind,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkKind {
+    Prometheus,
+    Influxdb(Box<InfluxDbConfig>),
+    Mqtt(MqttOutputConfig),
+    Homeassistant(HomeAssistantConfig),
+    Sqlite(SqliteConfig),
+    Alerts(AlertsConfig),
+}
+
+impl SinkKind {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            SinkKind::Prometheus => "prometheus",
+            SinkKind::Influxdb(_) => "influxdb",
+            SinkKind::Mqtt(_) => "mqtt",
+            SinkKind::Homeassistant(_) => "homeassistant",
+            SinkKind::Sqlite(_) => "sqlite",
+            SinkKind::Alerts(_) => "alerts",
+        }
+    }
+}
+
+impl SinkConfig {
+    pub fn name(&self) -> &str {
+        match &self.name {
+            Some(name) => name,
+            None => self.kind.type_name(),
+        }
+    }
+}
+
+fn default_queue_size() -> usize {
+    1000
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path, e))?;
+        Config::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let config : Config = toml::from_str(contents).map_err(|e| format!("invalid configuration: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Checks which serde can't express. Brokers are checked by brokers().
+    fn validate(&self) -> Result<(), String> {
+        for sink in &self.sinks {
+            if let SinkKind::Mqtt(mqtt) = &sink.kind {
+                mqtt.validate().map_err(|e| format!("sink {}: {}", sink.name(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Brokers to listen to, the [mqtt] section unless [[brokers]] are given.
+    // Site names must be unique.
+    pub fn brokers(&self) -> Result<Vec<MqttConfig>, String> {
+        if self.brokers.is_empty() {
+            self.mqtt.validate()?;
+            return Ok(vec![self.mqtt.clone()]);
+        }
+        let mut sites = std::collections::HashSet::new();
+        for broker in &self.brokers {
+            if !sites.insert(broker.site.as_str()) {
+                return Err(format!("site {} is configured for more than one broker", broker.site));
+            }
+            broker.validate()?;
+        }
+        Ok(self.brokers.clone())
+    }
+
+    // Sinks to run. Without any configured sinks we keep the old behaviour
+    // of only exporting measurements to Prometheus.
+    pub fn sinks(&self) -> Vec<SinkConfig> {
+        if self.sinks.is_empty() {
+            return vec![SinkConfig {
+                name: None,
+                queue_size: default_queue_size(),
+                overflow: OverflowPolicy::default(),
+                kind: SinkKind::Prometheus,
+            }];
+        }
+        self.sinks.clone()
+    }
+
+    // Topic prefixes the listener publishes to itself, which must not be
+    // decoded as gateway messages
+    pub fn output_topic_prefixes(&self) -> Vec<String> {
+        let mut prefixes : Vec<String> = Vec::new();
+        let sink_prefixes = self.sinks.iter()
+            .flat_map(|sink| match &sink.kind {
+                SinkKind::Mqtt(mqtt) => vec![mqtt.topic_prefix().to_string()],
+                SinkKind::Homeassistant(homeassistant) => homeassistant.topic_prefixes(),
+                _ => vec![],
+            })
+            .filter(|prefix| !prefix.is_empty());
+        for prefix in sink_prefixes {
+            if !prefixes.contains(&prefix) {
+                prefixes.push(prefix);
+            }
+        }
+        prefixes
+    }
+
+    // Topic the first broker connection sets as its last will, so that Home
+    // Assistant marks every tag unavailable when the listener dies
+    pub fn last_will_topic(&self) -> Option<String> {
+        self.sinks.iter().find_map(|sink| match &sink.kind {
+            SinkKind::Homeassistant(homeassistant) => Some(homeassistant.listener_availability_topic.clone()),
+            _ => None,
+        })
+    }
+
+    // Database served by the history API, the first configured SQLite sink
+    pub fn history_path(&self) -> Option<&str> {
+        self.sinks.iter().find_map(|sink| match &sink.kind {
+            SinkKind::Sqlite(sqlite) => Some(sqlite.path.as_str()),
+            _ => None,
+        })
+    }
+
+    // Tag names with the MACs normalized to upper case
+    pub fn tag_names(&self) -> HashMap<String, String> {
+        self.tags.iter()
+            .map(|(mac, name)| (mac.to_uppercase(), name.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let config = Config::parse("").unwrap();
+
+        assert_eq!("mqtt.juhonkoti.net", config.mqtt.host);
+        assert_eq!(9898, config.http.listen.port());
+        assert_eq!(Duration::from_secs(300), config.http.ready_max_age());
+        assert_eq!(1, config.sinks().len());
+        assert_eq!("prometheus", config.sinks()[0].name());
+        assert_eq!("info", config.log.filter);
+        assert_eq!(LogFormat::Text, config.log.format);
+        assert_eq!(Duration::from_secs(10), config.shutdown.timeout());
+        assert!(config.dashboard.enabled);
+        assert!(!config.clock.correct_timestamps);
+        assert_eq!(60, config.dashboard.recent_samples);
+        assert_eq!(None, config.state.snapshot_path);
+    }
+
+    #[test]
+    fn test_broker_config() {
+        let config = Config::parse("").unwrap();
+        let brokers = config.brokers().unwrap();
+        assert_eq!(1, brokers.len());
+        assert_eq!("default", brokers[0].site);
+        assert_eq!(vec!["ruuvi/#".to_string()], brokers[0].topics());
+
+        let config = Config::parse(r#"
+            [[brokers]]
+            site = "helsinki"
+            host = "mqtt.helsinki.example.com"
+            port = 8883
+            username = "listener"
+            password = "secret"
+            topics = ["gateways/+/ruuvi/#"]
+
+            [brokers.tls]
+            ca_file = "/etc/ssl/certs/helsinki-ca.pem"
+
+            [[brokers]]
+            site = "tampere"
+            host = "mqtt.tampere.example.com"
+            topic = ""
+            topics = ["a/#", "b/#"]
+        "#).unwrap();
+        let brokers = config.brokers().unwrap();
+        assert_eq!(2, brokers.len());
+        assert_eq!(vec!["ruuvi/#".to_string(), "gateways/+/ruuvi/#".to_string()], brokers[0].topics());
+        assert_eq!("/etc/ssl/certs/helsinki-ca.pem", brokers[0].tls.as_ref().unwrap().ca_file);
+        assert_eq!(Some("listener".to_string()), brokers[0].username);
+        assert_eq!(vec!["a/#".to_string(), "b/#".to_string()], brokers[1].topics());
+        assert_eq!(1883, brokers[1].port);
+
+        let config = Config::parse(r#"
+            [[brokers]]
+            host = "a"
+            [[brokers]]
+            host = "b"
+        "#).unwrap();
+        assert_eq!("site default is configured for more than one broker", config.brokers().unwrap_err());
+    }
+
+    #[test]
+    fn test_shared_subscription_config() {
+        let config = Config::parse(r#"
+            [mqtt]
+            protocol = "5"
+            shared_group = "listeners"
+            topics = ["gateways/#"]
+            qos = 1
+            clean_session = false
+            session_expiry_secs = 3600
+        "#).unwrap();
+        let brokers = config.brokers().unwrap();
+        assert_eq!(MqttProtocol::V5, brokers[0].protocol);
+        assert_eq!(vec!["$share/listeners/ruuvi/#".to_string(), "$share/listeners/gateways/#".to_string()], brokers[0].topics());
+        assert_eq!(QoS::AtLeastOnce, brokers[0].qos());
+
+        let config = Config::parse("[mqtt]\nsession_expiry_secs = 3600").unwrap();
+        assert_eq!("site default: session_expiry_secs needs protocol = \"5\"", config.brokers().unwrap_err());
+        let config = Config::parse("[mqtt]\nshared_group = \"a/b\"").unwrap();
+        assert!(config.brokers().is_err());
+    }
+
+    #[test]
+    fn test_log_config() {
+        let config = Config::parse(r#"
+            [log]
+            filter = "warn,ruuvi_gateway_listener=debug"
+            format = "json"
+        "#).unwrap();
+
+        assert_eq!("warn,ruuvi_gateway_listener=debug", config.log.filter);
+        assert_eq!(LogFormat::Json, config.log.format);
+    }
+
+    #[test]
+    fn test_sink_config() {
+        let config = Config::parse(r#"
+            [mqtt]
+            host = "localhost"
+
+            [[sinks]]
+            type = "prometheus"
+            name = "metrics"
+            queue_size = 10
+            overflow = "block"
+        "#).unwrap();
+
+        assert_eq!("localhost", config.mqtt.host);
+        assert_eq!(1883, config.mqtt.port);
+
+        let sinks = config.sinks();
+        assert_eq!(1, sinks.len());
+        assert_eq!("metrics", sinks[0].name());
+        assert_eq!(10, sinks[0].queue_size);
+        assert_eq!(OverflowPolicy::Block, sinks[0].overflow);
+    }
+
+    #[test]
+    fn test_influxdb_sink_config() {
+        let config = Config::parse(r#"
+            [tags]
+            "aa:bb:cc:dd:ee:ff" = "Freezer"
+
+            [[sinks]]
+            type = "influxdb"
+            url = "http://localhost:8086"
+            database = "ruuvi"
+        "#).unwrap();
+
+        let sinks = config.sinks();
+        assert_eq!("influxdb", sinks[0].name());
+        match &sinks[0].kind {
+            SinkKind::Influxdb(influxdb) => {
+                assert_eq!("ruuvi", influxdb.measurement);
+                assert_eq!(Some("ruuvi".to_string()), influxdb.database);
+            }
+            _ => panic!("expected an influxdb sink"),
+        }
+        assert_eq!(Some(&"Freezer".to_string()), config.tag_names().get("AA:BB:CC:DD:EE:FF"));
+    }
+
+    #[test]
+    fn test_mqtt_sink_config() {
+        let config = Config::parse(r#"
+            [[sinks]]
+            type = "mqtt"
+            topic = "decoded/{gateway_mac}/{tag_mac}"
+            retain = true
+        "#).unwrap();
+
+        assert_eq!("mqtt", config.sinks()[0].name());
+        assert_eq!(vec!["decoded/".to_string()], config.output_topic_prefixes());
+        assert_eq!(None, config.last_will_topic());
+
+        let error = Config::parse("[[sinks]]\ntype = \"mqtt\"\nqos = 3").unwrap_err();
+        assert_eq!("sink mqtt: qos must be 0, 1 or 2, not 3", error);
+    }
+
+    #[test]
+    fn test_sqlite_sink_config() {
+        let config = Config::parse(r#"
+            [[sinks]]
+            type = "sqlite"
+            path = "/var/lib/ruuvi/history.db"
+            raw_retention_days = 30
+        "#).unwrap();
+
+        assert_eq!("sqlite", config.sinks()[0].name());
+        assert_eq!(Some("/var/lib/ruuvi/history.db"), config.history_path());
+        match &config.sinks()[0].kind {
+            SinkKind::Sqlite(sqlite) => {
+                assert_eq!(30, sqlite.raw_retention_days);
+                assert_eq!(3600, sqlite.downsample_interval_secs);
+            }
+            _ => panic!("expected a sqlite sink"),
+        }
+    }
+
+    #[test]
+    fn test_alerts_sink_config() {
+        let config = Config::parse(r#"
+            [[sinks]]
+            type = "alerts"
+            webhook_url = "http://localhost:8080/hooks/ruuvi"
+
+            [[sinks.rules]]
+            name = "freezer warm"
+            tags = ["AA:BB:CC:DD:EE:FF"]
+            condition = "temperature_above"
+            threshold = -15.0
+            hysteresis = 1.0
+            for_secs = 300
+
+            [[sinks.rules]]
+            name = "tag lost"
+            condition = "not_seen"
+            timeout_secs = 900
+        "#).unwrap();
+
+        match &config.sinks()[0].kind {
+            SinkKind::Alerts(alerts) => {
+                assert_eq!(2, alerts.rules.len());
+                assert_eq!(300, alerts.rules[0].for_secs);
+                assert_eq!(10, alerts.check_interval_secs);
+            }
+            _ => panic!("expected an alerts sink"),
+        }
+    }
+
+    #[test]
+    fn test_homeassistant_sink_config() {
+        let config = Config::parse(r#"
+            [[sinks]]
+            type = "homeassistant"
+            stale_after_secs = 60
+        "#).unwrap();
+
+        assert_eq!("homeassistant", config.sinks()[0].name());
+        assert_eq!(vec!["homeassistant/".to_string(), "ruuvi/decoded/".to_string(), "ruuvi/listener/availability".to_string()], config.output_topic_prefixes());
+        assert_eq!(Some("ruuvi/listener/availability".to_string()), config.last_will_topic());
+    }
+}
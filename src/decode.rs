@@ -0,0 +1,142 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use ruuvi_gateway_listener::ruuvi::parser::{decode_hex, format_mac, parse_ad_structures, try_decode_ble_ruuvi, RuuviData, RuuviMeasurement, RuuviSink};
+
+#[derive(Serialize, Debug)]
+pub struct AdStructureReport {
+    pub ad_type: u8,
+    pub type_name: &'static str,
+    pub data: String,
+}
+
+// Everything the decode subcommand knows about one hex advertisement
+#[derive(Serialize, Debug)]
+pub struct DecodeReport {
+    pub input: String,
+    pub ad_structures: Vec<AdStructureReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub measurement: Option<RuuviData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+struct ReportSink {
+    measurement: Option<RuuviData>,
+}
+
+impl RuuviSink for ReportSink {
+    fn sink(&mut self, _source_mac : &str, measurement : RuuviData) {
+        self.measurement = Some(measurement);
+    }
+}
+
+fn to_hex(data : &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+// Accepts "0201..", "02 01 .." and "0x0201.."
+pub fn decode_report(input : &str) -> DecodeReport {
+    let cleaned : String = input.split_whitespace().collect();
+    let cleaned = cleaned.strip_prefix("0x").unwrap_or(&cleaned);
+
+    let mut report = DecodeReport {
+        input: cleaned.to_uppercase(),
+        ad_structures: Vec::new(),
+        measurement: None,
+        error: None,
+    };
+
+    let buf = match decode_hex(cleaned) {
+        Ok(buf) => buf,
+        Err(e) => {
+            report.error = Some(e.to_string());
+            return report;
+        }
+    };
+
+    report.ad_structures = parse_ad_structures(&buf)
+        .iter()
+        .map(|structure| AdStructureReport {
+            ad_type: structure.ad_type,
+            type_name: structure.type_name(),
+            data: to_hex(&structure.data),
+        })
+        .collect();
+
+    let mut sink = ReportSink { measurement: None };
+    match try_decode_ble_ruuvi(&buf, "", &mut sink) {
+        Ok(()) => report.measurement = sink.measurement,
+        Err(e) => report.error = Some(e.to_string()),
+    }
+
+    report
+}
+
+pub fn format_table(report : &DecodeReport) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", report.input);
+
+    for structure in &report.ad_structures {
+        let _ = writeln!(out, "  AD {:02X} {:<28} {}", structure.ad_type, structure.type_name, structure.data);
+    }
+
+    if let Some(data) = &report.measurement {
+        for (field, value) in data.fields() {
+            let _ = writeln!(out, "  {:<24} {}", field, value);
+        }
+        if let Some(mac) = data.mac() {
+            let _ = writeln!(out, "  {:<24} {}", "mac", format_mac(&mac));
+        }
+    }
+
+    if let Some(error) = &report.error {
+        let _ = writeln!(out, "  error: {}", error);
+    }
+
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_report() {
+        let report = decode_report("02 01 06 1B FF 99 04 05 12 FC 53 94 C3 7C 00 04 FF FC 04 0C AC 36 42 00 CD CB B8 33 4C 88 4F");
+
+        assert_eq!(2, report.ad_structures.len());
+        assert_eq!("Flags", report.ad_structures[0].type_name);
+        assert_eq!(5, report.measurement.as_ref().unwrap().format());
+        assert!(report.error.is_none());
+
+        let table = format_table(&report);
+        assert!(table.contains("temperature              24.3\n"));
+        assert!(table.contains("measurement_sequence     205"));
+        assert!(table.contains("mac                      CB:B8:33:4C:88:4F"));
+
+        // Format 3 has no sequence number or MAC
+        let table = format_table(&decode_report("02010611FF9904035D1929C6670029FFEA041B0B6B"));
+        assert!(table.contains("temperature              25.41\n"));
+        assert!(!table.contains("measurement_sequence"));
+        assert!(!table.contains("mac"));
+    }
+
+    #[test]
+    fn test_decode_report_explains_failure() {
+        let report = decode_report("0x0201061AFF4C000215");
+
+        // The manufacturer data is truncated, only the flags are listed
+        assert_eq!(1, report.ad_structures.len());
+        assert!(report.measurement.is_none());
+        assert_eq!("manufacturer id was not for Ruuvi Ltd's 0x0499 but 0x004C", report.error.as_ref().unwrap());
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!("0201061AFF4C000215", json["input"]);
+        assert!(json.get("measurement").is_none());
+
+        assert_eq!("invalid hex: \"xyzw\"", decode_report("xyzw").error.unwrap());
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, TextEncoder};
+use serde::Serialize;
+
+use ruuvi_gateway_listener::ruuvi;
+use ruuvi_gateway_listener::ruuvi::live::LiveStream;
+use ruuvi_gateway_listener::ruuvi::metrics::{register, Metrics};
+use ruuvi_gateway_listener::ruuvi::sqlite::SqliteHistory;
+use ruuvi_gateway_listener::ruuvi::state::TagStore;
+use ruuvi_gateway_listener::ruuvi::url::percent_decode;
+
+use crate::config::Config;
+
+const VERSION : &str = env!("CARGO_PKG_VERSION");
+const DASHBOARD : &str = include_str!("dashboard.html");
+
+// What the readiness endpoint reports, updated from the broker connections
+#[derive(Clone, Default)]
+pub struct Health {
+    inner: Arc<HealthInner>,
+}
+
+#[derive(Default)]
+struct HealthInner {
+    // Connection state by broker site
+    brokers: Mutex<BTreeMap<String, bool>>,
+    last_message: Mutex<Option<Instant>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    // True while every broker is connected
+    pub mqtt_connected: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub brokers: BTreeMap<String, bool>,
+    // Seconds since the last gateway message, missing before the first one
+    pub last_message_age_secs: Option<f64>,
+}
+
+impl Health {
+    pub fn set_mqtt_connected(&self, site : &str, connected : bool) {
+        self.inner.brokers.lock().unwrap().insert(site.to_string(), connected);
+    }
+
+    pub fn message_received(&self) {
+        *self.inner.last_message.lock().unwrap() = Some(Instant::now());
+    }
+
+    // Ready while connected to every broker and gateway messages keep arriving
+    pub fn readiness(&self, max_age : Duration) -> Readiness {
+        let brokers = self.inner.brokers.lock().unwrap().clone();
+        let mqtt_connected = !brokers.is_empty() && brokers.values().all(|connected| *connected);
+        let age = self.inner.last_message.lock().unwrap().map(|received| received.elapsed());
+        Readiness {
+            ready: mqtt_connected && age.is_some_and(|age| age <= max_age),
+            mqtt_connected,
+            brokers,
+            last_message_age_secs: age.map(|age| age.as_secs_f64()),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct HttpMetrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+}
+
+// Shared by all HTTP connections
+#[derive(Clone)]
+pub struct HttpState {
+    metrics: Metrics,
+    http_metrics: HttpMetrics,
+    history: Option<SqliteHistory>,
+    tags: TagStore,
+    stream: LiveStream,
+    health: Health,
+    ready_max_age: Duration,
+    dashboard: bool,
+}
+
+impl HttpState {
+    pub fn new(config : &Config, metrics : Metrics, history : Option<SqliteHistory>, tags : TagStore, stream : LiveStream, health : Health) -> Self {
+        let registry = metrics.registry();
+        let http_metrics = HttpMetrics {
+            requests: register(registry, IntCounterVec::new(Opts::new(
+                "ruuvi_http_requests_total",
+                "Number of HTTP requests made."),
+                &["handler", "status"]).unwrap()).unwrap(),
+            duration: register(registry, HistogramVec::new(HistogramOpts::new(
+                "ruuvi_http_request_duration_seconds",
+                "Time spent answering HTTP requests."),
+                &["handler"]).unwrap()).unwrap(),
+        };
+        let build_info = register(registry, IntGauge::with_opts(Opts::new(
+            "ruuvi_build_info",
+            "Always 1, labeled with the listener version.")
+            .const_label("version", VERSION)).unwrap()).unwrap();
+        build_info.set(1);
+
+        Self {
+            metrics,
+            http_metrics,
+            history,
+            tags,
+            stream,
+            health,
+            ready_max_age: config.http.ready_max_age(),
+            dashboard: config.dashboard.enabled,
+        }
+    }
+}
+
+pub async fn serve_req(req : Request<Body>, state : HttpState) -> Result<Response<Body>, hyper::Error> {
+    let start = Instant::now();
+    let (handler, response) = route(&req, &state).await;
+
+    state.http_metrics.requests.with_label_values(&[handler, response.status().as_str()]).inc();
+    state.http_metrics.duration.with_label_values(&[handler]).observe(start.elapsed().as_secs_f64());
+    Ok(response)
+}
+
+// Returns the handler name used in the metrics together with the response.
+// Unknown paths share one handler name so that scanners can't create new
+// label values.
+async fn route(req : &Request<Body>, state : &HttpState) -> (&'static str, Response<Body>) {
+    let path = req.uri().path();
+
+    if path.starts_with("/api/history/") {
+        return match (&state.history, req.method()) {
+            (Some(history), &Method::GET) => ("history", ruuvi::sqlite::serve_history(history.clone(), req).await),
+            (Some(_), _) => ("history", method_not_allowed()),
+            (None, _) => ("not_found", text(StatusCode::NOT_FOUND, "history is not enabled, configure a sqlite sink\n")),
+        };
+    }
+
+    if path == "/api/tags" || path.starts_with("/api/tags/") {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return ("tags", method_not_allowed());
+        }
+        return ("tags", serve_tags(&state.tags, path, req.uri().query()));
+    }
+
+    if path == "/api/stream" {
+        if req.method() != Method::GET {
+            return ("stream", method_not_allowed());
+        }
+        return ("stream", state.stream.serve(req));
+    }
+
+    let handler = match path {
+        "/" => "index",
+        "/metrics" => "metrics",
+        "/healthz" => "healthz",
+        "/readyz" => "readyz",
+        "/version" => "version",
+        _ => return ("not_found", text(StatusCode::NOT_FOUND, "not found\n")),
+    };
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return (handler, method_not_allowed());
+    }
+
+    let response = match handler {
+        "index" if state.dashboard => Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(DASHBOARD))
+            .unwrap(),
+        "index" => text(StatusCode::OK, "Ruuvi Gateway listener\n\n/metrics\n/healthz\n/readyz\n/version\n/api/tags\n/api/stream\n"),
+        "metrics" => serve_metrics(state),
+        "healthz" => text(StatusCode::OK, "ok\n"),
+        "readyz" => {
+            let readiness = state.health.readiness(state.ready_max_age);
+            let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+            json(status, &readiness)
+        }
+        _ => json(StatusCode::OK, &serde_json::json!({
+            "name": env!("CARGO_PKG_NAME"),
+            "version": VERSION,
+        })),
+    };
+    (handler, response)
+}
+
+fn serve_metrics(state : &HttpState) -> Response<Body> {
+    let encoder = TextEncoder::new();
+    let metric_families = state.metrics.registry().gather();
+    let mut buffer = vec![];
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap()
+}
+
+// GET /api/tags lists the latest state of every tag, /api/tags/{mac} one tag.
+// With ?recent=1 the list includes the recent readings of each tag.
+fn serve_tags(tags : &TagStore, path : &str, query : Option<&str>) -> Response<Body> {
+    match path.trim_start_matches("/api/tags").trim_start_matches('/') {
+        "" if query.is_some_and(|query| query.split('&').any(|pair| pair == "recent=1")) => json(StatusCode::OK, &tags.tags_with_recent()),
+        "" => json(StatusCode::OK, &tags.tags()),
+        mac => match tags.tag(&percent_decode(mac)) {
+            Some(tag) => json(StatusCode::OK, &tag),
+            None => text(StatusCode::NOT_FOUND, "tag not seen\n"),
+        },
+    }
+}
+
+fn text(status : StatusCode, body : &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn json<T : Serialize>(status : StatusCode, value : &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+fn method_not_allowed() -> Response<Body> {
+    text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use ruuvi_gateway_listener::ruuvi::gateway::RuuviGatewayMessage;
+    use ruuvi_gateway_listener::ruuvi::parser::{DataFormat5, RuuviData};
+
+    fn state() -> HttpState {
+        let metrics = Metrics::default();
+        let stream = LiveStream::new(10, &metrics);
+        let mut config = Config::default();
+        config.http.ready_max_age_secs = 60;
+        HttpState::new(&config, metrics, None, TagStore::new(HashMap::new(), 10, 60), stream, Health::default())
+    }
+
+    async fn get(state : &HttpState, path : &str) -> (StatusCode, String) {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        let response = serve_req(req, state.clone()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_routing_and_request_metrics() {
+        let state = state();
+
+        assert_eq!(StatusCode::OK, get(&state, "/healthz").await.0);
+        assert!(get(&state, "/").await.1.contains("<title>Ruuvi tags</title>"));
+        assert_eq!(StatusCode::NOT_FOUND, get(&state, "/favicon.ico").await.0);
+        assert_eq!(StatusCode::NOT_FOUND, get(&state, "/api/history/AA:BB:CC:DD:EE:FF").await.0);
+
+        let version : serde_json::Value = serde_json::from_str(&get(&state, "/version").await.1).unwrap();
+        assert_eq!(VERSION, version["version"]);
+
+        // The event stream only ends on shutdown
+        let req = Request::get("/api/stream").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::OK, serve_req(req, state.clone()).await.unwrap().status());
+        state.stream.close();
+
+        let req = Request::post("/metrics").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, serve_req(req, state.clone()).await.unwrap().status());
+
+        let (status, metrics) = get(&state, "/metrics").await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(metrics.contains("ruuvi_http_requests_total{handler=\"healthz\",status=\"200\"} 1"));
+        assert!(metrics.contains("ruuvi_http_requests_total{handler=\"not_found\",status=\"404\"} 2"));
+        assert!(metrics.contains("ruuvi_http_requests_total{handler=\"metrics\",status=\"405\"} 1"));
+        assert!(metrics.contains(&format!("ruuvi_build_info{{version=\"{}\"}} 1", VERSION)));
+    }
+
+    #[tokio::test]
+    async fn test_tags_api() {
+        let state = state();
+        assert_eq!((StatusCode::OK, "[]".to_string()), get(&state, "/api/tags").await);
+
+        let message = RuuviGatewayMessage {
+            mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            gateway_mac: "11:22:33:44:55:66".to_string(),
+            rssi: -70,
+            ..Default::default()
+        };
+        state.tags.update(&message, RuuviData::V5(DataFormat5 { temperature: Some(21.5), ..Default::default() }));
+
+        let (status, body) = get(&state, "/api/tags").await;
+        assert_eq!(StatusCode::OK, status);
+        let tags : serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!("AA:BB:CC:DD:EE:FF", tags[0]["mac"]);
+
+        let (status, body) = get(&state, "/api/tags/aa:bb:cc:dd:ee:ff").await;
+        assert_eq!(StatusCode::OK, status);
+        let tag : serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(21.5, tag["measurement"]["temperature"]);
+        assert_eq!(-70, tag["gateways"]["11:22:33:44:55:66"]["rssi"]);
+        assert_eq!(StatusCode::OK, get(&state, "/api/tags/AA%3ABB%3ACC%3ADD%3AEE%3AFF").await.0);
+
+        assert_eq!(StatusCode::NOT_FOUND, get(&state, "/api/tags/12:34:56:78:9A:BC").await.0);
+
+        let (_, body) = get(&state, "/api/tags?recent=1").await;
+        let tags : serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(21.5, tags[0]["recent"][0]["temperature"]);
+        assert!(tags[0]["gateways"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let state = state();
+        let health = state.health.clone();
+
+        let (status, body) = get(&state, "/readyz").await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!(r#"{"ready":false,"mqtt_connected":false,"last_message_age_secs":null}"#, body);
+
+        // Connected but nothing received yet
+        health.set_mqtt_connected("default", true);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, get(&state, "/readyz").await.0);
+
+        health.message_received();
+        assert_eq!(StatusCode::OK, get(&state, "/readyz").await.0);
+
+        // The last message is too old
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!health.readiness(Duration::from_millis(1)).ready);
+
+        health.set_mqtt_connected("default", false);
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, get(&state, "/readyz").await.0);
+
+        // Every broker has to be connected
+        health.set_mqtt_connected("default", true);
+        health.set_mqtt_connected("office", false);
+        let (status, body) = get(&state, "/readyz").await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert!(body.contains(r#""mqtt_connected":false,"brokers":{"default":true,"office":false}"#), "{}", body);
+    }
+}
@@ -0,0 +1,8 @@
+//! Decoding of Ruuvi tag BLE advertisements as forwarded by the Ruuvi Gateway,
+//! and the sinks used by the listener binary.
+//!
+//! Without default features only the parser is built, and it is `no_std`
+//! unless the `std` feature is enabled. See Cargo.toml for the other features.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod ruuvi;
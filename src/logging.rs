@@ -0,0 +1,26 @@
+use std::io::IsTerminal;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{LogConfig, LogFormat};
+
+// Installs the global tracing subscriber. RUST_LOG takes precedence over the
+// configured filter so verbosity can be raised without editing the config.
+pub fn init(config : &LogConfig) -> Result<(), String> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(filter) if !filter.is_empty() => filter,
+        _ => config.filter.clone(),
+    };
+    let filter = EnvFilter::try_new(&filter)
+        .map_err(|e| format!("invalid log filter {:?}: {}", filter, e))?;
+
+    // No colour codes in journald
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(std::io::stdout().is_terminal());
+    let result = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    result.map_err(|e| format!("couldn't initialize logging: {}", e))
+}
@@ -1,121 +1,483 @@
 use hyper::{
-    header::CONTENT_TYPE,
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Server,
 };
+use std::process::ExitCode;
 use std::time::Duration;
-use lazy_static::lazy_static;
-use prometheus::{Counter, Encoder, Gauge, HistogramVec, TextEncoder};
-use prometheus::{labels, opts, register_counter, register_gauge, register_histogram_vec};
+use clap::{Parser, Subcommand};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
-use rumqttc::{MqttOptions, AsyncClient, QoS};
-use rumqttc::Event::Incoming;
-use rumqttc::Packet::{Publish, ConnAck, SubAck, PingResp};
 //use std::{env, process, thread};
-mod ruuvi;
+mod broker;
+mod config;
+mod decode;
+mod http;
+mod logging;
 
-use crate::ruuvi::gateway::GatewayMessageResult;
+use crate::broker::{connect, Broker};
+use crate::config::{Config, SinkConfig, SinkKind, DEFAULT_SITE};
+use crate::http::{serve_req, Health, HttpState};
+use ruuvi_gateway_listener::ruuvi;
+use ruuvi_gateway_listener::ruuvi::battery::BatteryTrends;
+use ruuvi_gateway_listener::ruuvi::capture::{read_capture, CaptureWriter};
+use ruuvi_gateway_listener::ruuvi::clock::ClockTracker;
+use ruuvi_gateway_listener::ruuvi::encryption::TagKeys;
+use ruuvi_gateway_listener::ruuvi::gateway::GatewayMessageResult;
+use ruuvi_gateway_listener::ruuvi::latency::LatencyTracker;
+use ruuvi_gateway_listener::ruuvi::live::LiveStream;
+use ruuvi_gateway_listener::ruuvi::metrics::Metrics;
+use ruuvi_gateway_listener::ruuvi::mqtt::MqttClient;
+use ruuvi_gateway_listener::ruuvi::parser::{DecryptionKeys, RuuviSink};
+use ruuvi_gateway_listener::ruuvi::pipeline::{OverflowPolicy, SinkPipeline};
+use ruuvi_gateway_listener::ruuvi::sink::{AsyncRuuviSink, BlockingSink};
+use ruuvi_gateway_listener::ruuvi::sqlite::SqliteHistory;
+use ruuvi_gateway_listener::ruuvi::state::TagStore;
 
-lazy_static! {
-    static ref HTTP_COUNTER: Counter = register_counter!(opts!(
-        "ruuvi_http_requests_total",
-        "Number of HTTP requests made.",
-        labels! {"handler" => "all",}
-    ))
-    .unwrap();
+// Sinks which only buffer run as async sinks, everything else on a blocking
+// thread
+fn build_sink(config : &Config, sink_config : &SinkConfig, client : &MqttClient, battery_trends : &BatteryTrends, metrics : &Metrics) -> Result<Box<dyn AsyncRuuviSink>, String> {
+    let blocking = |sink : Box<dyn RuuviSink + Send>| -> Box<dyn AsyncRuuviSink> { Box::new(BlockingSink::new(sink)) };
+
+    Ok(match &sink_config.kind {
+        SinkKind::Prometheus => blocking(Box::new(ruuvi::prometheus::RuuviPrometheusSink::new(metrics).with_battery_trends(battery_trends.clone()))),
+        SinkKind::Influxdb(influxdb) => Box::new(ruuvi::influxdb::InfluxDbSink::new(influxdb.as_ref().clone(), config.tag_names(), metrics)),
+        SinkKind::Mqtt(mqtt) => blocking(Box::new(ruuvi::mqtt::MqttOutputSink::new(client.clone(), mqtt.clone(), config.tag_names(), metrics))),
+        SinkKind::Homeassistant(homeassistant) => blocking(Box::new(ruuvi::homeassistant::HomeAssistantSink::new(client.clone(), homeassistant.clone(), config.tag_names(), metrics))),
+        SinkKind::Alerts(alerts) => blocking(Box::new(ruuvi::alerts::AlertSink::new(alerts.clone(), config.tag_names(), metrics))),
+        SinkKind::Sqlite(sqlite) => blocking(Box::new(ruuvi::sqlite::SqliteSink::new(sqlite.clone(), metrics)
+            .map_err(|e| format!("couldn't open {}: {}", sqlite.path, e))?)),
+    })
+}
+
+fn start_sinks(config : &Config, client : &MqttClient, battery_trends : &BatteryTrends, metrics : &Metrics) -> SinkPipeline {
+    let mut pipeline = SinkPipeline::new(metrics);
+    for sink_config in config.sinks() {
+        info!(sink = sink_config.name(), kind = sink_config.kind.type_name(), "Starting sink");
+        let sink = match build_sink(config, &sink_config, client, battery_trends, metrics) {
+            Ok(sink) => sink,
+            Err(e) => {
+                error!(sink = sink_config.name(), error = %e, "couldn't start sink");
+                std::process::exit(1);
+            }
+        };
+        pipeline.add_async_sink(sink_config.name(), sink, sink_config.queue_size, sink_config.overflow);
+    }
+    pipeline
 }
 
-async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
-    let encoder = TextEncoder::new();
+#[derive(Parser)]
+#[command(version, about = "Reads Ruuvi Gateway packets from MQTT, decodes them and serves metrics for Prometheus")]
+struct Cli {
+    /// TOML configuration file
+    #[arg(short, long, global = true)]
+    config: Option<String>,
 
-    HTTP_COUNTER.inc();
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let metric_families = prometheus::gather();
-    let mut buffer = vec![];
-    encoder.encode(&metric_families, &mut buffer).unwrap();
+#[derive(Subcommand)]
+enum Command {
+    /// Listen to the MQTT brokers and feed measurements to the sinks (the default)
+    Run {
+        /// Append every incoming MQTT publish to this newline delimited JSON file
+        #[arg(long)]
+        capture: Option<String>,
+    },
+    /// Feed a capture file back through the decoder into the configured sinks
+    Replay {
+        file: String,
 
-    let response = Response::builder()
-        .status(200)
-        .header(CONTENT_TYPE, encoder.format_type())
-        .body(Body::from(buffer))
-        .unwrap();
+        /// Speed relative to the original timing, 0 replays as fast as possible
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+    /// Decode hex advertisements given as arguments or on stdin, one per line
+    #[command(alias = "ruuvi-decode")]
+    Decode {
+        hex: Vec<String>,
 
-    Ok(response)
+        /// Print JSON lines instead of a table
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 #[tokio::main]
-async fn main() {
-    // Setup paho mqtt
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    if let Some(Command::Decode { hex, json }) = &cli.command {
+        decode(hex, *json);
+    }
+
+    let config = match &cli.config {
+        Some(path) => match Config::from_file(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
+
+    if let Err(e) = logging::init(&config.log) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let stopped = match cli.command {
+        None => run(config, None).await,
+        Some(Command::Run { capture }) => run(config, capture).await,
+        Some(Command::Replay { file, speed }) => replay(config, &file, speed).await,
+        Some(Command::Decode { .. }) => unreachable!(),
+    };
+    info!(?stopped, "Stopped");
+    stopped.exit_code()
+}
+
+// Why the listener stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stopped {
+    // Stopped by a signal or a finished replay, everything was written
+    Finished,
+    // Sinks didn't drain within the shutdown timeout
+    DrainTimeout,
+    // A second signal while shutting down
+    Interrupted,
+}
+
+impl Stopped {
+    fn exit_code(self) -> ExitCode {
+        match self {
+            Stopped::Finished => ExitCode::SUCCESS,
+            Stopped::DrainTimeout => ExitCode::from(2),
+            Stopped::Interrupted => ExitCode::from(130),
+        }
+    }
+}
+
+// Resolves on SIGINT (Ctrl-C) or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!(error = %e, "couldn't listen for SIGTERM");
+                std::process::exit(1);
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Waits until the sinks have processed and flushed their queues. The broker
+// connections keep being polled meanwhile so that what the MQTT output sinks
+// publish gets sent.
+async fn drain_sinks(pipeline : SinkPipeline, timeout : Duration) -> Stopped {
+    info!(?timeout, "Draining sinks");
+    tokio::select! {
+        result = tokio::time::timeout(timeout, pipeline.close()) => {
+            if result.is_err() {
+                error!(?timeout, "sinks didn't drain in time");
+                return Stopped::DrainTimeout;
+            }
+            Stopped::Finished
+        }
+        _ = shutdown_signal() => {
+            warn!("Interrupted while draining sinks");
+            Stopped::Interrupted
+        }
+    }
+}
+
+fn decode(hex : &[String], json : bool) -> ! {
+    let inputs : Vec<String> = if hex.is_empty() {
+        std::io::stdin().lines().map_while(Result::ok).collect()
+    } else {
+        hex.to_vec()
+    };
+
+    let mut failed = false;
+    for input in inputs.iter().filter(|input| !input.trim().is_empty()) {
+        let report = decode::decode_report(input);
+        failed |= report.error.is_some();
+        if json {
+            println!("{}", serde_json::to_string(&report).unwrap());
+        } else {
+            print!("{}", decode::format_table(&report));
+        }
+    }
+
+    std::process::exit(if failed { 1 } else { 0 });
+}
+
+// Keys for encrypted tags, exits when the key file is configured but can't
+// be used
+fn load_tag_keys(config : &Config) -> Option<TagKeys> {
+    let path = config.encryption.key_file.as_ref()?;
+    match TagKeys::load(path) {
+        Ok(keys) => {
+            info!(path, tags = keys.len(), "loaded decryption keys");
+            Some(keys)
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn replay(config : Config, file : &str, speed : f64) -> Stopped {
+    let messages = match read_capture(file) {
+        Ok(messages) => messages,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Output sinks publish through the first broker
+    let broker = match config.brokers() {
+        Ok(brokers) => brokers.into_iter().next().unwrap(),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let (client, mut eventloop) = match connect(&broker, &format!("{}-replay", broker.client_id), config.last_will_topic().as_deref()) {
+        Ok(connection) => connection,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let eventloop = if config.output_topic_prefixes().is_empty() {
+        None
+    } else {
+        // Only needed to deliver what the MQTT output sinks publish
+        Some(tokio::spawn(async move {
+            loop {
+                if let Err(e) = eventloop.poll().await {
+                    warn!(error = %e, "MQTT connection error");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }))
+    };
+
+    let keys = load_tag_keys(&config);
+    let metrics = Metrics::default();
+    let pipeline = start_sinks(&config, &client, &BatteryTrends::default(), &metrics);
+
+    info!(messages = messages.len(), file, "replaying capture");
+    tokio::select! {
+        decoded = ruuvi::capture::replay(&messages, speed, keys.as_ref().map(|keys| keys as &dyn DecryptionKeys), &pipeline, &metrics) => {
+            info!(decoded, "replay finished");
+        }
+        _ = shutdown_signal() => {
+            info!("Replay interrupted");
+        }
+    }
 
-    let mut mqttoptions = MqttOptions::new("rumqtt-async", "mqtt.juhonkoti.net", 1883);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
-    
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
-    client.subscribe("ruuvi/#", QoS::AtMostOnce).await.unwrap();
+    let stopped = drain_sinks(pipeline, config.shutdown.timeout()).await;
+    if let Some(eventloop) = eventloop {
+        // Give the last publishes a moment to go out
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        eventloop.abort();
+    }
+    stopped
+}
 
-    
+async fn run(config : Config, capture : Option<String>) -> Stopped {
+    let broker_configs = match config.brokers() {
+        Ok(brokers) => brokers,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let capture = match capture {
+        Some(path) => match CaptureWriter::create(&path) {
+            Ok(writer) => {
+                info!(%path, "capturing MQTT messages");
+                Some(writer.spawn(1000))
+            }
+            Err(e) => {
+                error!(%path, error = %e, "couldn't open capture file");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
     // Setup Prometheus
-    let addr = ([0, 0, 0, 0], 9898).into();
-    println!("Listening on http://{}", addr);
+    let addr = config.http.listen;
+    info!("Listening on http://{}", addr);
 
-    let serve_future = Server::bind(&addr).serve(make_service_fn(|_| async {
-        Ok::<_, hyper::Error>(service_fn(serve_req))
-    }));
+    let metrics = Metrics::default();
 
-    println!("Preparing to serve prometheus traffic...");
-    tokio::spawn(async move { 
-        if let Err(err) = serve_future.await {
-            eprintln!("server error: {}", err);
-        }    
-    });
+    let history = match config.history_path() {
+        Some(path) => match SqliteHistory::open(path, &metrics) {
+            Ok(history) => Some(history),
+            Err(e) => {
+                error!(path, error = %e, "couldn't open history database");
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let keys = load_tag_keys(&config);
+    let health = Health::default();
+
+    // Every broker feeds the same decoding loop
+    let output_topic_prefixes = config.output_topic_prefixes();
+    let last_will_topic = config.last_will_topic();
+    let (messages, mut received) = tokio::sync::mpsc::channel(100);
+    let mut brokers = Vec::new();
+    for (index, broker_config) in broker_configs.iter().enumerate() {
+        // Output sinks publish through the first broker
+        let last_will_topic = if index == 0 { last_will_topic.as_deref() } else { None };
+        match Broker::spawn(broker_config, output_topic_prefixes.clone(), last_will_topic, messages.clone(), &metrics, health.clone()) {
+            Ok(broker) => brokers.push(broker),
+            Err(e) => {
+                error!(site = %broker_config.site, error = %e, "couldn't configure MQTT broker");
+                std::process::exit(1);
+            }
+        }
+    }
+    drop(messages);
 
-    
-    println!("Starting event loop polling");
-    let mut sink = ruuvi::prometheus::RuuviPrometheusSink::new();
+    let mut tags = TagStore::new(config.tag_names(), config.dashboard.recent_samples, config.dashboard.sample_interval_secs)
+        .with_metrics(&metrics);
+    if let Some(path) = &config.state.snapshot_path {
+        match tags.load(path, keys.as_ref().map(|keys| keys as &dyn DecryptionKeys)) {
+            Ok(loaded) => info!(path, tags = loaded, "restored tag snapshot"),
+            // Starting without the old state beats not starting
+            Err(e) => warn!(path, error = %e, "couldn't restore tag snapshot"),
+        }
+        tags = tags.with_snapshot(path, config.state.snapshot_interval());
+    }
+    let stream = LiveStream::new(config.http.stream_buffer, &metrics);
+    let http_state = HttpState::new(&config, metrics.clone(), history, tags.clone(), stream.clone(), health.clone());
+    let (stop_http, http_stopped) = tokio::sync::oneshot::channel::<()>();
+    let serve_future = Server::bind(&addr).serve(make_service_fn(move |_| {
+        let http_state = http_state.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| serve_req(req, http_state.clone())))
+        }
+    })).with_graceful_shutdown(async {
+        let _ = http_stopped.await;
+    });
 
-    
-    tokio::spawn(async move {
-        for i in 0..1000 {
-            //client.publish("hello/rumqtt", QoS::AtLeastOnce, false, vec![i; i as usize]).await.unwrap();
-            println!("Timer 1000 ms");
-            tokio::time::sleep(Duration::from_millis(1000)).await;
+    let http_server = tokio::spawn(async move {
+        if let Err(err) = serve_future.await {
+            error!(error = %err, "HTTP server error");
         }
     });
 
-    while let Ok(notification) = eventloop.poll().await {
-        match notification {
-            Incoming(incoming) => {
-                match incoming {
-                    Publish(publish) => {
-                        //println!("Incoming message to topic {:?}, message: {:?}", publish.topic, publish.payload);
-                        let message_result = ruuvi::gateway::parse_gateway_message(&publish.payload, publish.topic);
-                        match message_result {
-                            GatewayMessageResult::Received(message) => {
-                                println!("message: {:?}", message);
-                                ruuvi::parser::decode_ble_ruuvi_str(&message.data, &message.mac, &mut sink);
-                            }
-                            _ => {}
-                        }
-                    }
-
-                    ConnAck(connack) => {
-                        println!("Connection acknowledged: {:?}", connack.code)
-                    }
-
-                    SubAck(suback) => {
-                        println!("Subscription acknowledged: {:?}", suback.return_codes)
-                    }
-
-                    PingResp => {}
-
-                    _ => {
-                        println!("Received something else = {:?}", incoming);
-                    }
+
+    // Output sinks publish through the first broker
+    let mut pipeline = start_sinks(&config, &brokers[0].client, &tags.battery_trends(), &metrics);
+    let clock_offsets = tags.clock_offsets();
+    // Latest state of every tag for the HTTP API, saved to the snapshot
+    pipeline.add_async_sink("state", Box::new(tags), 1000, OverflowPolicy::Drop);
+    // Clients of /api/stream, flushing the sink on shutdown ends their streams
+    pipeline.add_async_sink("stream", Box::new(stream), 1000, OverflowPolicy::Drop);
+
+    let mut clock = ClockTracker::new(&metrics, config.clock.correct_timestamps).with_offsets(clock_offsets);
+    let mut latency = LatencyTracker::new(&metrics);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let received = tokio::select! {
+            _ = &mut shutdown => {
+                info!("Shutdown requested");
+                break;
+            }
+            received = received.recv() => match received {
+                Some(received) => received,
+                None => break,
+            },
+        };
+
+        let span = info_span!("message", site = %received.site, topic = %received.topic, gateway = tracing::field::Empty, tag = tracing::field::Empty);
+        let decoded = span.in_scope(|| {
+            if let Some(capture) = &capture {
+                if !capture.write(&received.topic, &received.payload, received.received_at) {
+                    warn!("capture queue is full, message not captured");
                 }
             }
-            _ => {}
+
+            let message_result = ruuvi::gateway::parse_gateway_message(&received.payload, received.topic, metrics.gateway());
+            let GatewayMessageResult::Received(mut message) = message_result else {
+                return None;
+            };
+            health.message_received();
+            message.received_at = Some(received.received_at);
+            // Measurements keep their old tags unless a site is configured
+            if received.site != DEFAULT_SITE {
+                message.site = received.site;
+            }
+            clock.observe(&mut message);
+            latency.observe(&message);
+            span.record("gateway", message.gateway_mac.as_str());
+            span.record("tag", message.mac.as_str());
+            debug!(rssi = message.rssi, data = %message.data, "gateway message");
+            let keys = keys.as_ref().map(|keys| keys as &dyn DecryptionKeys);
+            match ruuvi::parser::decode_gateway_measurement(&message, keys) {
+                Ok(measurement) => Some((message, measurement)),
+                Err(e) => {
+                    metrics.decryption().observe(&message.mac, &e);
+                    None
+                }
+            }
+        });
+        // Waits here for sinks with the block policy
+        if let Some((message, measurement)) = decoded {
+            pipeline.send(&message, measurement).instrument(span).await;
+        }
+    }
+
+    // No new messages while the sinks drain
+    for broker in &brokers {
+        broker.drain();
+    }
+    let timeout = config.shutdown.timeout();
+    if let Some(capture) = capture {
+        if tokio::time::timeout(timeout, capture.close()).await.is_err() {
+            warn!("capture file wasn't written in time");
         }
     }
+    let drained = drain_sinks(pipeline, timeout).await;
+    if drained == Stopped::Interrupted {
+        return drained;
+    }
+    for broker in &brokers {
+        broker.disconnect();
+    }
+    for broker in brokers {
+        broker.join(timeout).await;
+    }
+
+    let _ = stop_http.send(());
+    if tokio::time::timeout(timeout, http_server).await.is_err() {
+        warn!("HTTP server didn't stop in time");
+    }
 
+    drained
 }
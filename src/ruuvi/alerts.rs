@@ -0,0 +1,720 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Client, Method, Request};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use prometheus::{CounterVec, IntGauge, Opts, Registry};
+use tracing::{error, warn};
+
+use crate::ruuvi::metrics::{register, Metrics};
+use crate::ruuvi::parser::{RuuviData, RuuviMeasurement, RuuviSink};
+
+#[derive(Clone)]
+pub struct AlertMetrics {
+    pub firing: IntGauge,
+    pub notifications: CounterVec,
+}
+
+impl AlertMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            firing: register(registry, IntGauge::new(
+                "ruuvi_alerts_firing",
+                "Number of alerts currently firing.")?)?,
+            notifications: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_alert_notification_count",
+                "Number of alert notifications by result: ok, error, or dropped because the queue was full."),
+                &["status", "result"])?)?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AlertsConfig {
+    // Notifications are POSTed here as JSON
+    pub webhook_url: String,
+
+    // How often tags that stopped reporting and pending alerts are checked
+    #[serde(default = "default_check_interval_secs")]
+    pub check_interval_secs: u64,
+
+    #[serde(default = "default_webhook_retries")]
+    pub webhook_retries: u32,
+
+    #[serde(default)]
+    pub rules: Vec<AlertRule>,
+}
+
+fn default_check_interval_secs() -> u64 {
+    10
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AlertRule {
+    pub name: String,
+
+    // Tags the rule applies to, all tags when empty
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    #[serde(flatten)]
+    pub condition: Condition,
+
+    // How far back the value must return before a firing alert resolves
+    #[serde(default)]
+    pub hysteresis: f64,
+
+    // How long the condition must hold before the alert fires
+    #[serde(default)]
+    pub for_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Temperature,
+    Humidity,
+    Pressure,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "condition", rename_all = "snake_case")]
+pub enum Condition {
+    TemperatureAbove { threshold: f64 },
+    TemperatureBelow { threshold: f64 },
+    HumidityOutside { min: f64, max: f64 },
+    BatteryBelow { voltage: f64 },
+    NotSeen { timeout_secs: u64 },
+    // Absolute change per minute between consecutive measurements
+    RateOfChange { field: Field, max_per_minute: f64 },
+}
+
+impl Condition {
+    fn name(&self) -> &'static str {
+        match self {
+            Condition::TemperatureAbove { .. } => "temperature_above",
+            Condition::TemperatureBelow { .. } => "temperature_below",
+            Condition::HumidityOutside { .. } => "humidity_outside",
+            Condition::BatteryBelow { .. } => "battery_below",
+            Condition::NotSeen { .. } => "not_seen",
+            Condition::RateOfChange { .. } => "rate_of_change",
+        }
+    }
+}
+
+fn field_value(field : Field, data : &RuuviData) -> Option<f64> {
+    match field {
+        Field::Temperature => data.temperature().map(|value| value as f64),
+        Field::Humidity => data.humidity().map(|value| value as f64),
+        Field::Pressure => data.pressure().map(|value| value as f64),
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    pub status: AlertStatus,
+    pub rule: String,
+    pub condition: &'static str,
+    pub tag_mac: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Value which triggered or resolved the alert
+    pub value: Option<f64>,
+    // Unix seconds
+    pub started_at: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Default)]
+struct AlertState {
+    last_seen: Option<Instant>,
+    last_sample: Option<(Instant, f64)>,
+    last_value: Option<f64>,
+    pending_since: Option<Instant>,
+    firing_since: Option<SystemTime>,
+}
+
+fn unix_secs(time : SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Evaluates the alert rules against incoming measurements
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    names: HashMap<String, String>,
+    states: HashMap<(usize, String), AlertState>,
+}
+
+impl AlertEngine {
+    pub fn new(rules : Vec<AlertRule>, names : HashMap<String, String>, now : Instant) -> Self {
+        let mut rules = rules;
+        for rule in rules.iter_mut() {
+            for tag in rule.tags.iter_mut() {
+                *tag = tag.to_uppercase();
+            }
+        }
+
+        // Explicitly listed tags count as seen at start, so they alert even
+        // if they never report after a restart
+        let mut states = HashMap::new();
+        for (index, rule) in rules.iter().enumerate() {
+            if let Condition::NotSeen { .. } = rule.condition {
+                for tag in &rule.tags {
+                    states.insert((index, tag.clone()), AlertState {
+                        last_seen: Some(now),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        Self {
+            rules,
+            names,
+            states,
+        }
+    }
+
+    pub fn evaluate(&mut self, tag_mac : &str, data : &RuuviData, now : Instant) -> Vec<Notification> {
+        let tag_mac = tag_mac.to_uppercase();
+        let mut notifications = Vec::new();
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.tags.is_empty() && !rule.tags.contains(&tag_mac) {
+                continue;
+            }
+            let state = self.states.entry((index, tag_mac.clone())).or_default();
+            state.last_seen = Some(now);
+            let firing = state.firing_since.is_some();
+            let h = rule.hysteresis;
+
+            // Rules on a quantity the tag's data format doesn't provide are
+            // left as they are
+            let (breached, value) = match &rule.condition {
+                Condition::TemperatureAbove { threshold } => {
+                    let Some(value) = field_value(Field::Temperature, data) else { continue };
+                    (if firing { value > threshold - h } else { value > *threshold }, Some(value))
+                }
+                Condition::TemperatureBelow { threshold } => {
+                    let Some(value) = field_value(Field::Temperature, data) else { continue };
+                    (if firing { value < threshold + h } else { value < *threshold }, Some(value))
+                }
+                Condition::HumidityOutside { min, max } => {
+                    let Some(value) = field_value(Field::Humidity, data) else { continue };
+                    (if firing { value < min + h || value > max - h } else { value < *min || value > *max }, Some(value))
+                }
+                Condition::BatteryBelow { voltage } => {
+                    let Some(value) = data.voltage().map(|value| value as f64) else { continue };
+                    (if firing { value < voltage + h } else { value < *voltage }, Some(value))
+                }
+                Condition::NotSeen { .. } => (false, None),
+                Condition::RateOfChange { field, max_per_minute } => {
+                    let Some(value) = field_value(*field, data) else { continue };
+                    let previous = state.last_sample.replace((now, value));
+                    match previous {
+                        Some((time, previous)) if now > time => {
+                            let rate = (value - previous).abs() / now.duration_since(time).as_secs_f64() * 60.0;
+                            (if firing { rate > max_per_minute - h } else { rate > *max_per_minute }, Some(rate))
+                        }
+                        // Keep the current state until there is a rate to compare
+                        _ => (firing, None),
+                    }
+                }
+            };
+            state.last_value = value;
+
+            if let Some(notification) = transition(rule, state, &tag_mac, self.names.get(&tag_mac), breached, now) {
+                notifications.push(notification);
+            }
+        }
+
+        notifications
+    }
+
+    // Checks for tags which stopped reporting and alerts whose minimum
+    // duration has passed without new measurements
+    pub fn tick(&mut self, now : Instant) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+
+        for ((index, tag_mac), state) in self.states.iter_mut() {
+            let rule = &self.rules[*index];
+            let breached = match &rule.condition {
+                Condition::NotSeen { timeout_secs } => match state.last_seen {
+                    Some(last_seen) => now.duration_since(last_seen) >= Duration::from_secs(*timeout_secs),
+                    None => false,
+                },
+                _ => state.pending_since.is_some(),
+            };
+            if !breached {
+                continue;
+            }
+
+            if let Some(notification) = transition(rule, state, tag_mac, self.names.get(tag_mac), true, now) {
+                notifications.push(notification);
+            }
+        }
+
+        notifications
+    }
+}
+
+fn transition(rule : &AlertRule, state : &mut AlertState, tag_mac : &str, name : Option<&String>, breached : bool, now : Instant) -> Option<Notification> {
+    let status = match (breached, state.firing_since) {
+        (true, None) => {
+            let pending_since = *state.pending_since.get_or_insert(now);
+            if now.duration_since(pending_since) < Duration::from_secs(rule.for_secs) {
+                return None;
+            }
+            state.pending_since = None;
+            state.firing_since = Some(SystemTime::now());
+            AlertStatus::Firing
+        }
+        (false, Some(_)) => AlertStatus::Resolved,
+        (false, None) => {
+            state.pending_since = None;
+            return None;
+        }
+        (true, Some(_)) => return None,
+    };
+
+    let started_at = state.firing_since.map(unix_secs).unwrap_or(0);
+    if status == AlertStatus::Resolved {
+        state.firing_since = None;
+    }
+
+    Some(Notification {
+        status,
+        rule: rule.name.clone(),
+        condition: rule.condition.name(),
+        tag_mac: tag_mac.to_string(),
+        name: name.cloned(),
+        value: state.last_value,
+        started_at,
+        timestamp: unix_secs(SystemTime::now()),
+    })
+}
+
+async fn send_notification(client : &Client<hyper::client::HttpConnector>, url : &str, notification : &Notification) -> Result<(), String> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_vec(notification).map_err(|e| e.to_string())?))
+        .map_err(|e| e.to_string())?;
+
+    let response = client.request(request).await.map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(response.status().to_string())
+    }
+}
+
+async fn run_webhook(url : String, retries : u32, mut receiver : mpsc::Receiver<Notification>, metrics : AlertMetrics) {
+    let client = Client::new();
+
+    while let Some(notification) = receiver.recv().await {
+        let status = status_label(&notification.status);
+
+        let mut attempt = 0;
+        loop {
+            match send_notification(&client, &url, &notification).await {
+                Ok(()) => {
+                    metrics.notifications.with_label_values(&[status, "ok"]).inc();
+                    break;
+                }
+                Err(e) if attempt < retries => {
+                    warn!(rule = %notification.rule, tag = %notification.tag_mac, attempt, error = %e, "couldn't send alert, retrying");
+                    attempt += 1;
+                    tokio::time::sleep(Duration::from_secs(1 << attempt.min(6))).await;
+                }
+                Err(e) => {
+                    error!(rule = %notification.rule, tag = %notification.tag_mac, error = %e, "couldn't send alert");
+                    metrics.notifications.with_label_values(&[status, "error"]).inc();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// Notifications waiting for the webhook, more are dropped
+const NOTIFICATION_QUEUE : usize = 100;
+
+// How long shutdown waits for the queued notifications to be delivered
+const FLUSH_TIMEOUT : Duration = Duration::from_secs(10);
+
+fn status_label(status : &AlertStatus) -> &'static str {
+    match status {
+        AlertStatus::Firing => "firing",
+        AlertStatus::Resolved => "resolved",
+    }
+}
+
+// Feeds measurements to the alert engine and delivers the notifications
+// to the configured webhook
+pub struct AlertSink {
+    engine: Arc<Mutex<AlertEngine>>,
+    // Taken on flush so the webhook task ends once the queue is drained
+    notifications: Option<mpsc::Sender<Notification>>,
+    webhook: Option<JoinHandle<()>>,
+    ticker: JoinHandle<()>,
+    metrics: AlertMetrics,
+}
+
+// Queues the notifications for the webhook and keeps the firing gauge in sync
+fn send_notifications(notifications : Vec<Notification>, sender : &mpsc::Sender<Notification>, metrics : &AlertMetrics) {
+    for notification in notifications {
+        match notification.status {
+            AlertStatus::Firing => metrics.firing.inc(),
+            AlertStatus::Resolved => metrics.firing.dec(),
+        }
+        if let Err(mpsc::error::TrySendError::Full(notification)) = sender.try_send(notification) {
+            warn!(rule = %notification.rule, tag = %notification.tag_mac, "alert queue is full, dropping notification");
+            metrics.notifications.with_label_values(&[status_label(&notification.status), "dropped"]).inc();
+        }
+    }
+}
+
+impl AlertSink {
+    // Must be called from within a Tokio runtime.
+    pub fn new(config : AlertsConfig, names : HashMap<String, String>, metrics : &Metrics) -> Self {
+        let engine = Arc::new(Mutex::new(AlertEngine::new(config.rules.clone(), names, Instant::now())));
+        let (notifications, receiver) = mpsc::channel(NOTIFICATION_QUEUE);
+        let metrics = metrics.alerts().clone();
+
+        let webhook = tokio::spawn(run_webhook(config.webhook_url.clone(), config.webhook_retries, receiver, metrics.clone()));
+
+        let tick_engine = Arc::downgrade(&engine);
+        let tick_notifications = notifications.downgrade();
+        let tick_metrics = metrics.clone();
+        let ticker = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(config.check_interval_secs.max(1)));
+            loop {
+                interval.tick().await;
+                let (engine, sender) = match (tick_engine.upgrade(), tick_notifications.upgrade()) {
+                    (Some(engine), Some(sender)) => (engine, sender),
+                    _ => return,
+                };
+                let pending = engine.lock().unwrap().tick(Instant::now());
+                send_notifications(pending, &sender, &tick_metrics);
+            }
+        });
+
+        Self {
+            engine,
+            notifications: Some(notifications),
+            webhook: Some(webhook),
+            ticker,
+            metrics,
+        }
+    }
+}
+
+impl RuuviSink for AlertSink {
+    fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
+        let notifications = self.engine.lock().unwrap().evaluate(source_mac, &measurement, Instant::now());
+        if let Some(sender) = &self.notifications {
+            send_notifications(notifications, sender, &self.metrics);
+        }
+    }
+
+    // Runs on the blocking pool, waits for the queued notifications to be
+    // delivered
+    fn flush(&mut self) {
+        self.ticker.abort();
+        self.notifications = None;
+        let webhook = match self.webhook.take() {
+            Some(webhook) => webhook,
+            None => return,
+        };
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+        if handle.block_on(tokio::time::timeout(FLUSH_TIMEOUT, webhook)).is_err() {
+            warn!("alert notifications weren't delivered before shutdown");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::{DataFormat5, DataFormat6};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+
+    const TAG : &str = "AA:BB:CC:DD:EE:FF";
+
+    fn rules(toml : &str) -> Vec<AlertRule> {
+        let config : AlertsConfig = toml::from_str(&format!("webhook_url = \"http://localhost/\"\n{}", toml)).unwrap();
+        config.rules
+    }
+
+    fn temperature(value : f32) -> RuuviData {
+        RuuviData::V5(DataFormat5 {
+            temperature: Some(value),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_threshold_with_hysteresis() {
+        let start = Instant::now();
+        let mut engine = AlertEngine::new(rules(r#"
+            [[rules]]
+            name = "freezer"
+            condition = "temperature_above"
+            threshold = -15.0
+            hysteresis = 2.0
+        "#), HashMap::new(), start);
+
+        assert!(engine.evaluate(TAG, &temperature(-18.0), start).is_empty());
+
+        let notifications = engine.evaluate(TAG, &temperature(-14.0), start);
+        assert_eq!(1, notifications.len());
+        assert_eq!(AlertStatus::Firing, notifications[0].status);
+        assert_eq!("temperature_above", notifications[0].condition);
+        assert_eq!(Some(-14.0), notifications[0].value);
+
+        // Below the threshold but within the hysteresis
+        assert!(engine.evaluate(TAG, &temperature(-16.0), start).is_empty());
+
+        let notifications = engine.evaluate(TAG, &temperature(-17.5), start);
+        assert_eq!(1, notifications.len());
+        assert_eq!(AlertStatus::Resolved, notifications[0].status);
+    }
+
+    #[test]
+    fn test_minimum_duration() {
+        let start = Instant::now();
+        let mut engine = AlertEngine::new(rules(r#"
+            [[rules]]
+            name = "cold"
+            tags = ["aa:bb:cc:dd:ee:ff"]
+            condition = "temperature_below"
+            threshold = 10.0
+            for_secs = 60
+        "#), HashMap::new(), start);
+
+        assert!(engine.evaluate(TAG, &temperature(5.0), start).is_empty());
+        assert!(engine.evaluate(TAG, &temperature(5.0), start + Duration::from_secs(30)).is_empty());
+        // Other tags are not affected
+        assert!(engine.evaluate("11:22:33:44:55:66", &temperature(5.0), start + Duration::from_secs(90)).is_empty());
+
+        let notifications = engine.tick(start + Duration::from_secs(61));
+        assert_eq!(1, notifications.len());
+        assert_eq!(AlertStatus::Firing, notifications[0].status);
+
+        // A recovery before the duration resets the timer
+        let mut engine = AlertEngine::new(engine.rules.clone(), HashMap::new(), start);
+        assert!(engine.evaluate(TAG, &temperature(5.0), start).is_empty());
+        assert!(engine.evaluate(TAG, &temperature(15.0), start + Duration::from_secs(30)).is_empty());
+        assert!(engine.evaluate(TAG, &temperature(5.0), start + Duration::from_secs(70)).is_empty());
+        assert!(engine.tick(start + Duration::from_secs(71)).is_empty());
+    }
+
+    #[test]
+    fn test_not_seen() {
+        let start = Instant::now();
+        let mut names = HashMap::new();
+        names.insert(TAG.to_string(), "Freezer".to_string());
+        let mut engine = AlertEngine::new(rules(r#"
+            [[rules]]
+            name = "silent"
+            tags = ["AA:BB:CC:DD:EE:FF"]
+            condition = "not_seen"
+            timeout_secs = 600
+        "#), names, start);
+
+        assert!(engine.tick(start + Duration::from_secs(599)).is_empty());
+
+        let notifications = engine.tick(start + Duration::from_secs(600));
+        assert_eq!(1, notifications.len());
+        assert_eq!(AlertStatus::Firing, notifications[0].status);
+        assert_eq!(Some("Freezer".to_string()), notifications[0].name);
+        assert!(engine.tick(start + Duration::from_secs(700)).is_empty());
+
+        let notifications = engine.evaluate(TAG, &temperature(0.0), start + Duration::from_secs(701));
+        assert_eq!(1, notifications.len());
+        assert_eq!(AlertStatus::Resolved, notifications[0].status);
+    }
+
+    #[test]
+    fn test_rate_of_change() {
+        let start = Instant::now();
+        let mut engine = AlertEngine::new(rules(r#"
+            [[rules]]
+            name = "door open"
+            condition = "rate_of_change"
+            field = "temperature"
+            max_per_minute = 1.0
+        "#), HashMap::new(), start);
+
+        assert!(engine.evaluate(TAG, &temperature(-18.0), start).is_empty());
+        assert!(engine.evaluate(TAG, &temperature(-17.5), start + Duration::from_secs(60)).is_empty());
+
+        let notifications = engine.evaluate(TAG, &temperature(-16.0), start + Duration::from_secs(90));
+        assert_eq!(1, notifications.len());
+        assert_eq!(Some(3.0), notifications[0].value);
+
+        let battery = rules(r#"
+            [[rules]]
+            name = "battery"
+            condition = "battery_below"
+            voltage = 2.5
+        "#);
+        assert_eq!(Condition::BatteryBelow { voltage: 2.5 }, battery[0].condition);
+    }
+
+    #[test]
+    fn test_rules_skip_missing_fields() {
+        let start = Instant::now();
+        let mut engine = AlertEngine::new(rules(r#"
+            [[rules]]
+            name = "battery"
+            condition = "battery_below"
+            voltage = 2.5
+        "#), HashMap::new(), start);
+
+        // Format 6 has no battery voltage
+        let air = RuuviData::V6(DataFormat6::default());
+        assert!(engine.evaluate(TAG, &air, start).is_empty());
+
+        let notifications = engine.evaluate(TAG, &temperature(20.0), start);
+        assert_eq!(1, notifications.len());
+        assert_eq!(Some(0.0), notifications[0].value);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_notification() {
+        let (sender, mut received) = mpsc::unbounded_channel::<serde_json::Value>();
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req : Request<Body>| {
+                    let sender = sender.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        sender.send(serde_json::from_slice(&body).unwrap()).unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let config : AlertsConfig = toml::from_str(&format!(r#"
+            webhook_url = "http://{}/alerts"
+
+            [[rules]]
+            name = "hot"
+            condition = "temperature_above"
+            threshold = 30.0
+        "#, addr)).unwrap();
+
+        let metrics = Metrics::default();
+        let mut sink = AlertSink::new(config, HashMap::new(), &metrics);
+        sink.sink(TAG, temperature(35.0));
+        assert_eq!(1, metrics.alerts().firing.get());
+        sink.sink(TAG, temperature(20.0));
+        assert_eq!(0, metrics.alerts().firing.get());
+
+        let firing = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+        assert_eq!("firing", firing["status"]);
+        assert_eq!("hot", firing["rule"]);
+        assert_eq!(TAG, firing["tag_mac"]);
+        assert_eq!(35.0, firing["value"]);
+
+        let resolved = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+        assert_eq!("resolved", resolved["status"]);
+        assert_eq!(firing["started_at"], resolved["started_at"]);
+        assert_eq!(1.0, metrics.alerts().notifications.with_label_values(&["firing", "ok"]).get());
+    }
+
+    #[test]
+    fn test_full_queue_drops_notifications() {
+        let mut engine = AlertEngine::new(toml::from_str::<AlertsConfig>(r#"
+            webhook_url = "http://localhost/alerts"
+
+            [[rules]]
+            name = "hot"
+            condition = "temperature_above"
+            threshold = 30.0
+        "#).unwrap().rules, HashMap::new(), Instant::now());
+        let metrics = Metrics::default();
+        let (sender, mut receiver) = mpsc::channel(1);
+
+        let mut notifications = engine.evaluate(TAG, &temperature(35.0), Instant::now());
+        notifications.extend(engine.evaluate(TAG, &temperature(20.0), Instant::now()));
+        send_notifications(notifications, &sender, metrics.alerts());
+
+        assert_eq!(AlertStatus::Firing, receiver.try_recv().unwrap().status);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(1.0, metrics.alerts().notifications.with_label_values(&["resolved", "dropped"]).get());
+        assert_eq!(0, metrics.alerts().firing.get());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_flush_delivers_queued_notifications() {
+        let (sender, mut received) = mpsc::unbounded_channel::<serde_json::Value>();
+        let make_service = make_service_fn(move |_| {
+            let sender = sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req : Request<Body>| {
+                    let sender = sender.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        // Slow enough that the notification is still queued on flush
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        sender.send(serde_json::from_slice(&body).unwrap()).unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::empty()))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let config : AlertsConfig = toml::from_str(&format!(r#"
+            webhook_url = "http://{}/alerts"
+
+            [[rules]]
+            name = "hot"
+            condition = "temperature_above"
+            threshold = 30.0
+        "#, addr)).unwrap();
+
+        let metrics = Metrics::default();
+        let mut sink = AlertSink::new(config, HashMap::new(), &metrics);
+        sink.sink(TAG, temperature(35.0));
+        sink.sink(TAG, temperature(20.0));
+
+        // As the pipeline does on shutdown
+        tokio::task::spawn_blocking(move || sink.flush()).await.unwrap();
+
+        assert_eq!("firing", received.try_recv().unwrap()["status"]);
+        assert_eq!("resolved", received.try_recv().unwrap()["status"]);
+        assert_eq!(1.0, metrics.alerts().notifications.with_label_values(&["resolved", "ok"]).get());
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "prometheus")]
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
+
+#[cfg(feature = "prometheus")]
+use crate::ruuvi::metrics::register;
+use crate::ruuvi::parser::RuuviMeasurement;
+
+// Below this compensated voltage the cell should be replaced. Ruuvi Station
+// uses 2.5 V above 0 °C, 2.3 V below 0 °C and 2.0 V below -20 °C.
+pub const LOW_VOLTAGE : f32 = 2.5;
+
+// A CR2477 delivers less voltage when cold. Readings below the reference
+// temperature are raised by this much per degree, up to MAX_COMPENSATION.
+const REFERENCE_TEMPERATURE : f32 = 20.0;
+const COMPENSATION_PER_DEGREE : f32 = 0.01;
+const MAX_COMPENSATION : f32 = 0.5;
+
+// Remaining capacity of a CR2477 under the tag's load by voltage at room
+// temperature. The voltage stays flat for most of the life and drops fast
+// at the end.
+const DISCHARGE_CURVE : [(f32, f32); 7] = [
+    (3.00, 100.0),
+    (2.90, 85.0),
+    (2.80, 60.0),
+    (2.70, 35.0),
+    (2.60, 15.0),
+    (2.50, 5.0),
+    (2.30, 0.0),
+];
+
+// Hourly averages kept for the trend, 30 days
+const TREND_HOURS : usize = 30 * 24;
+// The trend needs at least this much history before predicting anything
+const MIN_TREND_SPAN_SECS : u64 = 2 * 86400;
+// Predictions further away are indistinguishable from a flat trend
+const MAX_PREDICTION_SECS : u64 = 10 * 365 * 86400;
+
+// The voltage the cell would have at room temperature
+pub fn compensated_voltage(voltage : f32, temperature : Option<f32>) -> f32 {
+    let compensation = match temperature {
+        Some(temperature) if temperature < REFERENCE_TEMPERATURE =>
+            ((REFERENCE_TEMPERATURE - temperature) * COMPENSATION_PER_DEGREE).min(MAX_COMPENSATION),
+        _ => 0.0,
+    };
+    voltage + compensation
+}
+
+// Estimated state of charge in percent
+pub fn state_of_charge(voltage : f32, temperature : Option<f32>) -> f32 {
+    let voltage = compensated_voltage(voltage, temperature);
+    let (full, _) = DISCHARGE_CURVE[0];
+    if voltage >= full {
+        return 100.0;
+    }
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let ((high_voltage, high), (low_voltage, low)) = (pair[0], pair[1]);
+        if voltage >= low_voltage {
+            return low + (voltage - low_voltage) / (high_voltage - low_voltage) * (high - low);
+        }
+    }
+    0.0
+}
+
+pub fn is_low(voltage : f32, temperature : Option<f32>) -> bool {
+    compensated_voltage(voltage, temperature) < LOW_VOLTAGE
+}
+
+// Battery estimate of a measurement, None for formats without voltage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatteryStatus {
+    pub voltage: f32,
+    pub percent: f32,
+    pub low: bool,
+}
+
+impl BatteryStatus {
+    pub fn of(measurement : &dyn RuuviMeasurement) -> Option<Self> {
+        let voltage = measurement.voltage()?;
+        let temperature = measurement.temperature();
+        Some(Self {
+            voltage,
+            percent: state_of_charge(voltage, temperature),
+            low: is_low(voltage, temperature),
+        })
+    }
+}
+
+// Compensated voltage of one tag averaged per hour, to predict when the
+// battery runs low
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BatteryTrend {
+    // (start of the hour in unix seconds, sum, count), oldest first
+    hours: VecDeque<(u64, f64, u32)>,
+}
+
+impl BatteryTrend {
+    pub fn add(&mut self, time : u64, voltage : f32, temperature : Option<f32>) {
+        let hour = time / 3600 * 3600;
+        let voltage = compensated_voltage(voltage, temperature) as f64;
+        match self.hours.back_mut() {
+            Some((last, sum, count)) if *last == hour => {
+                *sum += voltage;
+                *count += 1;
+            }
+            // Late measurements from before the current hour are ignored
+            Some((last, _, _)) if *last > hour => {}
+            _ => {
+                if self.hours.len() == TREND_HOURS {
+                    self.hours.pop_front();
+                }
+                self.hours.push_back((hour, voltage, 1));
+            }
+        }
+    }
+
+    // Unix time when the compensated voltage is expected to reach
+    // LOW_VOLTAGE, by a least squares fit over the hourly averages. None
+    // without enough history or a downward trend.
+    pub fn predicted_low_time(&self) -> Option<u64> {
+        let (first, _, _) = *self.hours.front()?;
+        let (last, _, _) = *self.hours.back()?;
+        if last - first < MIN_TREND_SPAN_SECS {
+            return None;
+        }
+
+        // Relative to the first hour to keep the sums small
+        let points : Vec<(f64, f64)> = self.hours.iter()
+            .map(|(hour, sum, count)| ((hour - first) as f64, sum / *count as f64))
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let covariance : f64 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+        let variance : f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        let slope = covariance / variance;
+        if slope >= 0.0 {
+            return None;
+        }
+
+        let now = (last - first) as f64;
+        let fitted_now = mean_y + slope * (now - mean_x);
+        let remaining = ((LOW_VOLTAGE as f64 - fitted_now) / slope).max(0.0);
+        if remaining > MAX_PREDICTION_SECS as f64 {
+            return None;
+        }
+        Some(last + remaining as u64)
+    }
+}
+
+// Trends by tag MAC, shared with the tag store which saves them in the
+// snapshot
+pub type BatteryTrends = Arc<Mutex<HashMap<String, BatteryTrend>>>;
+
+#[cfg(feature = "prometheus")]
+#[derive(Clone)]
+pub struct BatteryMetrics {
+    pub voltage: GaugeVec,
+    pub percent: GaugeVec,
+    pub low: IntGaugeVec,
+    pub replacement: GaugeVec,
+}
+
+#[cfg(feature = "prometheus")]
+impl BatteryMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            voltage: register(registry, GaugeVec::new(Opts::new(
+                "ruuvi_battery_voltage",
+                "Battery voltage in volts."),
+                &["mac"])?)?,
+            percent: register(registry, GaugeVec::new(Opts::new(
+                "ruuvi_battery_percent",
+                "Estimated battery state of charge, compensated for temperature."),
+                &["mac"])?)?,
+            low: register(registry, IntGaugeVec::new(Opts::new(
+                "ruuvi_battery_low",
+                "1 when the battery should be replaced."),
+                &["mac"])?)?,
+            replacement: register(registry, GaugeVec::new(Opts::new(
+                "ruuvi_battery_replacement_timestamp_seconds",
+                "Predicted time when the battery runs low, from the voltage trend."),
+                &["mac"])?)?,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_of_charge() {
+        assert_eq!(100.0, state_of_charge(3.1, Some(20.0)));
+        assert_eq!(85.0, state_of_charge(2.9, None));
+        assert!((state_of_charge(2.75, Some(25.0)) - 47.5).abs() < 0.01);
+        assert_eq!(0.0, state_of_charge(2.0, Some(20.0)));
+
+        // The same voltage in the freezer means a fuller battery
+        assert!((state_of_charge(2.6, Some(-10.0)) - 85.0).abs() < 0.01);
+        assert!(is_low(2.45, Some(20.0)));
+        assert!(!is_low(2.45, Some(0.0)));
+        assert!(is_low(1.95, Some(-40.0)));
+    }
+
+    #[test]
+    fn test_trend_predicts_low_battery() {
+        let mut trend = BatteryTrend::default();
+        let start = 1_700_000_000 / 3600 * 3600;
+        // Loses 10 mV a day from 2.65 V, with several readings an hour
+        for hour in 0..24 {
+            for minute in [0, 20, 40] {
+                let time = start + hour * 3600 + minute * 60;
+                trend.add(time, 2.65 - 0.01 * (time - start) as f32 / 86400.0, Some(20.0));
+            }
+        }
+        // A day of history isn't enough
+        assert_eq!(None, trend.predicted_low_time());
+
+        for hour in 24..72 {
+            let time = start + hour * 3600;
+            trend.add(time, 2.65 - 0.01 * (time - start) as f32 / 86400.0, Some(20.0));
+        }
+        // 2.5 V is reached after 15 days
+        let predicted = trend.predicted_low_time().unwrap();
+        assert!((predicted as i64 - (start + 15 * 86400) as i64).abs() < 3 * 3600, "{}", predicted as i64 - start as i64);
+
+        let mut flat = BatteryTrend::default();
+        for hour in 0..72 {
+            flat.add(start + hour * 3600, 2.9, Some(20.0));
+        }
+        assert_eq!(None, flat.predicted_low_time());
+    }
+}
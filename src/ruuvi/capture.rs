@@ -0,0 +1,266 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::ruuvi::gateway::{parse_gateway_message, GatewayMessageResult};
+use crate::ruuvi::metrics::Metrics;
+use crate::ruuvi::parser::{decode_gateway_measurement, DecryptionKeys};
+use crate::ruuvi::pipeline::SinkPipeline;
+
+// One captured MQTT publish, stored as a line of JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CapturedMessage {
+    pub topic: String,
+
+    // Gateways send JSON, other payloads are stored as hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_hex: Option<String>,
+
+    // Unix seconds
+    pub received_at: f64,
+}
+
+impl CapturedMessage {
+    pub fn new(topic : &str, payload : &[u8], received_at : SystemTime) -> Self {
+        let (payload, payload_hex) = match std::str::from_utf8(payload) {
+            Ok(payload) => (Some(payload.to_string()), None),
+            Err(_) => (None, Some(payload.iter().map(|b| format!("{:02X}", b)).collect())),
+        };
+
+        Self {
+            topic: topic.to_string(),
+            payload,
+            payload_hex,
+            received_at: received_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0),
+        }
+    }
+
+    pub fn payload_bytes(&self) -> Result<Bytes, String> {
+        match (&self.payload, &self.payload_hex) {
+            (Some(payload), _) => Ok(Bytes::from(payload.clone())),
+            (None, Some(hex)) => {
+                let bytes : Result<Vec<u8>, _> = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| hex.get(i..i + 2).ok_or(()).and_then(|b| u8::from_str_radix(b, 16).map_err(|_| ())))
+                    .collect();
+                bytes.map(Bytes::from).map_err(|_| format!("invalid payload_hex: {}", hex))
+            }
+            (None, None) => Ok(Bytes::new()),
+        }
+    }
+}
+
+// Appends every incoming MQTT publish to a newline delimited JSON file
+pub struct CaptureWriter {
+    writer: BufWriter<File>,
+}
+
+impl CaptureWriter {
+    pub fn create(path : &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn write(&mut self, topic : &str, payload : &[u8], received_at : SystemTime) -> std::io::Result<()> {
+        self.append(&CapturedMessage::new(topic, payload, received_at))?;
+        self.writer.flush()
+    }
+
+    fn append(&mut self, message : &CapturedMessage) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, message)?;
+        self.writer.write_all(b"\n")
+    }
+
+    // Writes the queued messages on a blocking thread, so that the caller
+    // never waits on the disk
+    pub fn spawn(self, queue_size : usize) -> CaptureTask {
+        let (sender, receiver) = mpsc::channel(queue_size);
+        CaptureTask {
+            sender,
+            task: tokio::task::spawn_blocking(move || self.run(receiver)),
+        }
+    }
+
+    fn run(mut self, mut receiver : mpsc::Receiver<CapturedMessage>) {
+        while let Some(message) = receiver.blocking_recv() {
+            let mut result = self.append(&message);
+            // Keep the file usable if the listener is killed
+            if result.is_ok() && receiver.is_empty() {
+                result = self.writer.flush();
+            }
+            if let Err(e) = result {
+                warn!(error = %e, "couldn't write capture");
+            }
+        }
+        if let Err(e) = self.writer.flush() {
+            warn!(error = %e, "couldn't write capture");
+        }
+    }
+}
+
+// Queue of a capture writer running on a blocking thread
+pub struct CaptureTask {
+    sender: mpsc::Sender<CapturedMessage>,
+    task: JoinHandle<()>,
+}
+
+impl CaptureTask {
+    // Returns false when the queue is full and the message isn't captured
+    pub fn write(&self, topic : &str, payload : &[u8], received_at : SystemTime) -> bool {
+        self.sender.try_send(CapturedMessage::new(topic, payload, received_at)).is_ok()
+    }
+
+    // Writes what is queued and closes the file
+    pub async fn close(self) {
+        drop(self.sender);
+        if let Err(e) = self.task.await {
+            warn!(error = %e, "capture writer failed");
+        }
+    }
+}
+
+pub fn read_capture(path : &str) -> Result<Vec<CapturedMessage>, String> {
+    let file = File::open(path).map_err(|e| format!("couldn't open {}: {}", path, e))?;
+    let mut messages = Vec::new();
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("couldn't read {}: {}", path, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = serde_json::from_str(&line)
+            .map_err(|e| format!("{} line {}: {}", path, number + 1, e))?;
+        messages.push(message);
+    }
+
+    Ok(messages)
+}
+
+// Feeds captured messages through the gateway parser and decoder into the
+// pipeline. With speed 1.0 the original timing is kept, 10.0 replays ten times
+// faster and 0 as fast as possible. Format 8 needs the keys. Returns the
+// number of decoded measurements.
+pub async fn replay(messages : &[CapturedMessage], speed : f64, keys : Option<&dyn DecryptionKeys>, pipeline : &SinkPipeline, metrics : &Metrics) -> usize {
+    let start = tokio::time::Instant::now();
+    let first_received_at = messages.first().map(|message| message.received_at).unwrap_or(0.0);
+    let mut decoded = 0;
+
+    for message in messages {
+        if speed > 0.0 {
+            let offset = ((message.received_at - first_received_at) / speed).max(0.0);
+            tokio::time::sleep_until(start + Duration::from_secs_f64(offset)).await;
+        }
+
+        let payload = match message.payload_bytes() {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!(topic = %message.topic, error = %e, "skipping captured message");
+                continue;
+            }
+        };
+
+        if let GatewayMessageResult::Received(gateway_message) = parse_gateway_message(&payload, message.topic.clone(), metrics.gateway()) {
+            if let Ok(measurement) = decode_gateway_measurement(&gateway_message, keys) {
+                pipeline.send(&gateway_message, measurement).await;
+                decoded += 1;
+            }
+        }
+    }
+
+    decoded
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::{RuuviData, RuuviMeasurement, RuuviSink};
+    use crate::ruuvi::pipeline::OverflowPolicy;
+    use std::sync::{Arc, Mutex};
+
+    struct CollectingSink {
+        measurements: Arc<Mutex<Vec<(String, RuuviData)>>>,
+    }
+
+    impl RuuviSink for CollectingSink {
+        fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
+            self.measurements.lock().unwrap().push((source_mac.to_string(), measurement));
+        }
+    }
+
+    fn test_path(name : &str) -> String {
+        let path = std::env::temp_dir().join(format!("ruuvi-capture-{}-{}.ndjson", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_non_utf8_payload_is_stored_as_hex() {
+        let message = CapturedMessage::new("ruuvi/test", &[0xFF, 0x00, 0x41], UNIX_EPOCH + Duration::from_millis(1500));
+
+        assert_eq!(None, message.payload);
+        assert_eq!(Some("FF0041".to_string()), message.payload_hex);
+        assert_eq!(1.5, message.received_at);
+        assert_eq!(Bytes::from(vec![0xFF, 0x00, 0x41]), message.payload_bytes().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_capture_task_writes_queued_messages() {
+        let path = test_path("task");
+        let capture = CaptureWriter::create(&path).unwrap().spawn(10);
+        let start = SystemTime::now();
+        assert!(capture.write("ruuvi/a", b"{}", start));
+        assert!(capture.write("ruuvi/b", &[0xFF], start + Duration::from_secs(1)));
+        capture.close().await;
+
+        let messages = read_capture(&path).unwrap();
+        assert_eq!(vec!["ruuvi/a", "ruuvi/b"], messages.iter().map(|message| message.topic.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some("FF".to_string()), messages[1].payload_hex);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_capture_and_replay() {
+        let path = test_path("replay");
+        let mut writer = CaptureWriter::create(&path).unwrap();
+        let start = SystemTime::now();
+        writer.write("ruuvi/00:11:22:33:44:55/11:22:33:44:55:66",
+            br#"{"rssi":-62,"ts":"1646578374","data":"0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F"}"#, start).unwrap();
+        writer.write("ruuvi/00:11:22:33:44:55/11:22:33:44:55:66", b"not json", start + Duration::from_secs(1)).unwrap();
+        writer.write("ruuvi/00:11:22:33:44:55/AA:BB:CC:DD:EE:FF",
+            br#"{"rssi":-70,"ts":"1646578376","data":"02010611FF9904035D1929C6670029FFEA041B0B6B"}"#, start + Duration::from_secs(2)).unwrap();
+        drop(writer);
+
+        let messages = read_capture(&path).unwrap();
+        assert_eq!(3, messages.len());
+        assert_eq!("not json", messages[1].payload.as_ref().unwrap());
+
+        let measurements = Arc::new(Mutex::new(Vec::new()));
+        let metrics = Metrics::default();
+        let mut pipeline = SinkPipeline::new(&metrics);
+        pipeline.add_sink("test_replay", Box::new(CollectingSink { measurements: measurements.clone() }), 10, OverflowPolicy::Block);
+        let replay_start = std::time::Instant::now();
+        assert_eq!(2, replay(&messages, 20.0, None, &pipeline, &metrics).await);
+        // Two seconds of capture at 20x speed
+        assert!(replay_start.elapsed() >= Duration::from_millis(100));
+        pipeline.close().await;
+
+        let measurements = measurements.lock().unwrap();
+        assert_eq!("11:22:33:44:55:66", measurements[0].0);
+        assert_eq!(5, measurements[0].1.format());
+        assert_eq!("AA:BB:CC:DD:EE:FF", measurements[1].0);
+        assert_eq!(3, measurements[1].1.format());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use prometheus::{GaugeVec, Opts, Registry};
+use tracing::info;
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+
+// The offset is the largest sample of this many minutes, the one delayed
+// least on the way. A shorter backlog doesn't move it, and a gateway clock
+// set back is followed after this long.
+const WINDOW_MINUTES : u64 = 15;
+// Messages the gateway held for longer were buffered, and are likely
+// delivered late as well
+const MAX_BUFFERING_SECS : u64 = 10;
+// Gateway timestamps are whole seconds, truncated
+const RESOLUTION_MIDPOINT : f64 = 0.5;
+// Changes of the correction are logged when they are at least this large
+const LOG_CHANGE_SECS : i64 = 5;
+
+// Estimated offsets by gateway MAC, shared with the tag store which saves
+// them in the snapshot
+pub type ClockOffsets = Arc<Mutex<HashMap<String, f64>>>;
+
+#[derive(Clone)]
+pub struct ClockMetrics {
+    pub offset: GaugeVec,
+}
+
+impl ClockMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            offset: register(registry, GaugeVec::new(Opts::new(
+                "ruuvi_gateway_clock_offset_seconds",
+                "Estimated offset of the gateway clock from the listener clock, positive when the gateway is ahead."),
+                &["gateway"])?)?,
+        })
+    }
+}
+
+// Estimates the clock offset of every gateway by comparing the time it sent
+// a message (gwts, or ts with older firmware) to when the listener received
+// it. The least delayed recent message is taken, the network delay still
+// makes the estimate slightly low. With correction enabled the offset is
+// removed from the timestamps of later messages.
+pub struct ClockTracker {
+    metrics: ClockMetrics,
+    correct: bool,
+    offsets: ClockOffsets,
+    // Largest sample per minute by gateway, oldest first
+    samples: HashMap<String, VecDeque<(u64, f64)>>,
+    // Last logged correction per gateway
+    logged: HashMap<String, i64>,
+}
+
+impl ClockTracker {
+    pub fn new(metrics : &Metrics, correct : bool) -> Self {
+        Self {
+            metrics: metrics.clock().clone(),
+            correct,
+            offsets: ClockOffsets::default(),
+            samples: HashMap::new(),
+            logged: HashMap::new(),
+        }
+    }
+
+    // Starts from offsets, such as those restored by the tag store
+    pub fn with_offsets(mut self, offsets : ClockOffsets) -> Self {
+        for (gateway_mac, offset) in offsets.lock().unwrap().iter() {
+            self.metrics.offset.with_label_values(&[gateway_mac]).set(*offset);
+        }
+        self.offsets = offsets;
+        self
+    }
+
+    pub fn offset(&self, gateway_mac : &str) -> Option<f64> {
+        self.offsets.lock().unwrap().get(gateway_mac).copied()
+    }
+
+    fn add_sample(&mut self, gateway_mac : &str, minute : u64, sample : f64) -> f64 {
+        let mut offsets = self.offsets.lock().unwrap();
+        let samples = self.samples.entry(gateway_mac.to_string()).or_insert_with(|| {
+            // A restored offset counts as a sample until the window has passed
+            offsets.get(gateway_mac).map(|offset| (minute, *offset)).into_iter().collect()
+        });
+        match samples.back_mut() {
+            Some((last, largest)) if *last == minute => *largest = largest.max(sample),
+            _ => samples.push_back((minute, sample)),
+        }
+        while samples.front().is_some_and(|(first, _)| first + WINDOW_MINUTES <= minute) {
+            samples.pop_front();
+        }
+
+        let offset = samples.iter().map(|(_, sample)| *sample).fold(f64::MIN, f64::max);
+        offsets.insert(gateway_mac.to_string(), offset);
+        offset
+    }
+
+    pub fn observe(&mut self, message : &mut RuuviGatewayMessage) {
+        let Some(sent) = message.raw_gateway_timestamp().or_else(|| message.raw_timestamp()) else { return };
+        let Some(received_at) = message.received_at.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) else { return };
+        let buffered = message.raw_timestamp().zip(message.raw_gateway_timestamp())
+            .is_some_and(|(ts, gwts)| gwts.saturating_sub(ts) > MAX_BUFFERING_SECS);
+
+        let offset = if buffered {
+            match self.offset(&message.gateway_mac) {
+                Some(offset) => offset,
+                None => return,
+            }
+        } else {
+            let sample = sent as f64 + RESOLUTION_MIDPOINT - received_at.as_secs_f64();
+            let offset = self.add_sample(&message.gateway_mac, received_at.as_secs() / 60, sample);
+            self.metrics.offset.with_label_values(&[&message.gateway_mac]).set(offset);
+            offset
+        };
+
+        if self.correct {
+            let correction = -offset.round() as i64;
+            let logged = self.logged.entry(message.gateway_mac.clone()).or_insert(0);
+            if (correction - *logged).abs() >= LOG_CHANGE_SECS {
+                info!(gateway = %message.gateway_mac, offset, correction, "correcting gateway clock");
+                *logged = correction;
+            }
+            message.clock_correction = correction;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn message(gateway_mac : &str, ts : u64, gwts : Option<u64>, received_at : f64) -> RuuviGatewayMessage {
+        RuuviGatewayMessage {
+            mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            gateway_mac: gateway_mac.to_string(),
+            ts: ts.to_string().into(),
+            gwts: gwts.map(|gwts| gwts.to_string()).unwrap_or_default().into(),
+            received_at: Some(UNIX_EPOCH + Duration::from_secs_f64(received_at)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_offset_ignores_delayed_messages() {
+        let metrics = Metrics::default();
+        let mut tracker = ClockTracker::new(&metrics, false);
+
+        // The gateway clock is two minutes ahead
+        let mut first = message("11:11:11:11:11:11", 1120, Some(1120), 1000.5);
+        tracker.observe(&mut first);
+        assert_eq!(Some(120.0), tracker.offset("11:11:11:11:11:11"));
+        assert_eq!(Some(1120), first.timestamp());
+
+        // A backlog delivered minutes late doesn't move the estimate
+        for delay in [300.0, 240.0, 180.0] {
+            tracker.observe(&mut message("11:11:11:11:11:11", 1130, Some(1130), 1010.5 + delay));
+        }
+        assert_eq!(Some(120.0), tracker.offset("11:11:11:11:11:11"));
+        assert_eq!(120.0, metrics.clock().offset.with_label_values(&["11:11:11:11:11:11"]).get());
+
+        // Older firmware without gwts
+        tracker.observe(&mut message("22:22:22:22:22:22", 995, None, 1000.5));
+        assert_eq!(Some(-5.0), tracker.offset("22:22:22:22:22:22"));
+    }
+
+    #[test]
+    fn test_offset_follows_clock_set_back() {
+        let metrics = Metrics::default();
+        let mut tracker = ClockTracker::new(&metrics, false);
+        tracker.observe(&mut message("11:11:11:11:11:11", 1120, Some(1120), 1000.5));
+
+        // Set right by NTP, followed once the old samples are out of the window
+        tracker.observe(&mut message("11:11:11:11:11:11", 1100, Some(1100), 1100.5));
+        assert_eq!(Some(120.0), tracker.offset("11:11:11:11:11:11"));
+        let later = 1000 + WINDOW_MINUTES * 60 + 60;
+        tracker.observe(&mut message("11:11:11:11:11:11", later + 2, Some(later + 2), later as f64 + 0.5));
+        assert_eq!(Some(2.0), tracker.offset("11:11:11:11:11:11"));
+    }
+
+    #[test]
+    fn test_buffered_messages_are_skipped() {
+        let metrics = Metrics::default();
+        let mut tracker = ClockTracker::new(&metrics, true);
+
+        // Held by the gateway, no estimate and no correction from it
+        let mut buffered = message("11:11:11:11:11:11", 1060, Some(1120), 1000.5);
+        tracker.observe(&mut buffered);
+        assert_eq!(None, tracker.offset("11:11:11:11:11:11"));
+        assert_eq!(0, buffered.clock_correction);
+
+        tracker.observe(&mut message("11:11:11:11:11:11", 1119, Some(1120), 1000.5));
+        let mut buffered = message("11:11:11:11:11:11", 1070, Some(1130), 1010.5);
+        tracker.observe(&mut buffered);
+        assert_eq!(-120, buffered.clock_correction);
+    }
+
+    #[test]
+    fn test_timestamps_are_corrected() {
+        let metrics = Metrics::default();
+        let mut tracker = ClockTracker::new(&metrics, true);
+
+        let mut message = message("11:11:11:11:11:11", 1118, Some(1120), 1000.5);
+        tracker.observe(&mut message);
+        assert_eq!(-120, message.clock_correction);
+        assert_eq!(Some(998), message.timestamp());
+        assert_eq!(Some(1000), message.gateway_timestamp());
+        assert_eq!(Some(1118), message.raw_timestamp());
+    }
+
+    #[test]
+    fn test_estimate_starts_from_restored_offsets() {
+        let metrics = Metrics::default();
+        let offsets = ClockOffsets::default();
+        offsets.lock().unwrap().insert("11:11:11:11:11:11".to_string(), 120.0);
+        let mut tracker = ClockTracker::new(&metrics, false).with_offsets(offsets.clone());
+        assert_eq!(120.0, metrics.clock().offset.with_label_values(&["11:11:11:11:11:11"]).get());
+
+        // A delayed first message after the restart
+        tracker.observe(&mut message("11:11:11:11:11:11", 1130, Some(1130), 1040.5));
+        assert_eq!(120.0, offsets.lock().unwrap()["11:11:11:11:11:11"]);
+    }
+}
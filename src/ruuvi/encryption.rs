@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+#[cfg(feature = "prometheus")]
+use prometheus::{CounterVec, Opts, Registry};
+
+#[cfg(feature = "prometheus")]
+use crate::ruuvi::metrics::register;
+#[cfg(feature = "prometheus")]
+use crate::ruuvi::parser::DecodeError;
+use crate::ruuvi::parser::{decode_hex, DecryptionKeys};
+
+// AES-128 keys of tags sending encrypted data format 8, by tag MAC
+#[derive(Clone, Default)]
+pub struct TagKeys {
+    keys: HashMap<[u8; 6], Aes128>,
+}
+
+impl TagKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, mac : [u8; 6], key : [u8; 16]) {
+        self.keys.insert(mac, Aes128::new(&key.into()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // Parses lines of a MAC and a key in hex separated by whitespace, such as
+    // "AA:BB:CC:DD:EE:FF 00112233445566778899AABBCCDDEEFF". Empty lines and
+    // lines starting with # are skipped.
+    pub fn parse(s : &str) -> Result<Self, String> {
+        let mut keys = Self::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (mac, key) = line.split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: expected a MAC and a key", number + 1))?;
+            let mac = decode_hex(&mac.replace(':', "")).ok()
+                .and_then(|mac| <[u8; 6]>::try_from(mac).ok())
+                .ok_or_else(|| format!("line {}: invalid MAC {:?}", number + 1, mac))?;
+            // Not echoed, the line holds a secret
+            let key = decode_hex(key.trim()).ok()
+                .and_then(|key| <[u8; 16]>::try_from(key).ok())
+                .ok_or_else(|| format!("line {}: key must be 32 hex digits", number + 1))?;
+            keys.insert(mac, key);
+        }
+        Ok(keys)
+    }
+
+    // Loads a key file, which must not be accessible to other users
+    pub fn load(path : impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = metadata.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(format!("{} is accessible to other users (mode {:o}), restrict it to the owner", path.display(), mode & 0o777));
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = metadata;
+        let contents = fs::read_to_string(path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+// Keys are never printed
+impl std::fmt::Debug for TagKeys {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TagKeys").field("tags", &self.keys.len()).finish()
+    }
+}
+
+impl DecryptionKeys for TagKeys {
+    fn decrypt(&self, mac : &[u8; 6], block : &mut [u8; 16]) -> bool {
+        match self.keys.get(mac) {
+            Some(cipher) => {
+                cipher.decrypt_block(block.into());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+#[derive(Clone)]
+pub struct DecryptionMetrics {
+    pub failures: CounterVec,
+}
+
+#[cfg(feature = "prometheus")]
+impl DecryptionMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            failures: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_decryption_failure_count",
+                "Number of encrypted advertisements which couldn't be decrypted."),
+                &["mac", "reason"])?)?,
+        })
+    }
+
+    // Counts the error if it's a decryption failure
+    pub fn observe(&self, mac : &str, error : &DecodeError) {
+        let reason = match error {
+            DecodeError::MissingKey => "missing_key",
+            DecodeError::CrcMismatch { .. } => "crc_mismatch",
+            _ => return,
+        };
+        self.failures.with_label_values(&[mac, reason]).inc();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncrypt;
+    use crate::ruuvi::parser::{try_decode_ruuvi_with_keys, RuuviData, RuuviMeasurement};
+
+    const KEY : &str = "000102030405060708090A0B0C0D0E0F";
+
+    #[test]
+    fn test_parse_key_file() {
+        let keys = TagKeys::parse(&format!("# comment\n\nCB:B8:33:4C:88:4F {}\naabbccddeeff {}\n", KEY, KEY)).unwrap();
+        assert_eq!(2, keys.len());
+
+        assert!(TagKeys::parse("CB:B8:33:4C:88:4F").unwrap_err().contains("line 1"));
+        assert!(TagKeys::parse("CB:B8:33:4C:88 0011").unwrap_err().contains("invalid MAC"));
+        let error = TagKeys::parse("CB:B8:33:4C:88:4F 0011").unwrap_err();
+        assert!(error.contains("32 hex digits"));
+        assert!(!error.contains("0011"));
+    }
+
+    #[test]
+    fn test_decrypts_format_8() {
+        // FIPS-197 example: this plaintext encrypts to this ciphertext under KEY
+        let keys = TagKeys::parse(&format!("CB:B8:33:4C:88:4F {}", KEY)).unwrap();
+        let mut block = [0u8; 16];
+        block.copy_from_slice(&decode_hex("69C4E0D86A7B0430D8CDB78070B4C55A").unwrap());
+        assert!(keys.decrypt(&[0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F], &mut block));
+        assert_eq!(decode_hex("00112233445566778899AABBCCDDEEFF").unwrap(), block);
+        assert!(!keys.decrypt(&[0; 6], &mut block));
+
+        // A tag encrypting the C5 test vector
+        let mut plain = [0u8; 16];
+        plain[..11].copy_from_slice(&decode_hex("12FC5394C37CAC364200CD").unwrap());
+        let crc = 0xBC;
+        let mut encrypted = plain;
+        Aes128::new(&decode_hex(KEY).map(|key| <[u8; 16]>::try_from(key).unwrap()).unwrap().into())
+            .encrypt_block((&mut encrypted).into());
+        let hex = encrypted.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+        let advertisement = decode_hex(&format!("0201061BFF990408{}{:02X}CBB8334C884F", hex, crc)).unwrap();
+
+        match try_decode_ruuvi_with_keys(&advertisement, Some(&keys)) {
+            Ok(RuuviData::V8(data)) => {
+                assert_eq!(data.pressure, Some(100044));
+                assert_eq!(data.measurement_sequence(), Some(205));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_counts_failures() {
+        let metrics = DecryptionMetrics::new(&Registry::new()).unwrap();
+        metrics.observe("CB:B8:33:4C:88:4F", &DecodeError::MissingKey);
+        metrics.observe("CB:B8:33:4C:88:4F", &DecodeError::CrcMismatch { expected: 1, actual: 2 });
+        metrics.observe("CB:B8:33:4C:88:4F", &DecodeError::UnknownFormat(0x09));
+
+        assert_eq!(1.0, metrics.failures.with_label_values(&["CB:B8:33:4C:88:4F", "missing_key"]).get());
+        assert_eq!(1.0, metrics.failures.with_label_values(&["CB:B8:33:4C:88:4F", "crc_mismatch"]).get());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rejects_readable_key_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("ruuvi-keys-{}.txt", std::process::id()));
+        fs::write(&path, format!("CB:B8:33:4C:88:4F {}\n", KEY)).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(TagKeys::load(&path).unwrap_err().contains("accessible to other users"));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(1, TagKeys::load(&path).unwrap().len());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
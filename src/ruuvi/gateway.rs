@@ -1,32 +1,95 @@
 use serde::{Deserialize};
 //use serde_json::Result;
+#[cfg(feature = "prometheus")]
 use bytes::Bytes;
+use std::time::SystemTime;
 //use std::{error::Error, fmt};
 
-use prometheus::{CounterVec};
-use prometheus::{register_counter_vec};
+#[cfg(feature = "prometheus")]
+use prometheus::{CounterVec, Opts, Registry};
 use lazy_static::lazy_static;
+#[cfg(feature = "prometheus")]
+use tracing::{trace, warn};
 use regex::Regex;
 
-lazy_static! {
-    static ref GATEWAY_SERDE_ERROR: CounterVec = register_counter_vec!(
-        "ruuvi_gateway_serde_error_count",
-        "Number of messages which could not be parsed.",
-        &["reason"]
-    ).unwrap();
+#[cfg(feature = "prometheus")]
+use crate::ruuvi::metrics::register;
 
+lazy_static! {
     static ref SOURCE_MAC_RE : Regex = Regex::new(".+(([0-9A-Fa-f]{2}:){5}([0-9A-Fa-f]{2}))$").unwrap();
+
+    static ref GATEWAY_MAC_RE : Regex = Regex::new("(([0-9A-Fa-f]{2}:){5}([0-9A-Fa-f]{2}))/([0-9A-Fa-f]{2}:){5}([0-9A-Fa-f]{2})$").unwrap();
 }
 
 
-#[derive(Deserialize, Debug, Clone)]
+#[cfg(feature = "prometheus")]
+#[derive(Clone)]
+pub struct GatewayMetrics {
+    pub serde_errors: CounterVec,
+}
+
+#[cfg(feature = "prometheus")]
+impl GatewayMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            serde_errors: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_gateway_serde_error_count",
+                "Number of messages which could not be parsed."),
+                &["reason"])?)?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct RuuviGatewayMessage {
     pub rssi: i16,
     pub ts : Box<str>,
+    // When the gateway sent the message, it may buffer advertisements
+    #[serde(default)]
+    pub gwts : Box<str>,
     pub data : Box<str>,
 
     #[serde(skip_deserializing)]
     pub mac: String,
+
+    // Newer gateway firmware sends its MAC in the payload, older ones only in the topic
+    #[serde(default, rename = "gw_mac")]
+    pub gateway_mac: String,
+
+    // Local time when the listener received the message
+    #[serde(skip_deserializing)]
+    pub received_at: Option<SystemTime>,
+
+    // Site of the broker the message came from, empty with a single broker
+    // configured the old way
+    #[serde(skip_deserializing)]
+    pub site: String,
+
+    // Seconds added to the gateway timestamps to correct a drifting gateway
+    // clock, see ClockTracker
+    #[serde(skip_deserializing)]
+    pub clock_correction: i64,
+}
+
+impl RuuviGatewayMessage {
+    // Gateway reception time as unix seconds, corrected for the gateway clock
+    pub fn timestamp(&self) -> Option<u64> {
+        self.raw_timestamp().map(|ts| ts.saturating_add_signed(self.clock_correction))
+    }
+
+    // Gateway send time as unix seconds, corrected for the gateway clock
+    pub fn gateway_timestamp(&self) -> Option<u64> {
+        self.raw_gateway_timestamp().map(|gwts| gwts.saturating_add_signed(self.clock_correction))
+    }
+
+    // The timestamps as the gateway sent them
+    pub fn raw_timestamp(&self) -> Option<u64> {
+        self.ts.parse().ok()
+    }
+
+    pub fn raw_gateway_timestamp(&self) -> Option<u64> {
+        self.gwts.parse().ok()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -36,29 +99,60 @@ pub enum GatewayMessageResult {
 }
 
 
-pub fn parse_gateway_message(bytes : &Bytes, topic : String) -> GatewayMessageResult {
-    let str = match std::str::from_utf8(bytes) {
-        Ok(str) => str,
-        Err(e) => {
-            println!("couldn't parse utf8: {:?}", e);
-            GATEWAY_SERDE_ERROR.with_label_values(&[&"utf8"]).inc();
-            return GatewayMessageResult::None()
-        },
-    };
-    let message: RuuviGatewayMessage = match serde_json::from_str(&str) {
-        Ok::<RuuviGatewayMessage, serde_json::Error>(mut message) => {
-            message.mac = parse_source_mac(&topic).to_string();
-            message
-        },
+#[derive(Debug)]
+pub enum GatewayMessageError {
+    Utf8(std::str::Utf8Error),
+    Json(serde_json::Error),
+}
+
+impl GatewayMessageError {
+    // Label used in the serde error metric
+    pub fn reason(&self) -> &'static str {
+        match self {
+            GatewayMessageError::Utf8(_) => "utf8",
+            GatewayMessageError::Json(_) => "json",
+        }
+    }
+}
+
+impl std::fmt::Display for GatewayMessageError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GatewayMessageError::Utf8(e) => write!(f, "couldn't parse gateway message as utf8: {}", e),
+            GatewayMessageError::Json(e) => write!(f, "couldn't parse gateway message json: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GatewayMessageError {}
+
+// Parses the JSON a gateway publishes for one advertisement. The tag MAC and
+// for older firmware the gateway MAC come from the topic.
+pub fn try_parse_gateway_message(bytes : &[u8], topic : &str) -> Result<RuuviGatewayMessage, GatewayMessageError> {
+    let str = std::str::from_utf8(bytes).map_err(GatewayMessageError::Utf8)?;
+    let mut message : RuuviGatewayMessage = serde_json::from_str(str).map_err(GatewayMessageError::Json)?;
+
+    message.mac = parse_source_mac(topic).to_string();
+    message.received_at = Some(SystemTime::now());
+    if message.gateway_mac.is_empty() {
+        message.gateway_mac = parse_gateway_mac(topic).unwrap_or_default().to_string();
+    }
+    Ok(message)
+}
+
+#[cfg(feature = "prometheus")]
+pub fn parse_gateway_message(bytes : &Bytes, topic : String, metrics : &GatewayMetrics) -> GatewayMessageResult {
+    match try_parse_gateway_message(bytes, &topic) {
+        Ok(message) => {
+            trace!(?message, "received gateway message");
+            GatewayMessageResult::Received(message)
+        }
         Err(e) => {
-            println!("json read error: {:?}", e);
-            GATEWAY_SERDE_ERROR.with_label_values(&[&"json"]).inc();
-            return GatewayMessageResult::None()
-        },
-    };
-    println!("Received RuuviGatewayMessage: {:?}", message);
-
-    return GatewayMessageResult::Received(message)
+            warn!(topic = %topic, error = %e, "couldn't parse gateway message");
+            metrics.serde_errors.with_label_values(&[e.reason()]).inc();
+            GatewayMessageResult::None()
+        }
+    }
 }
 
 fn parse_source_mac(topic : &str) -> &str {
@@ -70,6 +164,13 @@ fn parse_source_mac(topic : &str) -> &str {
     }
 }
 
+// Topics are of the form ruuvi/<gateway mac>/<tag mac>
+fn parse_gateway_mac(topic : &str) -> Option<&str> {
+    GATEWAY_MAC_RE.captures(topic).and_then(|cap| {
+        cap.get(1).map(|gateway_mac| gateway_mac.as_str())
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -82,4 +183,49 @@ mod tests {
         assert_eq!("11:22:33:44:55:66", parse_source_mac("ruuvi/11:22:33:44:55:66"));
         assert_eq!("11:22:33:44:55:66", parse_source_mac("ruuvi/asdf/asdf/asdf/11:22:33:44:55:66"));
     }
+
+    #[test]
+    fn test_gateway_mac_parsing() {
+        assert_eq!(Some("00:11:22:33:44:55"), parse_gateway_mac("ruuvi/00:11:22:33:44:55/11:22:33:44:55:66"));
+        assert_eq!(None, parse_gateway_mac("ruuvi/11:22:33:44:55:66"));
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_parse_gateway_message() {
+        let metrics = GatewayMetrics::new(&Registry::new()).unwrap();
+        let payload = Bytes::from(r#"{"gw_mac":"AA:BB:CC:DD:EE:FF","rssi":-62,"aoa":[],"gwts":"1646578375","ts":"1646578374","data":"0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F","coords":""}"#);
+
+        let message = match parse_gateway_message(&payload, "ruuvi/00:11:22:33:44:55/11:22:33:44:55:66".to_string(), &metrics) {
+            GatewayMessageResult::Received(message) => message,
+            _ => panic!("message was not parsed"),
+        };
+
+        assert_eq!(-62, message.rssi);
+        assert_eq!("11:22:33:44:55:66", message.mac);
+        assert_eq!("AA:BB:CC:DD:EE:FF", message.gateway_mac);
+        assert_eq!(Some(1646578374), message.timestamp());
+        assert_eq!(Some(1646578375), message.gateway_timestamp());
+
+        let payload = Bytes::from(r#"{"rssi":-62,"ts":"1646578374","data":"0201"}"#);
+        let message = match parse_gateway_message(&payload, "ruuvi/00:11:22:33:44:55/11:22:33:44:55:66".to_string(), &metrics) {
+            GatewayMessageResult::Received(message) => message,
+            _ => panic!("message was not parsed"),
+        };
+        assert_eq!("00:11:22:33:44:55", message.gateway_mac);
+        assert_eq!(None, message.gateway_timestamp());
+
+        let payload = Bytes::from("not json");
+        assert!(matches!(parse_gateway_message(&payload, "ruuvi/11:22:33:44:55:66".to_string(), &metrics), GatewayMessageResult::None()));
+        assert_eq!(1.0, metrics.serde_errors.with_label_values(&["json"]).get());
+    }
+
+    #[test]
+    fn test_try_parse_gateway_message() {
+        let message = try_parse_gateway_message(br#"{"rssi":-70,"ts":"1","data":"0201"}"#, "ruuvi/11:22:33:44:55:66").unwrap();
+        assert_eq!("11:22:33:44:55:66", message.mac);
+
+        assert_eq!("utf8", try_parse_gateway_message(&[0xFF, 0xFE], "ruuvi/11:22:33:44:55:66").unwrap_err().reason());
+        assert_eq!("json", try_parse_gateway_message(b"{}", "ruuvi/11:22:33:44:55:66").unwrap_err().reason());
+    }
 }
@@ -0,0 +1,608 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rumqttc::QoS;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::warn;
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::Metrics;
+use crate::ruuvi::mqtt::{fixed_topic_prefix, render_topic, MqttClient, MqttOutputConfig, MqttOutputSink};
+use crate::ruuvi::parser::{RuuviData, RuuviMeasurement, RuuviSink};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HomeAssistantConfig {
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+
+    // Topic with the decoded JSON measurements, see the mqtt sink
+    #[serde(default = "default_state_topic")]
+    pub state_topic: String,
+
+    // Set to false when an mqtt sink already publishes to state_topic
+    #[serde(default = "default_true")]
+    pub publish_state: bool,
+
+    #[serde(default = "default_availability_topic")]
+    pub availability_topic: String,
+
+    // Online while the listener runs, set to offline by the broker as the
+    // last will of the connection when the listener dies
+    #[serde(default = "default_listener_availability_topic")]
+    pub listener_availability_topic: String,
+
+    // Tags not heard from in this time are reported offline
+    #[serde(default = "default_stale_after_secs")]
+    pub stale_after_secs: u64,
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_state_topic() -> String {
+    "ruuvi/decoded/{tag_mac}".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_availability_topic() -> String {
+    "ruuvi/decoded/{tag_mac}/availability".to_string()
+}
+
+fn default_listener_availability_topic() -> String {
+    "ruuvi/listener/availability".to_string()
+}
+
+fn default_stale_after_secs() -> u64 {
+    300
+}
+
+impl HomeAssistantConfig {
+    // Fixed topic prefixes this sink publishes to
+    pub fn topic_prefixes(&self) -> Vec<String> {
+        vec![
+            format!("{}/", self.discovery_prefix),
+            fixed_topic_prefix(&self.state_topic).to_string(),
+            fixed_topic_prefix(&self.availability_topic).to_string(),
+            self.listener_availability_topic.clone(),
+        ]
+    }
+}
+
+struct SensorField {
+    key: &'static str,
+    // Measurement field the sensor reads, None for the gateway's RSSI
+    field: Option<&'static str>,
+    name: &'static str,
+    value_template: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+    state_class: &'static str,
+    entity_category: Option<&'static str>,
+    icon: Option<&'static str>,
+}
+
+const SENSOR_FIELDS : &[SensorField] = &[
+    SensorField {
+        key: "temperature",
+        field: Some("temperature"),
+        name: "Temperature",
+        value_template: "{{ value_json.temperature | round(2) }}",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: None,
+    },
+    SensorField {
+        key: "humidity",
+        field: Some("humidity"),
+        name: "Humidity",
+        value_template: "{{ value_json.humidity | round(2) }}",
+        device_class: Some("humidity"),
+        unit: Some("%"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: None,
+    },
+    SensorField {
+        key: "pressure",
+        field: Some("pressure"),
+        name: "Pressure",
+        value_template: "{{ (value_json.pressure / 100) | round(2) }}",
+        device_class: Some("pressure"),
+        unit: Some("hPa"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: None,
+    },
+    SensorField {
+        key: "battery_voltage",
+        field: Some("voltage"),
+        name: "Battery voltage",
+        value_template: "{{ value_json.voltage | round(3) }}",
+        device_class: Some("voltage"),
+        unit: Some("V"),
+        state_class: "measurement",
+        entity_category: Some("diagnostic"),
+        icon: None,
+    },
+    SensorField {
+        key: "rssi",
+        field: None,
+        name: "Signal strength",
+        value_template: "{{ value_json.rssi }}",
+        device_class: Some("signal_strength"),
+        unit: Some("dBm"),
+        state_class: "measurement",
+        entity_category: Some("diagnostic"),
+        icon: None,
+    },
+    SensorField {
+        key: "movement",
+        field: Some("movement"),
+        name: "Movement counter",
+        value_template: "{{ value_json.movement }}",
+        device_class: None,
+        unit: None,
+        state_class: "measurement",
+        entity_category: None,
+        icon: Some("mdi:run"),
+    },
+    SensorField {
+        key: "pm1_0",
+        field: Some("pm1_0"),
+        name: "PM1.0",
+        value_template: "{{ value_json.pm1_0 }}",
+        device_class: Some("pm1"),
+        unit: Some("µg/m³"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: None,
+    },
+    SensorField {
+        key: "pm2_5",
+        field: Some("pm2_5"),
+        name: "PM2.5",
+        value_template: "{{ value_json.pm2_5 }}",
+        device_class: Some("pm25"),
+        unit: Some("µg/m³"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: None,
+    },
+    SensorField {
+        key: "pm4_0",
+        field: Some("pm4_0"),
+        name: "PM4.0",
+        value_template: "{{ value_json.pm4_0 }}",
+        device_class: None,
+        unit: Some("µg/m³"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: Some("mdi:blur"),
+    },
+    SensorField {
+        key: "pm10_0",
+        field: Some("pm10_0"),
+        name: "PM10",
+        value_template: "{{ value_json.pm10_0 }}",
+        device_class: Some("pm10"),
+        unit: Some("µg/m³"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: None,
+    },
+    SensorField {
+        key: "co2",
+        field: Some("co2"),
+        name: "CO2",
+        value_template: "{{ value_json.co2 }}",
+        device_class: Some("carbon_dioxide"),
+        unit: Some("ppm"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: None,
+    },
+    SensorField {
+        key: "voc_index",
+        field: Some("voc_index"),
+        name: "VOC index",
+        value_template: "{{ value_json.voc_index }}",
+        device_class: None,
+        unit: None,
+        state_class: "measurement",
+        entity_category: None,
+        icon: Some("mdi:air-filter"),
+    },
+    SensorField {
+        key: "nox_index",
+        field: Some("nox_index"),
+        name: "NOx index",
+        value_template: "{{ value_json.nox_index }}",
+        device_class: None,
+        unit: None,
+        state_class: "measurement",
+        entity_category: None,
+        icon: Some("mdi:air-filter"),
+    },
+    SensorField {
+        key: "luminosity",
+        field: Some("luminosity"),
+        name: "Illuminance",
+        value_template: "{{ value_json.luminosity | round(1) }}",
+        device_class: Some("illuminance"),
+        unit: Some("lx"),
+        state_class: "measurement",
+        entity_category: None,
+        icon: None,
+    },
+];
+
+fn object_id(mac : &str) -> String {
+    format!("ruuvi_{}", mac.replace(':', "").to_lowercase())
+}
+
+// Discovery documents for the sensors with a value in data as (topic,
+// payload) pairs
+pub fn discovery_documents(config : &HomeAssistantConfig, message : &RuuviGatewayMessage, name : Option<&str>, data : &RuuviData) -> Vec<(String, String)> {
+    new_discovery_documents(config, message, name, data, &mut HashSet::new())
+}
+
+// Discovery documents for the sensors with a value in data which aren't in
+// discovered yet, and adds them. Invalid values are missing from a
+// measurement, so a sensor can first appear in a later one.
+fn new_discovery_documents(config : &HomeAssistantConfig, message : &RuuviGatewayMessage, name : Option<&str>, data : &RuuviData, discovered : &mut HashSet<&'static str>) -> Vec<(String, String)> {
+    let fields = data.fields();
+    let new_fields : Vec<&SensorField> = SENSOR_FIELDS.iter()
+        .filter(|field| !discovered.contains(field.key))
+        .filter(|field| field.field.is_none_or(|key| fields.iter().any(|(name, _)| *name == key)))
+        .collect();
+    if new_fields.is_empty() {
+        return Vec::new();
+    }
+    discovered.extend(new_fields.iter().map(|field| field.key));
+
+    let object_id = object_id(&message.mac);
+    let device_name = match name {
+        Some(name) => name.to_string(),
+        None => format!("Ruuvi {}", message.mac),
+    };
+    let device = json!({
+        "identifiers": [object_id],
+        "connections": [["mac", message.mac.to_lowercase()]],
+        "name": device_name,
+        "manufacturer": "Ruuvi Innovations",
+        "model": match data.format() {
+            0x06 | 0xE1 => "Ruuvi Air",
+            _ => "RuuviTag",
+        },
+    });
+    let state_topic = render_topic(&config.state_topic, message, name);
+    let availability_topic = render_topic(&config.availability_topic, message, name);
+
+    new_fields.into_iter().map(|field| {
+        let mut document = json!({
+            "name": field.name,
+            "unique_id": format!("{}_{}", object_id, field.key),
+            "object_id": format!("{}_{}", object_id, field.key),
+            "state_topic": state_topic,
+            "value_template": field.value_template,
+            "state_class": field.state_class,
+            "availability": [
+                { "topic": availability_topic },
+                { "topic": config.listener_availability_topic },
+            ],
+            "availability_mode": "all",
+            "payload_available": "online",
+            "payload_not_available": "offline",
+            "device": device,
+        });
+        if let Some(device_class) = field.device_class {
+            document["device_class"] = json!(device_class);
+        }
+        if let Some(unit) = field.unit {
+            document["unit_of_measurement"] = json!(unit);
+        }
+        if let Some(entity_category) = field.entity_category {
+            document["entity_category"] = json!(entity_category);
+        }
+        if let Some(icon) = field.icon {
+            document["icon"] = json!(icon);
+        }
+
+        let topic = format!("{}/sensor/{}/{}/config", config.discovery_prefix, object_id, field.key);
+        (topic, document.to_string())
+    }).collect()
+}
+
+struct TagAvailability {
+    availability_topic: String,
+    // Keys of the sensors published for discovery
+    discovered: HashSet<&'static str>,
+    last_seen: Instant,
+    online: bool,
+}
+
+type Tags = Arc<Mutex<HashMap<String, TagAvailability>>>;
+
+// Tags which haven't been seen within stale_after. Marks them offline and
+// returns their availability topics.
+fn mark_stale_tags(tags : &mut HashMap<String, TagAvailability>, now : Instant, stale_after : Duration) -> Vec<String> {
+    tags.values_mut()
+        .filter(|tag| tag.online && now.duration_since(tag.last_seen) >= stale_after)
+        .map(|tag| {
+            tag.online = false;
+            tag.availability_topic.clone()
+        })
+        .collect()
+}
+
+fn publish(client : &MqttClient, topic : String, payload : String) {
+    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+        warn!(error = %e, "couldn't publish to Home Assistant");
+    }
+}
+
+// Publishes Home Assistant MQTT discovery documents for every new sensor and
+// keeps the tag availability topics up to date.
+pub struct HomeAssistantSink {
+    client: MqttClient,
+    config: HomeAssistantConfig,
+    names: HashMap<String, String>,
+    state: Option<MqttOutputSink>,
+    tags: Tags,
+}
+
+impl HomeAssistantSink {
+    // Must be called from within a Tokio runtime.
+    pub fn new(client : impl Into<MqttClient>, config : HomeAssistantConfig, names : HashMap<String, String>, metrics : &Metrics) -> Self {
+        let client = client.into();
+        let state = if config.publish_state {
+            let state_config = MqttOutputConfig {
+                topic: config.state_topic.clone(),
+                retain: false,
+                qos: 0,
+            };
+            Some(MqttOutputSink::new(client.clone(), state_config, names.clone(), metrics))
+        } else {
+            None
+        };
+
+        // Retained, so that it replaces the last will of an earlier run
+        publish(&client, config.listener_availability_topic.clone(), "online".to_string());
+
+        let tags : Tags = Arc::new(Mutex::new(HashMap::new()));
+        let stale_after = Duration::from_secs(config.stale_after_secs);
+        let check_tags = Arc::downgrade(&tags);
+        let check_client = client.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval((stale_after / 10).max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                let tags = match check_tags.upgrade() {
+                    Some(tags) => tags,
+                    None => return,
+                };
+                let stale = mark_stale_tags(&mut tags.lock().unwrap(), Instant::now(), stale_after);
+                for topic in stale {
+                    publish(&check_client, topic, "offline".to_string());
+                }
+            }
+        });
+
+        Self {
+            client,
+            config,
+            names,
+            state,
+            tags,
+        }
+    }
+}
+
+impl RuuviSink for HomeAssistantSink {
+    fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
+        let message = RuuviGatewayMessage {
+            mac: source_mac.to_string(),
+            ..Default::default()
+        };
+        self.sink_message(&message, measurement);
+    }
+
+    fn sink_message(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        let mac = message.mac.to_uppercase();
+        let name = self.names.get(&mac).map(|name| name.as_str());
+
+        let mut tags = self.tags.lock().unwrap();
+        let tag = tags.entry(mac).or_insert_with(|| TagAvailability {
+            availability_topic: render_topic(&self.config.availability_topic, message, name),
+            discovered: HashSet::new(),
+            last_seen: Instant::now(),
+            online: false,
+        });
+        for (topic, payload) in new_discovery_documents(&self.config, message, name, &measurement, &mut tag.discovered) {
+            publish(&self.client, topic, payload);
+        }
+        tag.last_seen = Instant::now();
+        if !tag.online {
+            tag.online = true;
+            publish(&self.client, tag.availability_topic.clone(), "online".to_string());
+        }
+        drop(tags);
+
+        if let Some(state) = &mut self.state {
+            state.sink_message(message, measurement);
+        }
+    }
+
+    // The broker only publishes the last will when the connection is lost,
+    // not on a clean disconnect
+    fn flush(&mut self) {
+        if let Some(state) = &mut self.state {
+            state.flush();
+        }
+        publish(&self.client, self.config.listener_availability_topic.clone(), "offline".to_string());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::{DataFormat5, DataFormat6};
+    use rumqttc::{AsyncClient, Request};
+
+    fn test_message() -> RuuviGatewayMessage {
+        RuuviGatewayMessage {
+            mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            gateway_mac: "11:22:33:44:55:66".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn environment() -> RuuviData {
+        RuuviData::V5(DataFormat5 { temperature: Some(-18.0), humidity: Some(40.0), pressure: Some(100000), ..Default::default() })
+    }
+
+    #[test]
+    fn test_discovery_documents() {
+        let config : HomeAssistantConfig = toml::from_str("").unwrap();
+        let documents = discovery_documents(&config, &test_message(), Some("Freezer"), &environment());
+
+        // Temperature, humidity, pressure, battery voltage, RSSI and movement
+        assert_eq!(6, documents.len());
+
+        let (topic, payload) = &documents[0];
+        assert_eq!("homeassistant/sensor/ruuvi_aabbccddeeff/temperature/config", topic);
+        let document : serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!("ruuvi_aabbccddeeff_temperature", document["unique_id"]);
+        assert_eq!("temperature", document["device_class"]);
+        assert_eq!("°C", document["unit_of_measurement"]);
+        assert_eq!("ruuvi/decoded/AA:BB:CC:DD:EE:FF", document["state_topic"]);
+        assert_eq!("ruuvi/decoded/AA:BB:CC:DD:EE:FF/availability", document["availability"][0]["topic"]);
+        assert_eq!("ruuvi/listener/availability", document["availability"][1]["topic"]);
+        assert_eq!("all", document["availability_mode"]);
+        assert_eq!("Freezer", document["device"]["name"]);
+        assert_eq!("ruuvi_aabbccddeeff", document["device"]["identifiers"][0]);
+
+        let rssi : serde_json::Value = serde_json::from_str(&documents[4].1).unwrap();
+        assert_eq!("signal_strength", rssi["device_class"]);
+        assert_eq!("diagnostic", rssi["entity_category"]);
+    }
+
+    #[test]
+    fn test_discovery_documents_for_air_quality() {
+        let config : HomeAssistantConfig = toml::from_str("").unwrap();
+        let measurement = RuuviData::V6(DataFormat6 {
+            temperature: Some(21.0),
+            pm2_5: Some(3.2),
+            co2: Some(640),
+            ..Default::default()
+        });
+        let documents = discovery_documents(&config, &test_message(), None, &measurement);
+
+        let keys : Vec<&str> = documents.iter()
+            .map(|(topic, _)| topic.trim_end_matches("/config").rsplit('/').next().unwrap())
+            .collect();
+        assert_eq!(vec!["temperature", "rssi", "pm2_5", "co2"], keys);
+
+        let co2 : serde_json::Value = serde_json::from_str(&documents[3].1).unwrap();
+        assert_eq!("carbon_dioxide", co2["device_class"]);
+        assert_eq!("Ruuvi Air", co2["device"]["model"]);
+    }
+
+    #[test]
+    fn test_stale_tags_go_offline() {
+        let now = Instant::now();
+        let mut tags = HashMap::new();
+        tags.insert("A".to_string(), TagAvailability {
+            availability_topic: "a/availability".to_string(),
+            discovered: HashSet::new(),
+            last_seen: now,
+            online: true,
+        });
+        tags.insert("B".to_string(), TagAvailability {
+            availability_topic: "b/availability".to_string(),
+            discovered: HashSet::new(),
+            last_seen: now,
+            online: true,
+        });
+        tags.get_mut("B").unwrap().last_seen = now - Duration::from_secs(120);
+
+        let stale = mark_stale_tags(&mut tags, now, Duration::from_secs(60));
+        assert_eq!(vec!["b/availability".to_string()], stale);
+        assert!(!tags["B"].online);
+
+        // Offline tags are only reported once
+        assert!(mark_stale_tags(&mut tags, now, Duration::from_secs(60)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_publishes_discovery_once_per_tag() {
+        let (requests_tx, requests_rx) = flume::bounded(100);
+        let client = AsyncClient::from_senders(requests_tx);
+        let config : HomeAssistantConfig = toml::from_str("").unwrap();
+
+        let mut sink = HomeAssistantSink::new(client, config, HashMap::new(), &Metrics::default());
+        sink.sink_message(&test_message(), environment());
+        sink.sink_message(&test_message(), environment());
+
+        let mut topics = Vec::new();
+        while let Ok(Request::Publish(publish)) = requests_rx.try_recv() {
+            topics.push(publish.topic);
+        }
+
+        let discovery = topics.iter().filter(|topic| topic.starts_with("homeassistant/")).count();
+        assert_eq!(6, discovery);
+        assert_eq!(1, topics.iter().filter(|topic| *topic == "ruuvi/decoded/AA:BB:CC:DD:EE:FF/availability").count());
+        assert_eq!(1, topics.iter().filter(|topic| *topic == "ruuvi/listener/availability").count());
+        assert_eq!(2, topics.iter().filter(|topic| *topic == "ruuvi/decoded/AA:BB:CC:DD:EE:FF").count());
+    }
+
+    #[tokio::test]
+    async fn test_publishes_discovery_for_sensors_appearing_later() {
+        let (requests_tx, requests_rx) = flume::bounded(100);
+        let client = AsyncClient::from_senders(requests_tx);
+        let config : HomeAssistantConfig = toml::from_str("").unwrap();
+
+        let mut sink = HomeAssistantSink::new(client, config, HashMap::new(), &Metrics::default());
+        // The first advertisement has an invalid temperature
+        sink.sink_message(&test_message(), RuuviData::V5(DataFormat5 { temperature: None, humidity: Some(40.0), pressure: Some(100000), ..Default::default() }));
+        sink.sink_message(&test_message(), environment());
+        sink.sink_message(&test_message(), environment());
+
+        let mut discovery = Vec::new();
+        while let Ok(Request::Publish(publish)) = requests_rx.try_recv() {
+            if publish.topic.starts_with("homeassistant/") {
+                discovery.push(publish.topic);
+            }
+        }
+        assert_eq!(6, discovery.len());
+        assert_eq!("homeassistant/sensor/ruuvi_aabbccddeeff/temperature/config", discovery[5]);
+    }
+
+    #[tokio::test]
+    async fn test_listener_goes_offline_on_flush() {
+        let (requests_tx, requests_rx) = flume::bounded(100);
+        let client = AsyncClient::from_senders(requests_tx);
+        let config : HomeAssistantConfig = toml::from_str("").unwrap();
+
+        let mut sink = HomeAssistantSink::new(client, config, HashMap::new(), &Metrics::default());
+        sink.flush();
+
+        let mut listener = Vec::new();
+        while let Ok(Request::Publish(publish)) = requests_rx.try_recv() {
+            if publish.topic == "ruuvi/listener/availability" {
+                assert!(publish.retain);
+                listener.push(String::from_utf8(publish.payload.to_vec()).unwrap());
+            }
+        }
+        assert_eq!(vec!["online".to_string(), "offline".to_string()], listener);
+    }
+}
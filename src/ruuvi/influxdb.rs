@@ -0,0 +1,578 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use serde::Deserialize;
+use async_trait::async_trait;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use prometheus::{CounterVec, IntCounter, IntGauge, Opts, Registry};
+use tracing::{error, warn};
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+use crate::ruuvi::parser::{FieldValue, RuuviData, RuuviSink};
+use crate::ruuvi::sink::{AsyncRuuviSink, SinkError};
+
+#[derive(Clone)]
+pub struct InfluxDbMetrics {
+    pub buffered: IntGauge,
+    pub dropped: IntCounter,
+    pub writes: CounterVec,
+}
+
+impl InfluxDbMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            buffered: register(registry, IntGauge::new(
+                "ruuvi_influxdb_buffered_lines",
+                "Number of points waiting to be written to InfluxDB.")?)?,
+            dropped: register(registry, IntCounter::new(
+                "ruuvi_influxdb_dropped_lines_count",
+                "Number of points dropped because the InfluxDB buffer was full or the write was rejected.")?)?,
+            writes: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_influxdb_write_count",
+                "Number of InfluxDB write requests."),
+                &["result"])?)?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InfluxDbConfig {
+    // Base URL of the server, such as http://localhost:8086
+    pub url: String,
+
+    #[serde(default = "default_measurement")]
+    pub measurement: String,
+
+    // InfluxDB 1.x /write endpoint
+    pub database: Option<String>,
+    pub retention_policy: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+
+    // InfluxDB 2.x /api/v2/write endpoint, used when bucket is set
+    pub org: Option<String>,
+    pub bucket: Option<String>,
+    pub token: Option<String>,
+
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+
+    // Points kept in memory while the database is unreachable
+    #[serde(default = "default_max_buffered")]
+    pub max_buffered: usize,
+
+    #[serde(default = "default_retry_interval_ms")]
+    pub retry_interval_ms: u64,
+
+    #[serde(default = "default_max_retry_interval_ms")]
+    pub max_retry_interval_ms: u64,
+}
+
+fn default_measurement() -> String {
+    "ruuvi".to_string()
+}
+
+fn default_batch_size() -> usize {
+    500
+}
+
+fn default_flush_interval_ms() -> u64 {
+    1000
+}
+
+fn default_max_buffered() -> usize {
+    100_000
+}
+
+fn default_retry_interval_ms() -> u64 {
+    1000
+}
+
+fn default_max_retry_interval_ms() -> u64 {
+    60_000
+}
+
+impl InfluxDbConfig {
+    pub fn write_url(&self) -> String {
+        let base = self.url.trim_end_matches('/');
+        let mut params = vec![("precision", "s")];
+
+        match &self.bucket {
+            Some(bucket) => {
+                params.push(("bucket", bucket.as_str()));
+                if let Some(org) = &self.org {
+                    params.push(("org", org.as_str()));
+                }
+                format!("{}/api/v2/write?{}", base, encode_query(&params))
+            }
+            None => {
+                if let Some(database) = &self.database {
+                    params.push(("db", database.as_str()));
+                }
+                if let Some(retention_policy) = &self.retention_policy {
+                    params.push(("rp", retention_policy.as_str()));
+                }
+                if let Some(username) = &self.username {
+                    params.push(("u", username.as_str()));
+                }
+                if let Some(password) = &self.password {
+                    params.push(("p", password.as_str()));
+                }
+                format!("{}/write?{}", base, encode_query(&params))
+            }
+        }
+    }
+}
+
+fn encode_query(params : &[(&str, &str)]) -> String {
+    params.iter()
+        .map(|(key, value)| format!("{}={}", key, percent_encode(value)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+fn percent_encode(s : &str) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Escapes commas, equal signs and spaces in measurement names, tag keys and tag values
+fn escape(s : &str) -> String {
+    s.replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+pub fn to_line_protocol(measurement_name : &str, message : &RuuviGatewayMessage, name : Option<&str>, data : &RuuviData) -> String {
+    let mut line = escape(measurement_name);
+
+    line.push_str(&format!(",mac={}", escape(&message.mac)));
+    if !message.gateway_mac.is_empty() {
+        line.push_str(&format!(",gateway={}", escape(&message.gateway_mac)));
+    }
+    if let Some(name) = name {
+        line.push_str(&format!(",name={}", escape(name)));
+    }
+    if !message.site.is_empty() {
+        line.push_str(&format!(",site={}", escape(&message.site)));
+    }
+
+    // Only the fields the tag's data format provides
+    for (index, (field, value)) in data.fields().into_iter().enumerate() {
+        line.push(if index == 0 { ' ' } else { ',' });
+        match value {
+            FieldValue::Integer(value) => line.push_str(&format!("{}={}i", field, value)),
+            value => line.push_str(&format!("{}={}", field, value)),
+        }
+    }
+
+    // RSSI is only known for measurements received through a gateway
+    if !message.data.is_empty() {
+        line.push_str(&format!(",rssi={}i", message.rssi));
+    }
+
+    if let Some(timestamp) = message.timestamp() {
+        line.push_str(&format!(" {}", timestamp));
+    }
+
+    line
+}
+
+struct InfluxDbBuffer {
+    lines: Mutex<VecDeque<String>>,
+    notify: Notify,
+    closed: AtomicBool,
+    metrics: InfluxDbMetrics,
+}
+
+// Buffers points in line protocol and writes them in batches from a
+// background task, retrying with backoff while the database is down.
+pub struct InfluxDbSink {
+    config: InfluxDbConfig,
+    names: HashMap<String, String>,
+    buffer: Arc<InfluxDbBuffer>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl InfluxDbSink {
+    // Must be called from within a Tokio runtime.
+    pub fn new(config : InfluxDbConfig, names : HashMap<String, String>, metrics : &Metrics) -> Self {
+        let buffer = Arc::new(InfluxDbBuffer {
+            lines: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            metrics: metrics.influxdb().clone(),
+        });
+
+        let writer = tokio::spawn(run_writer(config.clone(), buffer.clone()));
+
+        Self {
+            config,
+            names,
+            buffer,
+            writer: Some(writer),
+        }
+    }
+
+    fn line(&self, message : &RuuviGatewayMessage, measurement : &RuuviData) -> String {
+        let name = self.names.get(&message.mac.to_uppercase()).map(|name| name.as_str());
+        to_line_protocol(&self.config.measurement, message, name, measurement)
+    }
+
+    fn push_line(&self, line : String) {
+        let mut lines = self.buffer.lines.lock().unwrap();
+        lines.push_back(line);
+        self.buffer.metrics.buffered.inc();
+        drop_overflow(&mut lines, self.config.max_buffered, &self.buffer.metrics);
+
+        if lines.len() >= self.config.batch_size {
+            self.buffer.notify.notify_one();
+        }
+    }
+}
+
+impl Drop for InfluxDbSink {
+    fn drop(&mut self) {
+        self.buffer.closed.store(true, Ordering::SeqCst);
+        self.buffer.notify.notify_one();
+    }
+}
+
+impl RuuviSink for InfluxDbSink {
+    fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
+        let message = RuuviGatewayMessage {
+            mac: source_mac.to_string(),
+            ..Default::default()
+        };
+        self.sink_message(&message, measurement);
+    }
+
+    fn sink_message(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        let line = self.line(message, &measurement);
+        self.push_line(line);
+    }
+}
+
+// Only buffers, so it doesn't need a blocking thread in the sink pipeline
+#[async_trait]
+impl AsyncRuuviSink for InfluxDbSink {
+    async fn sink(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) -> Result<(), SinkError> {
+        let line = self.line(message, &measurement);
+        self.push_line(line);
+        Ok(())
+    }
+
+    async fn sink_batch(&mut self, batch : Vec<(RuuviGatewayMessage, RuuviData)>) -> Result<(), SinkError> {
+        for (message, measurement) in batch {
+            let line = self.line(&message, &measurement);
+            self.push_line(line);
+        }
+        Ok(())
+    }
+
+    // Writes the buffered points, giving up if the database is unreachable
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        self.buffer.closed.store(true, Ordering::SeqCst);
+        self.buffer.notify.notify_one();
+        if let Some(writer) = self.writer.take() {
+            writer.await?;
+        }
+
+        match self.buffer.lines.lock().unwrap().len() {
+            0 => Ok(()),
+            lines => Err(format!("{} points were not written", lines).into()),
+        }
+    }
+}
+
+fn drop_overflow(lines : &mut VecDeque<String>, max_buffered : usize, metrics : &InfluxDbMetrics) {
+    while lines.len() > max_buffered {
+        lines.pop_front();
+        metrics.buffered.dec();
+        metrics.dropped.inc();
+    }
+}
+
+enum WriteError {
+    // The server refused the data, retrying won't help
+    Rejected(String),
+    Retry(String),
+}
+
+async fn write_batch(client : &Client<HttpConnector>, config : &InfluxDbConfig, url : &str, batch : &[String]) -> Result<(), WriteError> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("Content-Type", "text/plain; charset=utf-8");
+    if let (Some(_), Some(token)) = (&config.bucket, &config.token) {
+        request = request.header("Authorization", format!("Token {}", token));
+    }
+    let request = request
+        .body(Body::from(batch.join("\n")))
+        .map_err(|e| WriteError::Rejected(e.to_string()))?;
+
+    let response = client.request(request).await.map_err(|e| WriteError::Retry(e.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_server_error() || status.as_u16() == 429 {
+        Err(WriteError::Retry(status.to_string()))
+    } else {
+        Err(WriteError::Rejected(status.to_string()))
+    }
+}
+
+async fn run_writer(config : InfluxDbConfig, buffer : Arc<InfluxDbBuffer>) {
+    let client = Client::new();
+    let url = config.write_url();
+    let flush_interval = Duration::from_millis(config.flush_interval_ms);
+    let initial_retry_interval = Duration::from_millis(config.retry_interval_ms);
+    let max_retry_interval = Duration::from_millis(config.max_retry_interval_ms);
+    let mut retry_interval = initial_retry_interval;
+
+    loop {
+        let closed = buffer.closed.load(Ordering::SeqCst);
+        if !closed {
+            tokio::select! {
+                _ = buffer.notify.notified() => {}
+                _ = tokio::time::sleep(flush_interval) => {}
+            }
+        }
+
+        loop {
+            let batch : Vec<String> = {
+                let mut lines = buffer.lines.lock().unwrap();
+                let count = lines.len().min(config.batch_size.max(1));
+                lines.drain(..count).collect()
+            };
+            if batch.is_empty() {
+                break;
+            }
+
+            match write_batch(&client, &config, &url, &batch).await {
+                Ok(()) => {
+                    buffer.metrics.writes.with_label_values(&["ok"]).inc();
+                    buffer.metrics.buffered.sub(batch.len() as i64);
+                    retry_interval = initial_retry_interval;
+                }
+                Err(WriteError::Rejected(reason)) => {
+                    error!(points = batch.len(), %reason, "InfluxDB rejected points");
+                    buffer.metrics.writes.with_label_values(&["rejected"]).inc();
+                    buffer.metrics.buffered.sub(batch.len() as i64);
+                    buffer.metrics.dropped.inc_by(batch.len() as u64);
+                }
+                Err(WriteError::Retry(reason)) => {
+                    warn!(?retry_interval, %reason, "InfluxDB write failed, retrying");
+                    buffer.metrics.writes.with_label_values(&["error"]).inc();
+                    {
+                        let mut lines = buffer.lines.lock().unwrap();
+                        for line in batch.into_iter().rev() {
+                            lines.push_front(line);
+                        }
+                        drop_overflow(&mut lines, config.max_buffered, &buffer.metrics);
+                    }
+                    if closed {
+                        // Give up on the remaining points when shutting down
+                        return;
+                    }
+                    tokio::time::sleep(retry_interval).await;
+                    retry_interval = (retry_interval * 2).min(max_retry_interval);
+                }
+            }
+        }
+
+        if closed {
+            return;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::{DataFormat3, DataFormat5};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    type Requests = Arc<Mutex<Vec<(String, Option<String>, String)>>>;
+
+    // Records every request and answers with the given status codes in order,
+    // repeating the last one
+    async fn start_mock_server(statuses : Vec<u16>) -> (SocketAddr, Requests) {
+        let requests : Requests = Arc::new(Mutex::new(Vec::new()));
+        let server_requests = requests.clone();
+        let statuses = Arc::new(statuses);
+
+        let make_service = make_service_fn(move |_| {
+            let requests = server_requests.clone();
+            let statuses = statuses.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req : Request<Body>| {
+                    let requests = requests.clone();
+                    let statuses = statuses.clone();
+                    async move {
+                        let uri = req.uri().to_string();
+                        let auth = req.headers().get("Authorization").map(|v| v.to_str().unwrap().to_string());
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let mut requests = requests.lock().unwrap();
+                        requests.push((uri, auth, String::from_utf8(body.to_vec()).unwrap()));
+                        let status = statuses[(requests.len() - 1).min(statuses.len() - 1)];
+                        Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    async fn wait_for_requests(requests : &Requests, count : usize) {
+        for _ in 0..200 {
+            if requests.lock().unwrap().len() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("expected {} requests, got {}", count, requests.lock().unwrap().len());
+    }
+
+    fn test_config(addr : SocketAddr) -> InfluxDbConfig {
+        toml::from_str(&format!(r#"
+            url = "http://{}/"
+            database = "ruuvi"
+            flush_interval_ms = 10
+            retry_interval_ms = 10
+        "#, addr)).unwrap()
+    }
+
+    fn test_message() -> RuuviGatewayMessage {
+        RuuviGatewayMessage {
+            rssi: -62,
+            ts: "1646578374".into(),
+            gwts: "1646578375".into(),
+            data: "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F".into(),
+            mac: "11:22:33:44:55:66".to_string(),
+            gateway_mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            received_at: None,
+            site: String::new(),
+            clock_correction: 0,
+        }
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        let measurement = RuuviData::V5(DataFormat5 {
+            temperature: Some(24.3),
+            humidity: Some(0.0),
+            pressure: Some(100044),
+            ..Default::default()
+        });
+
+        let line = to_line_protocol("ruuvi", &test_message(), Some("living room"), &measurement);
+
+        assert_eq!("ruuvi,mac=11:22:33:44:55:66,gateway=AA:BB:CC:DD:EE:FF,name=living\\ room format=5i,temperature=24.3,humidity=0,pressure=100044i,acceleration_x=0,acceleration_y=0,acceleration_z=0,tx_power=0i,voltage=0,movement=0i,measurement_sequence=0i,rssi=-62i 1646578374", line);
+    }
+
+    #[test]
+    fn test_line_protocol_leaves_out_missing_fields() {
+        let measurement = RuuviData::V3(DataFormat3 {
+            temperature: 21.5,
+            ..Default::default()
+        });
+
+        let line = to_line_protocol("ruuvi", &test_message(), None, &measurement);
+
+        assert_eq!("ruuvi,mac=11:22:33:44:55:66,gateway=AA:BB:CC:DD:EE:FF format=3i,temperature=21.5,humidity=0,pressure=0i,acceleration_x=0,acceleration_y=0,acceleration_z=0,voltage=0,rssi=-62i 1646578374", line);
+
+        let message = RuuviGatewayMessage { site: "helsinki".to_string(), ..test_message() };
+        assert!(to_line_protocol("ruuvi", &message, None, &measurement).starts_with("ruuvi,mac=11:22:33:44:55:66,gateway=AA:BB:CC:DD:EE:FF,site=helsinki "));
+    }
+
+    #[test]
+    fn test_write_url() {
+        let mut config : InfluxDbConfig = toml::from_str(r#"
+            url = "http://localhost:8086"
+            database = "ruuvi data"
+            username = "user"
+            password = "p&ss"
+        "#).unwrap();
+        assert_eq!("http://localhost:8086/write?precision=s&db=ruuvi%20data&u=user&p=p%26ss", config.write_url());
+
+        config.bucket = Some("sensors".to_string());
+        config.org = Some("home".to_string());
+        assert_eq!("http://localhost:8086/api/v2/write?precision=s&bucket=sensors&org=home", config.write_url());
+    }
+
+    #[tokio::test]
+    async fn test_writes_batches_to_v1_endpoint() {
+        let (addr, requests) = start_mock_server(vec![204]).await;
+        let mut names = HashMap::new();
+        names.insert("11:22:33:44:55:66".to_string(), "sauna".to_string());
+
+        let mut sink = InfluxDbSink::new(test_config(addr), names, &Metrics::default());
+        sink.sink_message(&test_message(), RuuviData::V5(DataFormat5::default()));
+        sink.sink_message(&test_message(), RuuviData::V5(DataFormat5::default()));
+
+        wait_for_requests(&requests, 1).await;
+        let requests = requests.lock().unwrap();
+        assert_eq!("/write?precision=s&db=ruuvi", requests[0].0);
+        assert_eq!(None, requests[0].1);
+        let lines : Vec<&str> = requests[0].2.split('\n').collect();
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("ruuvi,mac=11:22:33:44:55:66,gateway=AA:BB:CC:DD:EE:FF,name=sauna "));
+    }
+
+    #[tokio::test]
+    async fn test_retries_v2_writes_while_database_is_down() {
+        let (addr, requests) = start_mock_server(vec![503, 503, 204]).await;
+        let mut config = test_config(addr);
+        config.bucket = Some("sensors".to_string());
+        config.org = Some("home".to_string());
+        config.token = Some("secret".to_string());
+
+        let mut sink = InfluxDbSink::new(config, HashMap::new(), &Metrics::default());
+        sink.sink_message(&test_message(), RuuviData::V5(DataFormat5::default()));
+
+        wait_for_requests(&requests, 3).await;
+        let requests = requests.lock().unwrap();
+        assert_eq!("/api/v2/write?precision=s&bucket=sensors&org=home", requests[2].0);
+        assert_eq!(Some("Token secret".to_string()), requests[2].1);
+        assert_eq!(requests[0].2, requests[2].2);
+    }
+
+    #[tokio::test]
+    async fn test_flush_writes_buffered_points() {
+        let (addr, requests) = start_mock_server(vec![204]).await;
+        let mut config = test_config(addr);
+        config.flush_interval_ms = 60_000;
+
+        let mut sink = InfluxDbSink::new(config, HashMap::new(), &Metrics::default());
+        let batch = vec![(test_message(), RuuviData::V5(DataFormat5::default())); 3];
+        AsyncRuuviSink::sink_batch(&mut sink, batch).await.unwrap();
+        assert!(requests.lock().unwrap().is_empty());
+
+        AsyncRuuviSink::flush(&mut sink).await.unwrap();
+        let requests = requests.lock().unwrap();
+        assert_eq!(1, requests.len());
+        assert_eq!(3, requests[0].2.split('\n').count());
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prometheus::{HistogramOpts, HistogramVec, Registry};
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+
+// Gateway timestamps have a resolution of one second
+const LATENCY_BUCKETS : &[f64] = &[0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 900.0];
+// Tags advertise about once a second, gateways may send in batches
+const INTERARRIVAL_BUCKETS : &[f64] = &[0.1, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+#[derive(Clone)]
+pub struct LatencyMetrics {
+    pub latency: HistogramVec,
+    pub buffering: HistogramVec,
+    pub interarrival: HistogramVec,
+}
+
+impl LatencyMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            latency: register(registry, HistogramVec::new(HistogramOpts::new(
+                "ruuvi_message_latency_seconds",
+                "Time from the gateway receiving an advertisement (ts) to the listener processing it.")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+                &["gateway"])?)?,
+            buffering: register(registry, HistogramVec::new(HistogramOpts::new(
+                "ruuvi_gateway_buffering_seconds",
+                "Time the gateway held an advertisement before sending it (gwts - ts).")
+                .buckets(LATENCY_BUCKETS.to_vec()),
+                &["gateway"])?)?,
+            interarrival: register(registry, HistogramVec::new(HistogramOpts::new(
+                "ruuvi_tag_interarrival_seconds",
+                "Time between consecutive messages of a tag through the same gateway.")
+                .buckets(INTERARRIVAL_BUCKETS.to_vec()),
+                &["mac"])?)?,
+        })
+    }
+}
+
+fn unix_secs_f64(time : SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs_f64()).unwrap_or(0.0)
+}
+
+// Observes the delays of every received gateway message. Gateway clocks
+// ahead of the listener would give negative delays, those are recorded as
+// zero.
+pub struct LatencyTracker {
+    metrics: LatencyMetrics,
+    // Local reception time of the previous message by (tag, gateway)
+    previous: HashMap<(String, String), f64>,
+}
+
+impl LatencyTracker {
+    pub fn new(metrics : &Metrics) -> Self {
+        Self {
+            metrics: metrics.latency().clone(),
+            previous: HashMap::new(),
+        }
+    }
+
+    pub fn observe(&mut self, message : &RuuviGatewayMessage) {
+        let received_at = unix_secs_f64(message.received_at.unwrap_or_else(SystemTime::now));
+        let gateway = message.gateway_mac.as_str();
+
+        // Without the clock correction, which would hide the drift
+        if let Some(timestamp) = message.raw_timestamp() {
+            self.metrics.latency.with_label_values(&[gateway]).observe((received_at - timestamp as f64).max(0.0));
+            if let Some(gateway_timestamp) = message.raw_gateway_timestamp() {
+                self.metrics.buffering.with_label_values(&[gateway]).observe(gateway_timestamp.saturating_sub(timestamp) as f64);
+            }
+        }
+
+        // Measured locally so that gateway buffering shows up as bursts
+        let key = (message.mac.to_uppercase(), message.gateway_mac.clone());
+        if let Some(previous) = self.previous.insert(key, received_at) {
+            self.metrics.interarrival.with_label_values(&[&message.mac]).observe((received_at - previous).max(0.0));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn message(gateway_mac : &str, ts : u64, gwts : u64, received_at : f64) -> RuuviGatewayMessage {
+        RuuviGatewayMessage {
+            mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            gateway_mac: gateway_mac.to_string(),
+            ts: ts.to_string().into(),
+            gwts: gwts.to_string().into(),
+            received_at: Some(UNIX_EPOCH + Duration::from_secs_f64(received_at)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_latency_and_interarrival() {
+        let metrics = Metrics::default();
+        let mut tracker = LatencyTracker::new(&metrics);
+
+        tracker.observe(&message("11:11:11:11:11:11", 1000, 1008, 1010.5));
+        tracker.observe(&message("11:11:11:11:11:11", 1001, 1008, 1010.6));
+        // Another gateway hears the same tag, its clock is ahead
+        tracker.observe(&message("22:22:22:22:22:22", 1012, 1012, 1010.7));
+        tracker.observe(&message("11:11:11:11:11:11", 1010, 1018, 1020.5));
+
+        let latency = metrics.latency().latency.with_label_values(&["11:11:11:11:11:11"]);
+        assert_eq!(3, latency.get_sample_count());
+        assert!((latency.get_sample_sum() - (10.5 + 9.6 + 10.5)).abs() < 0.001);
+        assert_eq!(0.0, metrics.latency().latency.with_label_values(&["22:22:22:22:22:22"]).get_sample_sum());
+
+        let buffering = metrics.latency().buffering.with_label_values(&["11:11:11:11:11:11"]);
+        assert_eq!(8.0 + 7.0 + 8.0, buffering.get_sample_sum());
+
+        // Only consecutive messages through the same gateway count
+        let interarrival = metrics.latency().interarrival.with_label_values(&["AA:BB:CC:DD:EE:FF"]);
+        assert_eq!(2, interarrival.get_sample_count());
+        assert!((interarrival.get_sample_sum() - (0.1 + 9.9)).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_latency_ignores_clock_correction() {
+        let metrics = Metrics::default();
+        let mut tracker = LatencyTracker::new(&metrics);
+
+        // A gateway clock 100 seconds behind, corrected
+        let corrected = RuuviGatewayMessage {
+            clock_correction: 100,
+            ..message("11:11:11:11:11:11", 900, 902, 1002.5)
+        };
+        tracker.observe(&corrected);
+
+        let latency = metrics.latency().latency.with_label_values(&["11:11:11:11:11:11"]);
+        assert_eq!(102.5, latency.get_sample_sum());
+    }
+}
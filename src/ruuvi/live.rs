@@ -0,0 +1,294 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::{header, Body, Request, Response, StatusCode};
+use prometheus::{CounterVec, IntGauge, Opts, Registry};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use tokio::sync::{broadcast, watch};
+use tracing::debug;
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+use crate::ruuvi::parser::{format_mac, FieldValue, RuuviData, RuuviMeasurement};
+use crate::ruuvi::sink::{AsyncRuuviSink, SinkError};
+use crate::ruuvi::url::query_decode;
+
+// Sent while there are no measurements so that closed connections are noticed
+const KEEPALIVE_INTERVAL : Duration = Duration::from_secs(15);
+
+#[derive(Clone)]
+pub struct LiveMetrics {
+    pub clients: IntGauge,
+    pub disconnected: CounterVec,
+}
+
+impl LiveMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            clients: register(registry, IntGauge::with_opts(Opts::new(
+                "ruuvi_stream_clients",
+                "Number of clients connected to the live measurement stream."))?)?,
+            disconnected: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_stream_disconnected_count",
+                "Number of live stream clients disconnected, by reason."),
+                &["reason"])?)?,
+        })
+    }
+}
+
+struct LiveMeasurement {
+    message: RuuviGatewayMessage,
+    data: RuuviData,
+}
+
+// Which measurements and fields a client wants, from the query string:
+// ?tag=<mac>&gateway=<mac>&fields=temperature,humidity
+// Every parameter can be repeated or hold a comma separated list.
+#[derive(Debug, Default, PartialEq)]
+pub struct StreamFilter {
+    tags: HashSet<String>,
+    gateways: HashSet<String>,
+    fields: HashSet<String>,
+}
+
+impl StreamFilter {
+    pub fn from_query(query : Option<&str>) -> Self {
+        let mut filter = Self::default();
+        for (key, values) in query.unwrap_or("").split('&').filter_map(|pair| pair.split_once('=')) {
+            let set = match key {
+                "tag" => &mut filter.tags,
+                "gateway" => &mut filter.gateways,
+                "fields" => &mut filter.fields,
+                _ => continue,
+            };
+            let values = query_decode(values);
+            set.extend(values.split(',').filter(|value| !value.is_empty()).map(|value| match key {
+                "fields" => value.to_string(),
+                _ => value.to_uppercase(),
+            }));
+        }
+        filter
+    }
+
+    fn matches(&self, message : &RuuviGatewayMessage) -> bool {
+        (self.tags.is_empty() || self.tags.contains(&message.mac.to_uppercase()))
+            && (self.gateways.is_empty() || self.gateways.contains(&message.gateway_mac.to_uppercase()))
+    }
+
+    // The measurement as a JSON object, or None when it has none of the
+    // requested fields
+    fn to_json(&self, measurement : &LiveMeasurement) -> Option<String> {
+        let fields : Vec<(&'static str, FieldValue)> = measurement.data.fields().into_iter()
+            .filter(|(name, _)| self.fields.is_empty() || self.fields.contains(*name))
+            .collect();
+        if !self.fields.is_empty() && fields.iter().all(|(name, _)| *name == "format") {
+            return None;
+        }
+        serde_json::to_string(&StreamEvent { measurement, fields }).ok()
+    }
+}
+
+struct StreamEvent<'a> {
+    measurement: &'a LiveMeasurement,
+    fields: Vec<(&'static str, FieldValue)>,
+}
+
+impl Serialize for StreamEvent<'_> {
+    fn serialize<S : Serializer>(&self, serializer : S) -> Result<S::Ok, S::Error> {
+        let message = &self.measurement.message;
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("tag_mac", &message.mac.to_uppercase())?;
+        map.serialize_entry("gateway_mac", &message.gateway_mac)?;
+        map.serialize_entry("rssi", &message.rssi)?;
+        map.serialize_entry("timestamp", &message.timestamp())?;
+        map.serialize_entry("received_at", &message.received_at
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs()))?;
+        for (name, value) in &self.fields {
+            map.serialize_entry(name, value)?;
+        }
+        if let Some(mac) = self.measurement.data.mac() {
+            map.serialize_entry("mac", &format_mac(&mac))?;
+        }
+        map.end()
+    }
+}
+
+// Fans decoded measurements out to the Server-Sent Events clients. Every
+// client has its own buffer of `buffer` measurements, a client which falls
+// further behind is disconnected. Cloning shares the stream.
+#[derive(Clone)]
+pub struct LiveStream {
+    sender: broadcast::Sender<Arc<LiveMeasurement>>,
+    closed: watch::Sender<bool>,
+    metrics: LiveMetrics,
+}
+
+impl LiveStream {
+    pub fn new(buffer : usize, metrics : &Metrics) -> Self {
+        let (sender, _) = broadcast::channel(buffer.max(1));
+        let (closed, _) = watch::channel(false);
+        Self {
+            sender,
+            closed,
+            metrics: metrics.live().clone(),
+        }
+    }
+
+    pub fn publish(&self, message : &RuuviGatewayMessage, data : RuuviData) {
+        // Without clients there is nobody to send to
+        let _ = self.sender.send(Arc::new(LiveMeasurement { message: message.clone(), data }));
+    }
+
+    // Ends every client stream, used on shutdown
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+
+    // Serves GET /api/stream as text/event-stream
+    pub fn serve(&self, req : &Request<Body>) -> Response<Body> {
+        let filter = StreamFilter::from_query(req.uri().query());
+        let mut receiver = self.sender.subscribe();
+        let mut closed = self.closed.subscribe();
+        let (mut body, response_body) = Body::channel();
+        let metrics = self.metrics.clone();
+
+        metrics.clients.inc();
+        tokio::spawn(async move {
+            let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+            keepalive.tick().await;
+            let reason = loop {
+                let event = tokio::select! {
+                    measurement = receiver.recv() => match measurement {
+                        Ok(measurement) if filter.matches(&measurement.message) => match filter.to_json(&measurement) {
+                            Some(json) => format!("event: measurement\ndata: {}\n\n", json),
+                            None => continue,
+                        },
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            debug!(skipped, "disconnecting slow stream client");
+                            break "slow";
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break "shutdown",
+                    },
+                    _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+                    _ = closed.wait_for(|closed| *closed) => break "shutdown",
+                };
+                if body.send_data(Bytes::from(event)).await.is_err() {
+                    break "closed";
+                }
+            };
+            metrics.clients.dec();
+            metrics.disconnected.with_label_values(&[reason]).inc();
+        });
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(response_body)
+            .unwrap()
+    }
+}
+
+#[async_trait]
+impl AsyncRuuviSink for LiveStream {
+    async fn sink(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) -> Result<(), SinkError> {
+        self.publish(message, measurement);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        self.close();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::DataFormat5;
+    use hyper::body::HttpBody;
+    use serde_json::Value;
+
+    fn message(mac : &str, gateway_mac : &str) -> RuuviGatewayMessage {
+        RuuviGatewayMessage {
+            mac: mac.to_string(),
+            gateway_mac: gateway_mac.to_string(),
+            rssi: -70,
+            ..Default::default()
+        }
+    }
+
+    fn measurement(temperature : f32) -> RuuviData {
+        RuuviData::V5(DataFormat5 { temperature: Some(temperature), ..Default::default() })
+    }
+
+    async fn next_event(body : &mut Body) -> String {
+        let chunk = tokio::time::timeout(Duration::from_secs(1), body.data()).await.unwrap().unwrap().unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_filter_from_query() {
+        let filter = StreamFilter::from_query(Some("tag=aa%3Abb%3Acc%3Add%3Aee%3Aff,11:22:33:44:55:66&fields=temperature&fields=humidity&other=1"));
+        assert_eq!(HashSet::from(["AA:BB:CC:DD:EE:FF".to_string(), "11:22:33:44:55:66".to_string()]), filter.tags);
+        assert_eq!(HashSet::from(["temperature".to_string(), "humidity".to_string()]), filter.fields);
+        assert!(filter.gateways.is_empty());
+
+        assert!(filter.matches(&message("aa:bb:cc:dd:ee:ff", "")));
+        assert!(!filter.matches(&message("00:00:00:00:00:01", "")));
+        assert_eq!(StreamFilter::default(), StreamFilter::from_query(None));
+    }
+
+    #[tokio::test]
+    async fn test_stream_filters_measurements() {
+        let metrics = Metrics::default();
+        let stream = LiveStream::new(10, &metrics);
+        let req = Request::get("/api/stream?gateway=11:11:11:11:11:11&fields=temperature").body(Body::empty()).unwrap();
+        let response = stream.serve(&req);
+        assert_eq!("text/event-stream", response.headers()[header::CONTENT_TYPE]);
+        let mut body = response.into_body();
+
+        stream.publish(&message("AA:BB:CC:DD:EE:FF", "22:22:22:22:22:22"), measurement(1.0));
+        stream.publish(&message("AA:BB:CC:DD:EE:FF", "11:11:11:11:11:11"), measurement(2.0));
+
+        let event = next_event(&mut body).await;
+        assert!(event.starts_with("event: measurement\ndata: {"));
+        let json : Value = serde_json::from_str(event.lines().nth(1).unwrap().trim_start_matches("data: ")).unwrap();
+        assert_eq!(2.0, json["temperature"]);
+        assert_eq!("11:11:11:11:11:11", json["gateway_mac"]);
+        assert!(json.get("humidity").is_none());
+        assert!(event.contains("\"temperature\":2.0,"));
+        assert_eq!(1, metrics.live().clients.get());
+
+        // The stream ends on shutdown
+        stream.close();
+        assert!(tokio::time::timeout(Duration::from_secs(1), body.data()).await.unwrap().is_none());
+        assert_eq!(0, metrics.live().clients.get());
+        assert_eq!(1.0, metrics.live().disconnected.with_label_values(&["shutdown"]).get());
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_is_disconnected() {
+        let metrics = Metrics::default();
+        let stream = LiveStream::new(2, &metrics);
+        let mut body = stream.serve(&Request::get("/api/stream").body(Body::empty()).unwrap()).into_body();
+
+        // The client doesn't read while more than its buffer is published
+        for i in 0..10 {
+            stream.publish(&message("AA:BB:CC:DD:EE:FF", ""), measurement(i as f32));
+        }
+        let mut events = 0;
+        while let Ok(Some(chunk)) = tokio::time::timeout(Duration::from_secs(1), body.data()).await {
+            assert!(chunk.is_ok());
+            events += 1;
+        }
+        assert!(events < 10);
+        assert_eq!(1.0, metrics.live().disconnected.with_label_values(&["slow"]).get());
+    }
+}
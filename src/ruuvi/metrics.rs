@@ -0,0 +1,190 @@
+use std::sync::{Arc, OnceLock};
+
+use prometheus::core::Collector;
+use prometheus::Registry;
+
+#[cfg(feature = "http")]
+use crate::ruuvi::alerts::AlertMetrics;
+use crate::ruuvi::battery::BatteryMetrics;
+use crate::ruuvi::clock::ClockMetrics;
+#[cfg(feature = "encryption")]
+use crate::ruuvi::encryption::DecryptionMetrics;
+use crate::ruuvi::gateway::GatewayMetrics;
+use crate::ruuvi::latency::LatencyMetrics;
+#[cfg(feature = "http")]
+use crate::ruuvi::influxdb::InfluxDbMetrics;
+#[cfg(feature = "http")]
+use crate::ruuvi::live::LiveMetrics;
+#[cfg(feature = "mqtt")]
+use crate::ruuvi::mqtt::{BrokerMetrics, MqttMetrics};
+use crate::ruuvi::pipeline::PipelineMetrics;
+use crate::ruuvi::prometheus::MeasurementMetrics;
+#[cfg(feature = "sqlite")]
+use crate::ruuvi::sqlite::SqliteMetrics;
+use crate::ruuvi::state::TagMetrics;
+
+// Metrics of one listener instance, all registered on the same registry.
+// Each group is registered on first use so only the metrics of configured
+// sinks are exported. Cloning shares the metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<MetricsInner>,
+}
+
+struct MetricsInner {
+    registry: Registry,
+    gateway: OnceLock<GatewayMetrics>,
+    pipeline: OnceLock<PipelineMetrics>,
+    measurements: OnceLock<MeasurementMetrics>,
+    battery: OnceLock<BatteryMetrics>,
+    latency: OnceLock<LatencyMetrics>,
+    clock: OnceLock<ClockMetrics>,
+    tags: OnceLock<TagMetrics>,
+    #[cfg(feature = "encryption")]
+    decryption: OnceLock<DecryptionMetrics>,
+    #[cfg(feature = "http")]
+    influxdb: OnceLock<InfluxDbMetrics>,
+    #[cfg(feature = "mqtt")]
+    mqtt: OnceLock<MqttMetrics>,
+    #[cfg(feature = "mqtt")]
+    brokers: OnceLock<BrokerMetrics>,
+    #[cfg(feature = "sqlite")]
+    sqlite: OnceLock<SqliteMetrics>,
+    #[cfg(feature = "http")]
+    alerts: OnceLock<AlertMetrics>,
+    #[cfg(feature = "http")]
+    live: OnceLock<LiveMetrics>,
+}
+
+impl Metrics {
+    pub fn new(registry : Registry) -> Self {
+        Self {
+            inner: Arc::new(MetricsInner {
+                registry,
+                gateway: OnceLock::new(),
+                pipeline: OnceLock::new(),
+                measurements: OnceLock::new(),
+                battery: OnceLock::new(),
+                latency: OnceLock::new(),
+                clock: OnceLock::new(),
+                tags: OnceLock::new(),
+                #[cfg(feature = "encryption")]
+                decryption: OnceLock::new(),
+                #[cfg(feature = "http")]
+                influxdb: OnceLock::new(),
+                #[cfg(feature = "mqtt")]
+                mqtt: OnceLock::new(),
+                #[cfg(feature = "mqtt")]
+                brokers: OnceLock::new(),
+                #[cfg(feature = "sqlite")]
+                sqlite: OnceLock::new(),
+                #[cfg(feature = "http")]
+                alerts: OnceLock::new(),
+                #[cfg(feature = "http")]
+                live: OnceLock::new(),
+            }),
+        }
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.inner.registry
+    }
+
+    pub fn gateway(&self) -> &GatewayMetrics {
+        self.inner.gateway.get_or_init(|| GatewayMetrics::new(self.registry()).expect("couldn't register gateway metrics"))
+    }
+
+    pub fn pipeline(&self) -> &PipelineMetrics {
+        self.inner.pipeline.get_or_init(|| PipelineMetrics::new(self.registry()).expect("couldn't register pipeline metrics"))
+    }
+
+    pub fn measurements(&self) -> &MeasurementMetrics {
+        self.inner.measurements.get_or_init(|| MeasurementMetrics::new(self.registry()).expect("couldn't register measurement metrics"))
+    }
+
+    pub fn battery(&self) -> &BatteryMetrics {
+        self.inner.battery.get_or_init(|| BatteryMetrics::new(self.registry()).expect("couldn't register battery metrics"))
+    }
+
+    pub fn latency(&self) -> &LatencyMetrics {
+        self.inner.latency.get_or_init(|| LatencyMetrics::new(self.registry()).expect("couldn't register latency metrics"))
+    }
+
+    pub fn clock(&self) -> &ClockMetrics {
+        self.inner.clock.get_or_init(|| ClockMetrics::new(self.registry()).expect("couldn't register clock metrics"))
+    }
+
+    pub fn tags(&self) -> &TagMetrics {
+        self.inner.tags.get_or_init(|| TagMetrics::new(self.registry()).expect("couldn't register tag metrics"))
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn decryption(&self) -> &DecryptionMetrics {
+        self.inner.decryption.get_or_init(|| DecryptionMetrics::new(self.registry()).expect("couldn't register decryption metrics"))
+    }
+
+    #[cfg(feature = "http")]
+    pub fn influxdb(&self) -> &InfluxDbMetrics {
+        self.inner.influxdb.get_or_init(|| InfluxDbMetrics::new(self.registry()).expect("couldn't register InfluxDB metrics"))
+    }
+
+    #[cfg(feature = "mqtt")]
+    pub fn mqtt(&self) -> &MqttMetrics {
+        self.inner.mqtt.get_or_init(|| MqttMetrics::new(self.registry()).expect("couldn't register MQTT metrics"))
+    }
+
+    #[cfg(feature = "mqtt")]
+    pub fn brokers(&self) -> &BrokerMetrics {
+        self.inner.brokers.get_or_init(|| BrokerMetrics::new(self.registry()).expect("couldn't register broker metrics"))
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite(&self) -> &SqliteMetrics {
+        self.inner.sqlite.get_or_init(|| SqliteMetrics::new(self.registry()).expect("couldn't register SQLite metrics"))
+    }
+
+    #[cfg(feature = "http")]
+    pub fn alerts(&self) -> &AlertMetrics {
+        self.inner.alerts.get_or_init(|| AlertMetrics::new(self.registry()).expect("couldn't register alert metrics"))
+    }
+
+    #[cfg(feature = "http")]
+    pub fn live(&self) -> &LiveMetrics {
+        self.inner.live.get_or_init(|| LiveMetrics::new(self.registry()).expect("couldn't register live stream metrics"))
+    }
+}
+
+impl std::default::Default for Metrics {
+    fn default() -> Self {
+        Self::new(Registry::new())
+    }
+}
+
+// Registers the collector and hands it back for use
+pub fn register<C : Collector + Clone + 'static>(registry : &Registry, collector : C) -> prometheus::Result<C> {
+    registry.register(Box::new(collector.clone()))?;
+    Ok(collector)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groups_are_registered_once_on_first_use() {
+        let metrics = Metrics::default();
+        assert!(metrics.registry().gather().is_empty());
+
+        metrics.gateway().serde_errors.with_label_values(&["json"]).inc();
+        metrics.clone().gateway().serde_errors.with_label_values(&["json"]).inc();
+
+        let families = metrics.registry().gather();
+        assert_eq!(1, families.len());
+        assert_eq!("ruuvi_gateway_serde_error_count", families[0].get_name());
+        assert_eq!(2.0, families[0].get_metric()[0].get_counter().get_value());
+
+        // Independent instances don't share values
+        assert_eq!(0.0, Metrics::default().gateway().serde_errors.with_label_values(&["json"]).get());
+    }
+}
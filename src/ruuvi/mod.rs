@@ -1,3 +1,37 @@
 pub mod parser;
+#[cfg(feature = "prometheus")]
 pub mod prometheus;
+#[cfg(feature = "std")]
 pub mod gateway;
+#[cfg(feature = "std")]
+pub mod battery;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "prometheus")]
+pub mod pipeline;
+#[cfg(feature = "prometheus")]
+pub mod sink;
+#[cfg(feature = "prometheus")]
+pub mod state;
+#[cfg(feature = "prometheus")]
+pub mod latency;
+#[cfg(feature = "prometheus")]
+pub mod clock;
+#[cfg(feature = "http")]
+pub mod influxdb;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "mqtt")]
+pub mod homeassistant;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "http")]
+pub mod alerts;
+#[cfg(feature = "http")]
+pub mod live;
+#[cfg(feature = "http")]
+pub mod url;
+#[cfg(feature = "prometheus")]
+pub mod capture;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rumqttc::{v5, AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+use prometheus::{CounterVec, IntGaugeVec, Opts, Registry};
+use tracing::{error, warn};
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+use crate::ruuvi::parser::{RuuviData, RuuviSink};
+
+#[derive(Clone)]
+pub struct MqttMetrics {
+    pub published: CounterVec,
+}
+
+impl MqttMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            published: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_mqtt_publish_count",
+                "Number of decoded measurements published to MQTT."),
+                &["result"])?)?,
+        })
+    }
+}
+
+// Connection state of the brokers the listener subscribes to
+#[derive(Clone)]
+pub struct BrokerMetrics {
+    pub connected: IntGaugeVec,
+    pub connects: CounterVec,
+    pub errors: CounterVec,
+    pub received: CounterVec,
+    pub dropped: CounterVec,
+}
+
+impl BrokerMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            connected: register(registry, IntGaugeVec::new(Opts::new(
+                "ruuvi_mqtt_connected",
+                "1 while connected to the broker."),
+                &["site"])?)?,
+            connects: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_mqtt_connect_count",
+                "Number of connections acknowledged by the broker."),
+                &["site"])?)?,
+            errors: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_mqtt_connection_error_count",
+                "Number of connection errors, each followed by a reconnect."),
+                &["site"])?)?,
+            received: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_mqtt_received_count",
+                "Number of messages received from the broker."),
+                &["site"])?)?,
+            dropped: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_mqtt_dropped_count",
+                "Number of messages dropped because the decoding loop was behind."),
+                &["site"])?)?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MqttOutputConfig {
+    // Topic for the decoded measurements. {tag_mac}, {gateway_mac} and {name}
+    // are replaced with the values of each measurement.
+    #[serde(default = "default_topic")]
+    pub topic: String,
+
+    #[serde(default)]
+    pub retain: bool,
+
+    #[serde(default)]
+    pub qos: u8,
+}
+
+fn default_topic() -> String {
+    "ruuvi/decoded/{tag_mac}".to_string()
+}
+
+impl MqttOutputConfig {
+    // The fixed part of the topic before any placeholders. Messages published
+    // here must not be fed back to the decoder.
+    pub fn topic_prefix(&self) -> &str {
+        fixed_topic_prefix(&self.topic)
+    }
+
+    // Only valid after validate()
+    pub fn qos(&self) -> QoS {
+        match self.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.qos > 2 {
+            return Err(format!("qos must be 0, 1 or 2, not {}", self.qos));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DecodedMeasurement<'a> {
+    pub tag_mac: &'a str,
+    pub gateway_mac: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    pub site: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<&'a str>,
+    pub rssi: i16,
+    // Gateway reception time, unix seconds
+    pub timestamp: Option<u64>,
+    // Local reception time, unix seconds
+    pub received_at: Option<u64>,
+    #[serde(flatten)]
+    pub data: &'a RuuviData,
+}
+
+impl<'a> DecodedMeasurement<'a> {
+    pub fn new(message : &'a RuuviGatewayMessage, name : Option<&'a str>, data : &'a RuuviData) -> Self {
+        Self {
+            tag_mac: &message.mac,
+            gateway_mac: &message.gateway_mac,
+            site: &message.site,
+            name,
+            rssi: message.rssi,
+            timestamp: message.timestamp(),
+            received_at: message.received_at
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs()),
+            data,
+        }
+    }
+}
+
+// The part of a topic template before the first placeholder
+pub fn fixed_topic_prefix(template : &str) -> &str {
+    match template.find('{') {
+        Some(index) => &template[..index],
+        None => template,
+    }
+}
+
+// Replaces the placeholders of a topic template. The values can't add topic
+// levels or wildcards, /, + and # in them become _.
+pub fn render_topic(template : &str, message : &RuuviGatewayMessage, name : Option<&str>) -> String {
+    template
+        .replace("{tag_mac}", &topic_level(&message.mac))
+        .replace("{gateway_mac}", &topic_level(&message.gateway_mac))
+        .replace("{name}", &topic_level(name.unwrap_or(&message.mac)))
+}
+
+fn topic_level(value : &str) -> String {
+    value.replace(['/', '+', '#', '\0'], "_")
+}
+
+// Client of an MQTT 3.1.1 or 5 connection. The try_ methods only queue the
+// request for the event loop.
+#[derive(Clone)]
+pub enum MqttClient {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl From<AsyncClient> for MqttClient {
+    fn from(client : AsyncClient) -> Self {
+        MqttClient::V4(client)
+    }
+}
+
+impl From<v5::AsyncClient> for MqttClient {
+    fn from(client : v5::AsyncClient) -> Self {
+        MqttClient::V5(client)
+    }
+}
+
+fn v5_qos(qos : QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+impl MqttClient {
+    pub fn try_publish<P : Into<Vec<u8>>>(&self, topic : String, qos : QoS, retain : bool, payload : P) -> Result<(), String> {
+        match self {
+            MqttClient::V4(client) => client.try_publish(topic, qos, retain, payload).map_err(|e| e.to_string()),
+            MqttClient::V5(client) => client.try_publish(topic, v5_qos(qos), retain, payload.into()).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn try_subscribe(&self, topic : &str, qos : QoS) -> Result<(), String> {
+        match self {
+            MqttClient::V4(client) => client.try_subscribe(topic, qos).map_err(|e| e.to_string()),
+            MqttClient::V5(client) => client.try_subscribe(topic, v5_qos(qos)).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn try_unsubscribe(&self, topic : &str) -> Result<(), String> {
+        match self {
+            MqttClient::V4(client) => client.try_unsubscribe(topic).map_err(|e| e.to_string()),
+            MqttClient::V5(client) => client.try_unsubscribe(topic).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn try_disconnect(&self) -> Result<(), String> {
+        match self {
+            MqttClient::V4(client) => client.try_disconnect().map_err(|e| e.to_string()),
+            MqttClient::V5(client) => client.try_disconnect().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Publishes every measurement as a JSON document through the listener's own
+// MQTT connection.
+pub struct MqttOutputSink {
+    client: MqttClient,
+    config: MqttOutputConfig,
+    names: HashMap<String, String>,
+    metrics: MqttMetrics,
+}
+
+impl MqttOutputSink {
+    pub fn new(client : impl Into<MqttClient>, config : MqttOutputConfig, names : HashMap<String, String>, metrics : &Metrics) -> Self {
+        Self {
+            client: client.into(),
+            config,
+            names,
+            metrics: metrics.mqtt().clone(),
+        }
+    }
+}
+
+impl RuuviSink for MqttOutputSink {
+    fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
+        let message = RuuviGatewayMessage {
+            mac: source_mac.to_string(),
+            received_at: Some(SystemTime::now()),
+            ..Default::default()
+        };
+        self.sink_message(&message, measurement);
+    }
+
+    fn sink_message(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        let name = self.names.get(&message.mac.to_uppercase()).map(|name| name.as_str());
+        let topic = render_topic(&self.config.topic, message, name);
+        let payload = match serde_json::to_vec(&DecodedMeasurement::new(message, name, &measurement)) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!(error = %e, "couldn't serialize measurement");
+                self.metrics.published.with_label_values(&["error"]).inc();
+                return;
+            }
+        };
+
+        // try_publish doesn't wait for the event loop, which might itself be
+        // waiting for this sink
+        match self.client.try_publish(topic, self.config.qos(), self.config.retain, payload) {
+            Ok(()) => self.metrics.published.with_label_values(&["ok"]).inc(),
+            Err(e) => {
+                warn!(error = %e, "couldn't publish measurement");
+                self.metrics.published.with_label_values(&["error"]).inc();
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::{DataFormat3, DataFormat5};
+    use rumqttc::Request;
+
+    fn test_message() -> RuuviGatewayMessage {
+        RuuviGatewayMessage {
+            rssi: -62,
+            ts: "1646578374".into(),
+            mac: "11:22:33:44:55:66".to_string(),
+            gateway_mac: "AA:BB:CC:DD:EE:FF".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_topic_template() {
+        let config : MqttOutputConfig = toml::from_str("").unwrap();
+        assert_eq!("ruuvi/decoded/11:22:33:44:55:66", render_topic(&config.topic, &test_message(), None));
+        assert_eq!("ruuvi/decoded/", config.topic_prefix());
+
+        assert_eq!("home/AA:BB:CC:DD:EE:FF/sauna", render_topic("home/{gateway_mac}/{name}", &test_message(), Some("sauna")));
+        assert_eq!("home/sauna_bench _1_", render_topic("home/{name}", &test_message(), Some("sauna/bench #1+")));
+    }
+
+    #[test]
+    fn test_decoded_measurement_json() {
+        let measurement = RuuviData::V5(DataFormat5 {
+            temperature: Some(24.5),
+            mac: [0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+            ..Default::default()
+        });
+
+        let message = test_message();
+        let json = serde_json::to_value(DecodedMeasurement::new(&message, Some("sauna"), &measurement)).unwrap();
+
+        assert_eq!("11:22:33:44:55:66", json["tag_mac"]);
+        assert_eq!("AA:BB:CC:DD:EE:FF", json["gateway_mac"]);
+        assert_eq!("sauna", json["name"]);
+        assert_eq!(-62, json["rssi"]);
+        assert_eq!(1646578374, json["timestamp"]);
+        assert_eq!(5, json["format"]);
+        assert_eq!(24.5, json["temperature"]);
+        assert_eq!("11:22:33:44:55:66", json["mac"]);
+
+        // Fields the data format doesn't have are left out
+        let measurement = RuuviData::V3(DataFormat3::default());
+        let json = serde_json::to_value(DecodedMeasurement::new(&message, None, &measurement)).unwrap();
+        assert_eq!(3, json["format"]);
+        assert!(json.get("tx_power").is_none());
+        assert!(json.get("mac").is_none());
+    }
+
+    #[test]
+    fn test_publishes_to_client() {
+        let (requests_tx, requests_rx) = flume::bounded(10);
+        let client = AsyncClient::from_senders(requests_tx);
+        let config : MqttOutputConfig = toml::from_str("retain = true\nqos = 1").unwrap();
+
+        let mut sink = MqttOutputSink::new(client, config, HashMap::new(), &Metrics::default());
+        sink.sink_message(&test_message(), RuuviData::V5(DataFormat5::default()));
+
+        match requests_rx.try_recv().unwrap() {
+            Request::Publish(publish) => {
+                assert_eq!("ruuvi/decoded/11:22:33:44:55:66", publish.topic);
+                assert!(publish.retain);
+                assert_eq!(QoS::AtLeastOnce, publish.qos);
+                let json : serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+                assert_eq!("11:22:33:44:55:66", json["tag_mac"]);
+            }
+            request => panic!("unexpected request {:?}", request),
+        }
+    }
+
+    #[test]
+    fn test_publishes_to_v5_client() {
+        let (requests_tx, requests_rx) = flume::bounded(10);
+        let client = v5::AsyncClient::from_senders(requests_tx);
+        let config : MqttOutputConfig = toml::from_str("qos = 1").unwrap();
+
+        let mut sink = MqttOutputSink::new(client, config, HashMap::new(), &Metrics::default());
+        sink.sink_message(&test_message(), RuuviData::V5(DataFormat5::default()));
+
+        match requests_rx.try_recv().unwrap() {
+            v5::Request::Publish(publish) => {
+                assert_eq!(&b"ruuvi/decoded/11:22:33:44:55:66"[..], publish.topic);
+                assert_eq!(v5::mqttbytes::QoS::AtLeastOnce, publish.qos);
+            }
+            request => panic!("unexpected request {:?}", request),
+        }
+    }
+}
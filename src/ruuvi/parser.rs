@@ -1,133 +1,880 @@
-use std::{num::ParseIntError};
+#[cfg(feature = "std")]
+use std::collections::hash_map::Entry;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::mem::Discriminant;
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
-pub struct RuuviData {
+#[cfg(feature = "std")]
+use lazy_static::lazy_static;
+#[cfg(feature = "std")]
+use serde::ser::{Serialize, SerializeMap, Serializer};
+#[cfg(feature = "std")]
+use tracing::{debug, warn};
 
-    pub format: u8,
+#[cfg(feature = "std")]
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+
+// Data format 3 (RAWv1)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataFormat3 {
     pub temperature: f32,
-    pub pressure: u32,
     pub humidity: f32,
+    pub pressure: u32,
+    pub acceleration_x: f32,
+    pub acceleration_y: f32,
+    pub acceleration_z: f32,
+    pub voltage: f32,
+}
+
+// Data format 5 (RAWv2). Temperature, humidity and pressure the tag reports
+// as invalid are None.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataFormat5 {
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<u32>,
     pub acceleration_x: f32,
     pub acceleration_y: f32,
     pub acceleration_z: f32,
     pub tx_power: i16,
     pub voltage: f32,
     pub movement: u8,
-    pub measurement_sequence: u32,
+    pub measurement_sequence: u16,
     pub mac: [u8; 6],
-    // mac
 }
 
-impl std::default::Default for RuuviData {
-    fn default() -> Self {
-        Self {
-            format: 0,
-            temperature: 0.0,
-            pressure: 0,
-            humidity: 0.0,
-            acceleration_x: 0.0,
-            acceleration_y: 0.0,
-            acceleration_z: 0.0,
-            tx_power: 0,
-            voltage: 0.0,
-            movement: 0,
-            measurement_sequence: 0,
-            mac: [0; 6],
+// Data format C5 (Cut-RAWv2), format 5 without acceleration
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataFormatC5 {
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<u32>,
+    pub tx_power: i16,
+    pub voltage: f32,
+    pub movement: u8,
+    pub measurement_sequence: u16,
+    pub mac: [u8; 6],
+}
+
+// Data format 8, format C5 encrypted with a per-tag AES-128 key
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataFormat8 {
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<u32>,
+    pub tx_power: i16,
+    pub voltage: f32,
+    pub movement: u8,
+    pub measurement_sequence: u16,
+    pub mac: [u8; 6],
+}
+
+// Data format 6, Ruuvi Air over Bluetooth 4. Sensors which aren't ready
+// report None.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataFormat6 {
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<u32>,
+    pub pm2_5: Option<f32>,
+    pub co2: Option<u16>,
+    pub voc_index: Option<u16>,
+    pub nox_index: Option<u16>,
+    pub luminosity: Option<f32>,
+    // Lowest 8 bits of the sequence number
+    pub measurement_sequence: u8,
+    pub calibration_in_progress: bool,
+    // Lowest 3 bytes of the MAC
+    pub mac_suffix: [u8; 3],
+}
+
+// Data format E1, Ruuvi Air extended advertisements
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DataFormatE1 {
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<u32>,
+    pub pm1_0: Option<f32>,
+    pub pm2_5: Option<f32>,
+    pub pm4_0: Option<f32>,
+    pub pm10_0: Option<f32>,
+    pub co2: Option<u16>,
+    pub voc_index: Option<u16>,
+    pub nox_index: Option<u16>,
+    pub luminosity: Option<f32>,
+    pub measurement_sequence: Option<u32>,
+    pub calibration_in_progress: bool,
+    pub mac: [u8; 6],
+}
+
+// A decoded measurement in the data format the tag sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuuviData {
+    V3(DataFormat3),
+    V5(DataFormat5),
+    C5(DataFormatC5),
+    V8(DataFormat8),
+    V6(DataFormat6),
+    E1(DataFormatE1),
+}
+
+// Quantities shared between the data formats. Quantities a format doesn't
+// carry, or which the tag reported as invalid, are None.
+pub trait RuuviMeasurement {
+    fn format(&self) -> u8;
+    fn temperature(&self) -> Option<f32> { None }
+    fn humidity(&self) -> Option<f32> { None }
+    fn pressure(&self) -> Option<u32> { None }
+    // x, y and z in G
+    fn acceleration(&self) -> Option<(f32, f32, f32)> { None }
+    fn tx_power(&self) -> Option<i16> { None }
+    fn voltage(&self) -> Option<f32> { None }
+    fn movement(&self) -> Option<u8> { None }
+    fn measurement_sequence(&self) -> Option<u32> { None }
+    fn mac(&self) -> Option<[u8; 6]> { None }
+    fn pm1_0(&self) -> Option<f32> { None }
+    fn pm2_5(&self) -> Option<f32> { None }
+    fn pm4_0(&self) -> Option<f32> { None }
+    fn pm10_0(&self) -> Option<f32> { None }
+    fn co2(&self) -> Option<u16> { None }
+    fn voc_index(&self) -> Option<u16> { None }
+    fn nox_index(&self) -> Option<u16> { None }
+    fn luminosity(&self) -> Option<f32> { None }
+    fn calibration_in_progress(&self) -> Option<bool> { None }
+}
+
+impl RuuviMeasurement for DataFormat3 {
+    fn format(&self) -> u8 { 0x03 }
+    fn temperature(&self) -> Option<f32> { Some(self.temperature) }
+    fn humidity(&self) -> Option<f32> { Some(self.humidity) }
+    fn pressure(&self) -> Option<u32> { Some(self.pressure) }
+    fn acceleration(&self) -> Option<(f32, f32, f32)> { Some((self.acceleration_x, self.acceleration_y, self.acceleration_z)) }
+    fn voltage(&self) -> Option<f32> { Some(self.voltage) }
+}
+
+impl RuuviMeasurement for DataFormat5 {
+    fn format(&self) -> u8 { 0x05 }
+    fn temperature(&self) -> Option<f32> { self.temperature }
+    fn humidity(&self) -> Option<f32> { self.humidity }
+    fn pressure(&self) -> Option<u32> { self.pressure }
+    fn acceleration(&self) -> Option<(f32, f32, f32)> { Some((self.acceleration_x, self.acceleration_y, self.acceleration_z)) }
+    fn tx_power(&self) -> Option<i16> { Some(self.tx_power) }
+    fn voltage(&self) -> Option<f32> { Some(self.voltage) }
+    fn movement(&self) -> Option<u8> { Some(self.movement) }
+    fn measurement_sequence(&self) -> Option<u32> { Some(self.measurement_sequence as u32) }
+    fn mac(&self) -> Option<[u8; 6]> { Some(self.mac) }
+}
+
+impl RuuviMeasurement for DataFormatC5 {
+    fn format(&self) -> u8 { 0xC5 }
+    fn temperature(&self) -> Option<f32> { self.temperature }
+    fn humidity(&self) -> Option<f32> { self.humidity }
+    fn pressure(&self) -> Option<u32> { self.pressure }
+    fn tx_power(&self) -> Option<i16> { Some(self.tx_power) }
+    fn voltage(&self) -> Option<f32> { Some(self.voltage) }
+    fn movement(&self) -> Option<u8> { Some(self.movement) }
+    fn measurement_sequence(&self) -> Option<u32> { Some(self.measurement_sequence as u32) }
+    fn mac(&self) -> Option<[u8; 6]> { Some(self.mac) }
+}
+
+impl RuuviMeasurement for DataFormat8 {
+    fn format(&self) -> u8 { 0x08 }
+    fn temperature(&self) -> Option<f32> { self.temperature }
+    fn humidity(&self) -> Option<f32> { self.humidity }
+    fn pressure(&self) -> Option<u32> { self.pressure }
+    fn tx_power(&self) -> Option<i16> { Some(self.tx_power) }
+    fn voltage(&self) -> Option<f32> { Some(self.voltage) }
+    fn movement(&self) -> Option<u8> { Some(self.movement) }
+    fn measurement_sequence(&self) -> Option<u32> { Some(self.measurement_sequence as u32) }
+    fn mac(&self) -> Option<[u8; 6]> { Some(self.mac) }
+}
+
+impl RuuviMeasurement for DataFormat6 {
+    fn format(&self) -> u8 { 0x06 }
+    fn temperature(&self) -> Option<f32> { self.temperature }
+    fn humidity(&self) -> Option<f32> { self.humidity }
+    fn pressure(&self) -> Option<u32> { self.pressure }
+    fn measurement_sequence(&self) -> Option<u32> { Some(self.measurement_sequence as u32) }
+    fn pm2_5(&self) -> Option<f32> { self.pm2_5 }
+    fn co2(&self) -> Option<u16> { self.co2 }
+    fn voc_index(&self) -> Option<u16> { self.voc_index }
+    fn nox_index(&self) -> Option<u16> { self.nox_index }
+    fn luminosity(&self) -> Option<f32> { self.luminosity }
+    fn calibration_in_progress(&self) -> Option<bool> { Some(self.calibration_in_progress) }
+}
+
+impl RuuviMeasurement for DataFormatE1 {
+    fn format(&self) -> u8 { 0xE1 }
+    fn temperature(&self) -> Option<f32> { self.temperature }
+    fn humidity(&self) -> Option<f32> { self.humidity }
+    fn pressure(&self) -> Option<u32> { self.pressure }
+    fn measurement_sequence(&self) -> Option<u32> { self.measurement_sequence }
+    fn mac(&self) -> Option<[u8; 6]> { Some(self.mac) }
+    fn pm1_0(&self) -> Option<f32> { self.pm1_0 }
+    fn pm2_5(&self) -> Option<f32> { self.pm2_5 }
+    fn pm4_0(&self) -> Option<f32> { self.pm4_0 }
+    fn pm10_0(&self) -> Option<f32> { self.pm10_0 }
+    fn co2(&self) -> Option<u16> { self.co2 }
+    fn voc_index(&self) -> Option<u16> { self.voc_index }
+    fn nox_index(&self) -> Option<u16> { self.nox_index }
+    fn luminosity(&self) -> Option<f32> { self.luminosity }
+    fn calibration_in_progress(&self) -> Option<bool> { Some(self.calibration_in_progress) }
+}
+
+impl RuuviData {
+    fn measurement(&self) -> &dyn RuuviMeasurement {
+        match self {
+            RuuviData::V3(data) => data,
+            RuuviData::V5(data) => data,
+            RuuviData::C5(data) => data,
+            RuuviData::V8(data) => data,
+            RuuviData::V6(data) => data,
+            RuuviData::E1(data) => data,
+        }
+    }
+
+    // The quantities this measurement provides, in a fixed order. The MAC
+    // isn't included.
+    #[cfg(feature = "std")]
+    pub fn fields(&self) -> Vec<(&'static str, FieldValue)> {
+        let mut fields = vec![("format", FieldValue::Integer(self.format() as i64))];
+        let mut push = |name, value : Option<FieldValue>| {
+            if let Some(value) = value {
+                fields.push((name, value));
+            }
+        };
+        let float = |value : Option<f32>| value.map(FieldValue::Float);
+
+        push("temperature", float(self.temperature()));
+        push("humidity", float(self.humidity()));
+        push("pressure", self.pressure().map(|value| FieldValue::Integer(value as i64)));
+        if let Some((x, y, z)) = self.acceleration() {
+            push("acceleration_x", Some(FieldValue::Float(x)));
+            push("acceleration_y", Some(FieldValue::Float(y)));
+            push("acceleration_z", Some(FieldValue::Float(z)));
+        }
+        push("tx_power", self.tx_power().map(|value| FieldValue::Integer(value as i64)));
+        push("voltage", float(self.voltage()));
+        push("movement", self.movement().map(|value| FieldValue::Integer(value as i64)));
+        push("measurement_sequence", self.measurement_sequence().map(|value| FieldValue::Integer(value as i64)));
+        push("pm1_0", float(self.pm1_0()));
+        push("pm2_5", float(self.pm2_5()));
+        push("pm4_0", float(self.pm4_0()));
+        push("pm10_0", float(self.pm10_0()));
+        push("co2", self.co2().map(|value| FieldValue::Integer(value as i64)));
+        push("voc_index", self.voc_index().map(|value| FieldValue::Integer(value as i64)));
+        push("nox_index", self.nox_index().map(|value| FieldValue::Integer(value as i64)));
+        push("luminosity", float(self.luminosity()));
+        push("calibration_in_progress", self.calibration_in_progress().map(FieldValue::Boolean));
+        fields
+    }
+
+    #[cfg(feature = "std")]
+    pub fn has_field(&self, name : &str) -> bool {
+        self.fields().iter().any(|(field, _)| *field == name)
+    }
+}
+
+impl RuuviMeasurement for RuuviData {
+    fn format(&self) -> u8 { self.measurement().format() }
+    fn temperature(&self) -> Option<f32> { self.measurement().temperature() }
+    fn humidity(&self) -> Option<f32> { self.measurement().humidity() }
+    fn pressure(&self) -> Option<u32> { self.measurement().pressure() }
+    fn acceleration(&self) -> Option<(f32, f32, f32)> { self.measurement().acceleration() }
+    fn tx_power(&self) -> Option<i16> { self.measurement().tx_power() }
+    fn voltage(&self) -> Option<f32> { self.measurement().voltage() }
+    fn movement(&self) -> Option<u8> { self.measurement().movement() }
+    fn measurement_sequence(&self) -> Option<u32> { self.measurement().measurement_sequence() }
+    fn mac(&self) -> Option<[u8; 6]> { self.measurement().mac() }
+    fn pm1_0(&self) -> Option<f32> { self.measurement().pm1_0() }
+    fn pm2_5(&self) -> Option<f32> { self.measurement().pm2_5() }
+    fn pm4_0(&self) -> Option<f32> { self.measurement().pm4_0() }
+    fn pm10_0(&self) -> Option<f32> { self.measurement().pm10_0() }
+    fn co2(&self) -> Option<u16> { self.measurement().co2() }
+    fn voc_index(&self) -> Option<u16> { self.measurement().voc_index() }
+    fn nox_index(&self) -> Option<u16> { self.measurement().nox_index() }
+    fn luminosity(&self) -> Option<f32> { self.measurement().luminosity() }
+    fn calibration_in_progress(&self) -> Option<bool> { self.measurement().calibration_in_progress() }
+}
+
+// Value of one measurement field, integers are kept apart for InfluxDB
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue {
+    Float(f32),
+    Integer(i64),
+    Boolean(bool),
+}
+
+#[cfg(feature = "std")]
+impl std::fmt::Display for FieldValue {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FieldValue::Float(value) => write!(f, "{}", value),
+            FieldValue::Integer(value) => write!(f, "{}", value),
+            FieldValue::Boolean(value) => write!(f, "{}", value),
         }
     }
 }
 
-impl RuuviData {
-    pub fn new() -> Self {
-        Self::default()
+#[cfg(feature = "std")]
+impl Serialize for FieldValue {
+    fn serialize<S: Serializer>(&self, serializer : S) -> Result<S::Ok, S::Error> {
+        match self {
+            FieldValue::Float(value) => serializer.serialize_f32(*value),
+            FieldValue::Integer(value) => serializer.serialize_i64(*value),
+            FieldValue::Boolean(value) => serializer.serialize_bool(*value),
+        }
+    }
+}
+
+// Serialized as a flat object of the provided fields and the MAC
+#[cfg(feature = "std")]
+impl Serialize for RuuviData {
+    fn serialize<S: Serializer>(&self, serializer : S) -> Result<S::Ok, S::Error> {
+        let fields = self.fields();
+        let mut map = serializer.serialize_map(Some(fields.len() + 1))?;
+        for (name, value) in &fields {
+            map.serialize_entry(name, value)?;
+        }
+        if let Some(mac) = self.mac() {
+            map.serialize_entry("mac", &format_mac(&mac))?;
+        }
+        map.end()
     }
 }
 
+// Formats a MAC as AA:BB:CC:DD:EE:FF
+#[cfg(feature = "std")]
+pub fn format_mac(mac : &[u8; 6]) -> String {
+    mac.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(":")
+}
+
+#[cfg(feature = "std")]
 pub trait RuuviSink {
     fn sink(&mut self, source_mac : &str, measurement : RuuviData);
+
+    // Called for measurements decoded from a gateway message. Sinks which need
+    // more than the tag MAC (gateway, RSSI, timestamp) override this.
+    fn sink_message(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        self.sink(&message.mac, measurement);
+    }
+
+    // Called once on shutdown after the last measurement
+    fn flush(&mut self) {}
+}
+
+// Why an advertisement couldn't be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    OddHexLength(usize),
+    #[cfg(feature = "std")]
+    InvalidHex(String),
+    PacketTooShort(usize),
+    NotManufacturerData(u8),
+    UnknownManufacturer(u16),
+    UnknownFormat(u8),
+    PayloadTooShort { length: usize, expected: usize },
+    // Format 8 from a tag without a configured key
+    MissingKey,
+    // The decrypted format 8 block didn't match its CRC, usually a wrong key
+    CrcMismatch { expected: u8, actual: u8 },
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f : &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            DecodeError::OddHexLength(length) => write!(f, "odd number of hex digits: {}", length),
+            #[cfg(feature = "std")]
+            DecodeError::InvalidHex(s) => write!(f, "invalid hex: {:?}", s),
+            DecodeError::PacketTooShort(length) => write!(f, "packet too short: {} bytes", length),
+            DecodeError::NotManufacturerData(data_type) => write!(f, "data type not FF but {:02X}", data_type),
+            DecodeError::UnknownManufacturer(id) => write!(f, "manufacturer id was not for Ruuvi Ltd's 0x0499 but 0x{:04X}", id),
+            DecodeError::UnknownFormat(format) => write!(f, "unknown ruuvi protocol version {:02X}", format),
+            DecodeError::PayloadTooShort { length, expected } => write!(f, "ruuvi payload too short: {} bytes, expected {}", length, expected),
+            DecodeError::MissingKey => write!(f, "no decryption key for encrypted payload"),
+            DecodeError::CrcMismatch { expected, actual } => write!(f, "CRC of decrypted payload was {:02X}, expected {:02X}", actual, expected),
+        }
+    }
+}
+
+// A broken tag fails the same way on every advertisement, so each tag and
+// error kind is logged at most once per interval
+#[cfg(feature = "std")]
+const DECODE_ERROR_LOG_INTERVAL : Duration = Duration::from_secs(60);
+#[cfg(feature = "std")]
+const DECODE_ERROR_LOG_MAX_KEYS : usize = 4096;
+
+#[cfg(feature = "std")]
+struct DecodeErrorLog {
+    interval: Duration,
+    // Last logged time and suppressed repeats since then
+    entries: HashMap<(String, Discriminant<DecodeError>), (Instant, u64)>,
+}
+
+#[cfg(feature = "std")]
+impl DecodeErrorLog {
+    fn new(interval : Duration) -> Self {
+        Self {
+            interval,
+            entries: HashMap::new(),
+        }
+    }
+
+    // Returns the number of suppressed repeats when the error should be logged now
+    fn should_log(&mut self, source_mac : &str, error : &DecodeError, now : Instant) -> Option<u64> {
+        if self.entries.len() >= DECODE_ERROR_LOG_MAX_KEYS {
+            let interval = self.interval;
+            self.entries.retain(|_, (last, _)| now.duration_since(*last) < interval);
+        }
+
+        match self.entries.entry((source_mac.to_string(), std::mem::discriminant(error))) {
+            Entry::Occupied(mut entry) => {
+                let (last, suppressed) = entry.get_mut();
+                if now.duration_since(*last) < self.interval {
+                    *suppressed += 1;
+                    return None;
+                }
+                let repeats = *suppressed;
+                *last = now;
+                *suppressed = 0;
+                Some(repeats)
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+lazy_static! {
+    static ref DECODE_ERROR_LOG: Mutex<DecodeErrorLog> = Mutex::new(DecodeErrorLog::new(DECODE_ERROR_LOG_INTERVAL));
+}
+
+#[cfg(feature = "std")]
+fn log_decode_error(source_mac : &str, error : &DecodeError) {
+    match error {
+        // Gateways forward advertisements of every nearby BLE device
+        DecodeError::NotManufacturerData(_) | DecodeError::UnknownManufacturer(_) => {
+            debug!(tag = source_mac, %error, "not a Ruuvi advertisement");
+        }
+        _ => {
+            let suppressed = match DECODE_ERROR_LOG.lock() {
+                Ok(mut log) => log.should_log(source_mac, error, Instant::now()),
+                Err(_) => Some(0),
+            };
+            if let Some(suppressed) = suppressed {
+                warn!(tag = source_mac, %error, suppressed, "couldn't decode advertisement");
+            }
+        }
+    }
 }
 
 // Takes a string such as "AABB" and returns Vec with AA and BB
-fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
+#[cfg(feature = "std")]
+pub fn decode_hex(s : &str) -> Result<Vec<u8>, DecodeError> {
+    if !s.len().is_multiple_of(2) {
+        return Err(DecodeError::OddHexLength(s.len()));
+    }
     (0..s.len())
         .step_by(2)
-        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
-        .collect()
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| DecodeError::InvalidHex(s.to_string()))
 }
 
+// One length-type-value AD structure of a BLE advertisement
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdStructure {
+    pub ad_type: u8,
+    pub data: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl AdStructure {
+    pub fn type_name(&self) -> &'static str {
+        match self.ad_type {
+            0x01 => "Flags",
+            0x02 | 0x03 => "16-bit service UUIDs",
+            0x06 | 0x07 => "128-bit service UUIDs",
+            0x08 => "Shortened local name",
+            0x09 => "Complete local name",
+            0x0A => "TX power level",
+            0x16 => "Service data",
+            0xFF => "Manufacturer specific data",
+            _ => "Unknown",
+        }
+    }
+}
+
+// Splits an advertisement into its AD structures. Stops at the first zero
+// length or at a structure running past the end of the buffer.
+#[cfg(feature = "std")]
+pub fn parse_ad_structures(buf : &[u8]) -> Vec<AdStructure> {
+    let mut structures = Vec::new();
+    let mut i = 0;
+    while i < buf.len() {
+        let length = buf[i] as usize;
+        if length == 0 || i + 1 + length > buf.len() {
+            break;
+        }
+        structures.push(AdStructure {
+            ad_type: buf[i + 1],
+            data: buf[i + 2..i + 1 + length].to_vec(),
+        });
+        i += 1 + length;
+    }
+    structures
+}
+
+#[cfg(feature = "std")]
 pub fn decode_ble_ruuvi_str(s : &str, source_mac : &str, sink : &mut dyn RuuviSink) -> bool {
-    let buf = decode_hex(s).unwrap();
-    decode_ble_ruuvi(&buf[..], &source_mac, sink)
+    let buf = match decode_hex(s) {
+        Ok(buf) => buf,
+        Err(e) => {
+            log_decode_error(source_mac, &e);
+            return false;
+        }
+    };
+    decode_ble_ruuvi(&buf[..], source_mac, sink)
+}
+
+// Passes the gateway message along with each decoded measurement
+#[cfg(feature = "std")]
+struct GatewayMessageSink<'a> {
+    message : &'a RuuviGatewayMessage,
+    sink : &'a mut dyn RuuviSink,
+}
+
+#[cfg(feature = "std")]
+impl RuuviSink for GatewayMessageSink<'_> {
+    fn sink(&mut self, _source_mac : &str, measurement : RuuviData) {
+        self.sink.sink_message(self.message, measurement);
+    }
+}
+
+#[cfg(feature = "std")]
+pub fn decode_gateway_message(message : &RuuviGatewayMessage, sink : &mut dyn RuuviSink) -> bool {
+    let mut message_sink = GatewayMessageSink { message, sink };
+    decode_ble_ruuvi_str(&message.data, &message.mac, &mut message_sink)
+}
+
+// Like decode_gateway_message but decrypts format 8 with the given keys.
+// Errors are logged and returned so the caller can count them.
+#[cfg(feature = "std")]
+pub fn decode_gateway_message_with_keys(message : &RuuviGatewayMessage, keys : Option<&dyn DecryptionKeys>, sink : &mut dyn RuuviSink) -> Result<(), DecodeError> {
+    let measurement = decode_gateway_measurement(message, keys)?;
+    sink.sink_message(message, measurement);
+    Ok(())
+}
+
+// Decodes the advertisement of a gateway message for sinks fed
+// asynchronously. Errors are logged.
+#[cfg(feature = "std")]
+pub fn decode_gateway_measurement(message : &RuuviGatewayMessage, keys : Option<&dyn DecryptionKeys>) -> Result<RuuviData, DecodeError> {
+    decode_hex(&message.data)
+        .and_then(|buf| try_decode_ruuvi_with_keys(&buf, keys))
+        .inspect_err(|e| log_decode_error(&message.mac, e))
 }
 
 //pub fn decode_ble_ruuvi(buf : &[u8], sink : &mut Box<dyn RuuviSink>) -> bool {
+#[cfg(feature = "std")]
 pub fn decode_ble_ruuvi(buf : &[u8], source_mac : &str, sink : &mut dyn RuuviSink) -> bool {
+    match try_decode_ble_ruuvi(buf, source_mac, sink) {
+        Ok(()) => true,
+        Err(e) => {
+            log_decode_error(source_mac, &e);
+            false
+        }
+    }
+}
+
+// Like decode_ble_ruuvi but tells why decoding failed
+#[cfg(feature = "std")]
+pub fn try_decode_ble_ruuvi(buf : &[u8], source_mac : &str, sink : &mut dyn RuuviSink) -> Result<(), DecodeError> {
+    let measurement = try_decode_ruuvi(buf)?;
+    sink.sink(source_mac, measurement);
+    Ok(())
+}
+
+// Decrypts the AES block of format 8 payloads
+pub trait DecryptionKeys {
+    // Decrypts the block in place with the key of the tag. Returns false
+    // when there's no key for the tag.
+    fn decrypt(&self, mac : &[u8; 6], block : &mut [u8; 16]) -> bool;
+}
+
+// Decodes without keys, format 8 fails with MissingKey
+pub fn try_decode_ruuvi(buf : &[u8]) -> Result<RuuviData, DecodeError> {
+    try_decode_ruuvi_with_keys(buf, None)
+}
+
+pub fn try_decode_ruuvi_with_keys(buf : &[u8], keys : Option<&dyn DecryptionKeys>) -> Result<RuuviData, DecodeError> {
+
+    if buf.len() < 8 {
+        return Err(DecodeError::PacketTooShort(buf.len()));
+    }
 
     let _data_length = buf[3];
     let data_type = buf[4]; // 0xFF for manufacturer specific data
     if data_type != 0xFF {
-        println!("ERR, data type not FF but {:x}", data_type);
-        return false;
+        return Err(DecodeError::NotManufacturerData(data_type));
     }
     
     if buf[5] != 0x99 || buf[6] != 0x04 {
         // Manufacturer id was not for Ruuvi Ltd's 0x0499
-        println!("ERR, Manufacturer id was not for Ruuvi Ltd's 0x0499 but {:x}{:x}", buf[5], buf[6]);
-        return false;
+        return Err(DecodeError::UnknownManufacturer(((buf[6] as u16) << 8) + buf[5] as u16));
     }
     
-    // Ruuvi protocol version 3
-    if buf[7] == 0x03 {
-        let measurement = ruuvi_decode_v3(&buf[7..]);
-        sink.sink(source_mac, measurement);
-        return true;
-    } else if buf[7] == 0x05 {
-        let measurement = ruuvi_decode_v5(&buf[7..]);
-        sink.sink(source_mac, measurement);
-        return true;
-    } else {
-        println!("ERR, Unknown ruuvi protocol version {:x}", buf[7]);
+    let payload = &buf[7..];
+    let required_length = match payload[0] {
+        0x03 => 14,
+        0x05 => 24,
+        0x06 => 20,
+        0x08 => 24,
+        0xC5 => 18,
+        0xE1 => 40,
+        format => return Err(DecodeError::UnknownFormat(format)),
+    };
+    if payload.len() < required_length {
+        return Err(DecodeError::PayloadTooShort { length: payload.len(), expected: required_length });
     }
 
-    return false;
+    Ok(match payload[0] {
+        // Ruuvi protocol version 3
+        0x03 => RuuviData::V3(ruuvi_decode_v3(payload)),
+        0x05 => RuuviData::V5(ruuvi_decode_v5(payload)),
+        0x06 => RuuviData::V6(ruuvi_decode_v6(payload)),
+        0x08 => RuuviData::V8(ruuvi_decode_v8(payload, keys.ok_or(DecodeError::MissingKey)?)?),
+        0xC5 => RuuviData::C5(ruuvi_decode_c5(payload)),
+        _ => RuuviData::E1(ruuvi_decode_e1(payload)),
+    })
 }
 
-pub fn ruuvi_decode_v5(buf : &[u8]) -> RuuviData {
+fn u16_at(buf : &[u8], index : usize) -> u16 {
+    ((buf[index] as u16) << 8) + buf[index + 1] as u16
+}
 
-    let mut data : RuuviData = RuuviData::new();
+fn u24_at(buf : &[u8], index : usize) -> u32 {
+    ((buf[index] as u32) << 16) + ((buf[index + 1] as u32) << 8) + buf[index + 2] as u32
+}
 
-    data.format = buf[0] as u8;
-    data.temperature = (((buf[1] as u16) << 8) + buf[2] as u16) as i16 as f32 * 0.005;
-    data.humidity = (((buf[3] as u16) << 8) + buf[4] as u16) as f32 * 0.0025;
-    data.pressure = (((buf[5] as u32) << 8) + buf[6] as u32) + 50000;
-    data.acceleration_x = ((((buf[7] as u16) << 8) + buf[8] as u16) as i16) as f32 / 1000.0;
-    data.acceleration_y = ((((buf[9] as u16) << 8) + buf[10] as u16) as i16) as f32 / 1000.0;
-    data.acceleration_z = ((((buf[11] as u16) << 8) + buf[12] as u16) as i16) as f32 / 1000.0;
+// Temperature, humidity and pressure are encoded the same way in formats 5,
+// C5, 8, 6 and E1, starting at byte 1
+fn decode_environment(buf : &[u8]) -> (Option<f32>, Option<f32>, Option<u32>) {
+    let temperature = match u16_at(buf, 1) {
+        0x8000 => None,
+        raw => Some(raw as i16 as f32 * 0.005),
+    };
+    let humidity = match u16_at(buf, 3) {
+        0xFFFF => None,
+        raw => Some(raw as f32 * 0.0025),
+    };
+    let pressure = match u16_at(buf, 5) {
+        0xFFFF => None,
+        raw => Some(raw as u32 + 50000),
+    };
+    (temperature, humidity, pressure)
+}
 
-    let power_info = ((buf[13] as u16) << 8) + buf[14] as u16;
-    data.tx_power = ((power_info & 0b11111) as i16 * 2 - 40) as i16;
-    data.voltage = ((power_info >> 5) + 1600) as f32 / 1000.0;
-    data.movement = buf[15] as u8;
-    data.measurement_sequence = ((buf[16] as u32) << 8) + (buf[17] as u32);
+// Battery voltage in the upper 11 bits, TX power in the lower 5
+fn decode_power_info(power_info : u16) -> (f32, i16) {
+    (((power_info >> 5) + 1600) as f32 / 1000.0, (power_info & 0b11111) as i16 * 2 - 40)
+}
+
+fn decode_particulate_matter(raw : u16) -> Option<f32> {
+    (raw != 0xFFFF).then_some(raw as f32 * 0.1)
+}
+
+fn decode_co2(raw : u16) -> Option<u16> {
+    (raw != 0xFFFF).then_some(raw)
+}
 
-    for x in 0..6 {
-        data.mac[x] = buf[18 + x];
+// VOC and NOx indices are 9 bits, the upper 8 have their own byte and the
+// lowest bit is in the flags
+fn decode_index(high : u8, low_bit : bool) -> Option<u16> {
+    let index = ((high as u16) << 1) | low_bit as u16;
+    (index != 511).then_some(index)
+}
+
+// e^x for small positive x, core has no exp without std
+fn exp(x : f32) -> f32 {
+    let k = (x / core::f32::consts::LN_2) as u32;
+    let r = x - k as f32 * core::f32::consts::LN_2;
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for n in 1..10 {
+        term *= r / n as f32;
+        sum += term;
     }
+    sum * (1u32 << k) as f32
+}
 
-    return data;
+// Format 6 sends luminosity as a logarithmic code, 0 to 254 covers 0 to
+// 65535 lux
+fn decode_luminosity_code(code : u8) -> Option<f32> {
+    // ln(65536) / 254
+    const DELTA : f32 = 16.0 * core::f32::consts::LN_2 / 254.0;
+    (code != 255).then(|| exp(code as f32 * DELTA) - 1.0)
 }
 
-pub fn ruuvi_decode_v3(buf : &[u8]) -> RuuviData {
+pub fn ruuvi_decode_v5(buf : &[u8]) -> DataFormat5 {
+
+    let (temperature, humidity, pressure) = decode_environment(buf);
+    let mut data = DataFormat5 {
+        temperature,
+        humidity,
+        pressure,
+        acceleration_x: ((((buf[7] as u16) << 8) + buf[8] as u16) as i16) as f32 / 1000.0,
+        acceleration_y: ((((buf[9] as u16) << 8) + buf[10] as u16) as i16) as f32 / 1000.0,
+        acceleration_z: ((((buf[11] as u16) << 8) + buf[12] as u16) as i16) as f32 / 1000.0,
+        ..Default::default()
+    };
 
-    let mut data : RuuviData = RuuviData::new();
+    (data.voltage, data.tx_power) = decode_power_info(u16_at(buf, 13));
+    data.movement = buf[15];
+    data.measurement_sequence = u16_at(buf, 16);
 
-    data.format = buf[0] as u8;
-    data.humidity = (buf[1] as u8) as f32 * 0.5;
+    data.mac.copy_from_slice(&buf[18..24]);
+
+    data
+}
+
+// CRC-8 with polynomial 0x07 and no initial value, as used by format 8
+fn crc8(buf : &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in buf {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+// Bytes 1 to 16 are an AES-128-ECB block, byte 17 the CRC of the plaintext
+// and the MAC is in the clear. The plaintext has the layout of format C5
+// from byte 1 on, followed by reserved bytes.
+pub fn ruuvi_decode_v8(buf : &[u8], keys : &dyn DecryptionKeys) -> Result<DataFormat8, DecodeError> {
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&buf[18..24]);
+    let mut block = [0u8; 16];
+    block.copy_from_slice(&buf[1..17]);
+    if !keys.decrypt(&mac, &mut block) {
+        return Err(DecodeError::MissingKey);
+    }
+    let actual = crc8(&block);
+    if actual != buf[17] {
+        return Err(DecodeError::CrcMismatch { expected: buf[17], actual });
+    }
+
+    let mut plain = [0u8; 17];
+    plain[0] = buf[0];
+    plain[1..].copy_from_slice(&block);
+    let c5 = ruuvi_decode_c5_fields(&plain);
+    Ok(DataFormat8 {
+        temperature: c5.temperature,
+        humidity: c5.humidity,
+        pressure: c5.pressure,
+        tx_power: c5.tx_power,
+        voltage: c5.voltage,
+        movement: c5.movement,
+        measurement_sequence: c5.measurement_sequence,
+        mac,
+    })
+}
+
+pub fn ruuvi_decode_c5(buf : &[u8]) -> DataFormatC5 {
+    let mut data = ruuvi_decode_c5_fields(buf);
+    data.mac.copy_from_slice(&buf[12..18]);
+    data
+}
+
+// Format C5 up to the MAC
+fn ruuvi_decode_c5_fields(buf : &[u8]) -> DataFormatC5 {
+    let (temperature, humidity, pressure) = decode_environment(buf);
+    let mut data = DataFormatC5 {
+        temperature,
+        humidity,
+        pressure,
+        movement: buf[9],
+        measurement_sequence: u16_at(buf, 10),
+        ..Default::default()
+    };
+    (data.voltage, data.tx_power) = decode_power_info(u16_at(buf, 7));
+    data
+}
+
+pub fn ruuvi_decode_v6(buf : &[u8]) -> DataFormat6 {
+    let (temperature, humidity, pressure) = decode_environment(buf);
+    let flags = buf[16];
+
+    let mut data = DataFormat6 {
+        temperature,
+        humidity,
+        pressure,
+        pm2_5: decode_particulate_matter(u16_at(buf, 7)),
+        co2: decode_co2(u16_at(buf, 9)),
+        voc_index: decode_index(buf[11], flags & 0x40 != 0),
+        nox_index: decode_index(buf[12], flags & 0x80 != 0),
+        luminosity: decode_luminosity_code(buf[13]),
+        measurement_sequence: buf[15],
+        calibration_in_progress: flags & 0x01 != 0,
+        ..Default::default()
+    };
+    data.mac_suffix.copy_from_slice(&buf[17..20]);
+    data
+}
+
+pub fn ruuvi_decode_e1(buf : &[u8]) -> DataFormatE1 {
+    let (temperature, humidity, pressure) = decode_environment(buf);
+    let flags = buf[28];
+
+    let mut data = DataFormatE1 {
+        temperature,
+        humidity,
+        pressure,
+        pm1_0: decode_particulate_matter(u16_at(buf, 7)),
+        pm2_5: decode_particulate_matter(u16_at(buf, 9)),
+        pm4_0: decode_particulate_matter(u16_at(buf, 11)),
+        pm10_0: decode_particulate_matter(u16_at(buf, 13)),
+        co2: decode_co2(u16_at(buf, 15)),
+        voc_index: decode_index(buf[17], flags & 0x40 != 0),
+        nox_index: decode_index(buf[18], flags & 0x80 != 0),
+        luminosity: match u24_at(buf, 19) {
+            0xFFFFFF => None,
+            raw => Some(raw as f32 * 0.01),
+        },
+        measurement_sequence: match u24_at(buf, 25) {
+            0xFFFFFF => None,
+            raw => Some(raw),
+        },
+        calibration_in_progress: flags & 0x01 != 0,
+        ..Default::default()
+    };
+    data.mac.copy_from_slice(&buf[34..40]);
+    data
+}
+
+pub fn ruuvi_decode_v3(buf : &[u8]) -> DataFormat3 {
+
+    let mut data = DataFormat3 {
+        humidity: buf[1] as f32 * 0.5,
+        ..Default::default()
+    };
 
     // Temperature base: (MSB is sign, next 7 bits are decimal value)
     // Temperature fraction in 1/100
-    let temperature_base = buf[2] as u8 & 0x7F;
-    let temperature_fraction = (buf[3] as u8 as f32) / 100.0;
+    let temperature_base = buf[2] & 0x7F;
+    let temperature_fraction = (buf[3] as f32) / 100.0;
     let mut temperature = temperature_base as f32 + temperature_fraction;
     if (buf[2] >> 7) & 1 == 1 {
         temperature = -temperature;
@@ -141,11 +888,11 @@ pub fn ruuvi_decode_v3(buf : &[u8]) -> RuuviData {
 
     data.voltage = (((buf[12] as u16) << 8) + buf[13] as u16) as f32 / 1000.0;
 
-    return data;
+    data
 }
 
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
 
@@ -155,7 +902,7 @@ mod tests {
     }
 
     impl RuuviSink for RuuviTestSink {
-        fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
+        fn sink(&mut self, _source_mac : &str, measurement : RuuviData) {
             self.measurement = Some(measurement);
         }
     }
@@ -167,10 +914,10 @@ mod tests {
         //let mut test_sink = Box::new(RuuviTestSink{measurement:None});
         let mut test_sink = RuuviTestSink{measurement:None};
 
-        assert_eq!(true, decode_ble_ruuvi(&s[..], "", &mut test_sink));
-        assert_eq!(3, test_sink.measurement.as_ref().unwrap().format);
-        assert_eq!(25.41, test_sink.measurement.as_ref().unwrap().temperature);
-        assert_eq!(100791, test_sink.measurement.as_ref().unwrap().pressure);
+        assert!(decode_ble_ruuvi(&s[..], "", &mut test_sink));
+        assert_eq!(3, test_sink.measurement.as_ref().unwrap().format());
+        assert_eq!(Some(25.41), test_sink.measurement.as_ref().unwrap().temperature());
+        assert_eq!(Some(100791), test_sink.measurement.as_ref().unwrap().pressure());
     }
 
     #[test]
@@ -180,12 +927,96 @@ mod tests {
         //let mut test_sink = Box::new(RuuviTestSink{measurement:None});
         let mut test_sink = RuuviTestSink{measurement:None};
 
-        assert_eq!(true, decode_ble_ruuvi(&s[..], "", &mut test_sink));
-        assert_eq!(3, test_sink.measurement.as_ref().unwrap().format);
-        assert_eq!(25.41, test_sink.measurement.as_ref().unwrap().temperature);
-        assert_eq!(100791, test_sink.measurement.as_ref().unwrap().pressure);
+        assert!(decode_ble_ruuvi(&s[..], "", &mut test_sink));
+        assert_eq!(3, test_sink.measurement.as_ref().unwrap().format());
+        assert_eq!(Some(25.41), test_sink.measurement.as_ref().unwrap().temperature());
+        assert_eq!(Some(100791), test_sink.measurement.as_ref().unwrap().pressure());
+    }
+
+
+    #[test]
+    fn test_decode_gateway_message() {
+        let message = RuuviGatewayMessage {
+            data: "02010611FF9904035D1929C6670029FFEA041B0B6B".into(),
+            ..Default::default()
+        };
+
+        let mut test_sink = RuuviTestSink{measurement:None};
+
+        assert!(decode_gateway_message(&message, &mut test_sink));
+        assert_eq!(3, test_sink.measurement.as_ref().unwrap().format());
+    }
+
+    #[test]
+    fn test_decode_truncated_packets() {
+        let mut test_sink = RuuviTestSink{measurement:None};
+
+        let mut message = RuuviGatewayMessage {
+            data: "02010611FF9904035D1929".into(),
+            ..Default::default()
+        };
+        assert!(!decode_gateway_message(&message, &mut test_sink));
+
+        message.data = "02010611FF990405".into();
+        assert!(!decode_gateway_message(&message, &mut test_sink));
+
+        message.data = "020106".into();
+        assert!(!decode_gateway_message(&message, &mut test_sink));
+
+        message.data = "02010611FF99040".into();
+        assert!(!decode_gateway_message(&message, &mut test_sink));
+
+        message.data = "02010611FF9904ZZ".into();
+        assert!(!decode_gateway_message(&message, &mut test_sink));
+
+        assert!(test_sink.measurement.is_none());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(Err(DecodeError::OddHexLength(3)), decode_hex("ABC"));
+        assert_eq!(Err(DecodeError::InvalidHex("ZZ".to_string())), decode_hex("ZZ"));
+        assert_eq!(DecodeError::PacketTooShort(3), try_decode_ruuvi(&decode_hex("020106").unwrap()).unwrap_err());
+        assert_eq!(DecodeError::NotManufacturerData(0x16), try_decode_ruuvi(&decode_hex("0201060B16AABBCCDD").unwrap()).unwrap_err());
+        assert_eq!(DecodeError::UnknownManufacturer(0x004C), try_decode_ruuvi(&decode_hex("0201061AFF4C000215").unwrap()).unwrap_err());
+        assert_eq!(DecodeError::UnknownFormat(0x09), try_decode_ruuvi(&decode_hex("02010611FF99040900").unwrap()).unwrap_err());
+        assert_eq!(DecodeError::PayloadTooShort { length: 2, expected: 24 },
+            try_decode_ruuvi(&decode_hex("02010611FF99040800").unwrap()).unwrap_err());
+        assert_eq!(DecodeError::PayloadTooShort { length: 3, expected: 24 },
+            try_decode_ruuvi(&decode_hex("02010611FF9904051229").unwrap()).unwrap_err());
+    }
+
+    #[test]
+    fn test_parse_ad_structures() {
+        let buf = decode_hex("0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F").unwrap();
+        let structures = parse_ad_structures(&buf);
+
+        assert_eq!(2, structures.len());
+        assert_eq!(0x01, structures[0].ad_type);
+        assert_eq!(vec![0x06], structures[0].data);
+        assert_eq!("Manufacturer specific data", structures[1].type_name());
+        assert_eq!(26, structures[1].data.len());
+
+        // Truncated structure is left out
+        assert_eq!(1, parse_ad_structures(&buf[..10]).len());
     }
 
+    #[test]
+    fn test_decode_error_log_is_rate_limited() {
+        let mut log = DecodeErrorLog::new(Duration::from_secs(60));
+        let start = Instant::now();
+        let too_short = DecodeError::PacketTooShort(3);
+
+        assert_eq!(Some(0), log.should_log("AA", &too_short, start));
+        assert_eq!(None, log.should_log("AA", &too_short, start + Duration::from_secs(1)));
+        assert_eq!(None, log.should_log("AA", &DecodeError::PacketTooShort(4), start + Duration::from_secs(2)));
+        // Other tags and other kinds of errors are logged separately
+        assert_eq!(Some(0), log.should_log("BB", &too_short, start + Duration::from_secs(2)));
+        assert_eq!(Some(0), log.should_log("AA", &DecodeError::UnknownFormat(8), start + Duration::from_secs(2)));
+
+        assert_eq!(Some(2), log.should_log("AA", &too_short, start + Duration::from_secs(61)));
+        assert_eq!(None, log.should_log("AA", &too_short, start + Duration::from_secs(62)));
+    }
 
     #[test]
     fn it_works() {
@@ -221,10 +1052,10 @@ mod tests {
 
         let data = ruuvi_decode_v5(&s[..]);
 
-        assert_eq!(data.format, 5);
-        assert_approx_eq!(data.temperature, 24.3, 1e-5);
-        assert_approx_eq!(data.humidity, 53.49, 1e-5);
-        assert_eq!(data.pressure, 100044);
+        assert_eq!(data.format(), 5);
+        assert_approx_eq!(data.temperature.unwrap(), 24.3, 1e-5);
+        assert_approx_eq!(data.humidity.unwrap(), 53.49, 1e-5);
+        assert_eq!(data.pressure, Some(100044));
         assert_approx_eq!(data.acceleration_x, 0.004, 1e-9);
         assert_approx_eq!(data.acceleration_y, -0.004, 1e-9);
         assert_approx_eq!(data.acceleration_z, 1.036, 1e-9);
@@ -250,10 +1081,10 @@ mod tests {
 
         let data = ruuvi_decode_v5(&s[..]);
 
-        assert_eq!(data.format, 5);
-        assert_approx_eq!(data.temperature, 163.835, 1e-4);
-        assert_eq!(data.pressure, 115534);
-        assert_approx_eq!(data.humidity, 163.8350, 1e-4);
+        assert_eq!(data.format(), 5);
+        assert_approx_eq!(data.temperature.unwrap(), 163.835, 1e-4);
+        assert_eq!(data.pressure, Some(115534));
+        assert_approx_eq!(data.humidity.unwrap(), 163.835, 1e-4);
         assert_approx_eq!(data.acceleration_x, 32.767, 1e-4);
         assert_approx_eq!(data.acceleration_y, 32.767, 1e-4);
         assert_approx_eq!(data.acceleration_z, 32.767, 1e-4);
@@ -279,10 +1110,10 @@ mod tests {
 
         let data = ruuvi_decode_v5(&s[..]);
 
-        assert_eq!(data.format, 5);
-        assert_approx_eq!(data.temperature, -163.835, 1e-4);
-        assert_eq!(data.pressure, 50000);
-        assert_approx_eq!(data.humidity, 0.0, 1e-4);
+        assert_eq!(data.format(), 5);
+        assert_approx_eq!(data.temperature.unwrap(), -163.835, 1e-4);
+        assert_eq!(data.pressure, Some(50000));
+        assert_approx_eq!(data.humidity.unwrap(), 0.0, 1e-4);
         assert_approx_eq!(data.acceleration_x, -32.767, 1e-4);
         assert_approx_eq!(data.acceleration_y, -32.767, 1e-4);
         assert_approx_eq!(data.acceleration_z, -32.767, 1e-4);
@@ -308,7 +1139,7 @@ mod tests {
 
         let data = ruuvi_decode_v3(&s[..]);
 
-        assert_eq!(data.format, 3);
+        assert_eq!(data.format(), 3);
         assert_approx_eq!(data.humidity, 20.5, 1e-4);
         assert_approx_eq!(data.temperature, 26.3, 1e-4);
         assert_eq!(data.pressure, 102766);
@@ -327,7 +1158,7 @@ mod tests {
 
         let data = ruuvi_decode_v3(&s[..]);
 
-        assert_eq!(data.format, 3);
+        assert_eq!(data.format(), 3);
         assert_approx_eq!(data.humidity, 0.0, 1e-4);
         assert_approx_eq!(data.temperature, -127.99, 1e-4);
         assert_eq!(data.pressure, 50000);
@@ -345,7 +1176,7 @@ mod tests {
 
         let data = ruuvi_decode_v3(&s[..]);
 
-        assert_eq!(data.format, 3);
+        assert_eq!(data.format(), 3);
         assert_approx_eq!(data.humidity, 127.5, 1e-4);
         assert_approx_eq!(data.temperature, 127.99, 1e-4);
         assert_eq!(data.pressure, 115535);
@@ -355,4 +1186,196 @@ mod tests {
         assert_eq!(data.voltage, 65.535);
     }
 
+    #[test]
+    fn test_ruuvi_decode_c5() {
+        let s = decode_hex("C512FC5394C37CAC364200CDCBB8334C884F").unwrap();
+
+        let data = ruuvi_decode_c5(&s[..]);
+
+        assert_eq!(data.format(), 0xC5);
+        assert_approx_eq!(data.temperature.unwrap(), 24.3, 1e-5);
+        assert_approx_eq!(data.humidity.unwrap(), 53.49, 1e-5);
+        assert_eq!(data.pressure, Some(100044));
+        assert_eq!(data.tx_power, 4);
+        assert_eq!(data.voltage, 2.977);
+        assert_eq!(data.movement, 66);
+        assert_eq!(data.measurement_sequence, 205);
+        assert_eq!(data.mac, [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F]);
+        assert_eq!(data.acceleration(), None);
+    }
+
+    #[test]
+    fn test_ruuvi_decode_v6() {
+        let s = decode_hex("06170C5668C79E007000C90501D9FFCD004C884F").unwrap();
+        /* Taken from https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-6
+        */
+
+        let data = ruuvi_decode_v6(&s[..]);
+
+        assert_eq!(data.format(), 6);
+        assert_approx_eq!(data.temperature.unwrap(), 29.5, 1e-4);
+        assert_approx_eq!(data.humidity.unwrap(), 55.3, 1e-4);
+        assert_eq!(data.pressure, Some(101102));
+        assert_approx_eq!(data.pm2_5.unwrap(), 11.2, 1e-4);
+        assert_eq!(data.co2, Some(201));
+        assert_eq!(data.voc_index, Some(10));
+        assert_eq!(data.nox_index, Some(2));
+        assert_approx_eq!(data.luminosity.unwrap(), 13026.67, 0.5);
+        assert_eq!(data.measurement_sequence, 205);
+        assert!(!data.calibration_in_progress);
+        assert_eq!(data.mac_suffix, [0x4C, 0x88, 0x4F]);
+        assert_eq!(data.voltage(), None);
+        assert_eq!(data.mac(), None);
+
+        // The lowest bits of the VOC and NOx indices are in the flags
+        let mut s = s;
+        s[16] = 0xC1;
+        let data = ruuvi_decode_v6(&s[..]);
+        assert_eq!(data.voc_index, Some(11));
+        assert_eq!(data.nox_index, Some(3));
+        assert!(data.calibration_in_progress);
+    }
+
+    #[test]
+    fn test_ruuvi_decode_v6_invalid_values() {
+        let s = decode_hex("068000FFFFFFFFFFFFFFFFFFFFFFFFFFC0FFFFFF").unwrap();
+
+        let data = ruuvi_decode_v6(&s[..]);
+
+        assert_eq!(data.temperature, None);
+        assert_eq!(data.humidity, None);
+        assert_eq!(data.pressure, None);
+        assert_eq!(data.pm2_5, None);
+        assert_eq!(data.co2, None);
+        assert_eq!(data.voc_index, None);
+        assert_eq!(data.nox_index, None);
+        assert_eq!(data.luminosity, None);
+
+        let fields : Vec<&str> = RuuviData::V6(data).fields().iter().map(|(name, _)| *name).collect();
+        assert_eq!(vec!["format", "measurement_sequence", "calibration_in_progress"], fields);
+    }
+
+    #[test]
+    fn test_ruuvi_decode_e1() {
+        let s = decode_hex("E1170C5668C79E006500700049004400C9050113E0ACFFFFFFDECDEE00FFFFFFFFFFCBB8334C884F").unwrap();
+
+        let data = ruuvi_decode_e1(&s[..]);
+
+        assert_eq!(data.format(), 0xE1);
+        assert_approx_eq!(data.temperature.unwrap(), 29.5, 1e-4);
+        assert_approx_eq!(data.humidity.unwrap(), 55.3, 1e-4);
+        assert_eq!(data.pressure, Some(101102));
+        assert_approx_eq!(data.pm1_0.unwrap(), 10.1, 1e-4);
+        assert_approx_eq!(data.pm2_5.unwrap(), 11.2, 1e-4);
+        assert_approx_eq!(data.pm4_0.unwrap(), 7.3, 1e-4);
+        assert_approx_eq!(data.pm10_0.unwrap(), 6.8, 1e-4);
+        assert_eq!(data.co2, Some(201));
+        assert_eq!(data.voc_index, Some(10));
+        assert_eq!(data.nox_index, Some(2));
+        assert_approx_eq!(data.luminosity.unwrap(), 13027.0, 1e-2);
+        assert_eq!(data.measurement_sequence, Some(14601710));
+        assert_eq!(data.mac, [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F]);
+    }
+
+    #[test]
+    fn test_decode_data_formats() {
+        let decode = |payload : &str| try_decode_ruuvi(&decode_hex(&format!("0201061BFF9904{}", payload)).unwrap());
+
+        assert!(matches!(decode("C512FC5394C37CAC364200CDCBB8334C884F"), Ok(RuuviData::C5(_))));
+        assert!(matches!(decode("06170C5668C79E007000C90501D9FFCD004C884F"), Ok(RuuviData::V6(_))));
+        assert!(matches!(decode("E1170C5668C79E006500700049004400C9050113E0ACFFFFFFDECDEE00FFFFFFFFFFCBB8334C884F"), Ok(RuuviData::E1(_))));
+        assert_eq!(Err(DecodeError::PayloadTooShort { length: 20, expected: 40 }), decode("E1170C5668C79E007000C90501D9FFCD004C884F"));
+    }
+
+    // Stands in for AES, the key is XORed into the block
+    struct XorKeys {
+        mac: [u8; 6],
+        key: u8,
+    }
+
+    impl DecryptionKeys for XorKeys {
+        fn decrypt(&self, mac : &[u8; 6], block : &mut [u8; 16]) -> bool {
+            if *mac != self.mac {
+                return false;
+            }
+            block.iter_mut().for_each(|b| *b ^= self.key);
+            true
+        }
+    }
+
+    #[test]
+    fn test_decode_format_8() {
+        let mac = [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F];
+        let keys = XorKeys { mac, key: 0x5A };
+        // The C5 test vector as plaintext with reserved bytes
+        let plain = decode_hex("12FC5394C37CAC364200CD0000000000").unwrap();
+        let encrypted = plain.iter().map(|b| format!("{:02X}", b ^ 0x5A)).collect::<String>();
+        let advertisement = |crc : u8| decode_hex(&format!("0201061BFF990408{}{:02X}CBB8334C884F", encrypted, crc)).unwrap();
+        let crc = crc8(&plain);
+
+        let data = match try_decode_ruuvi_with_keys(&advertisement(crc), Some(&keys)) {
+            Ok(RuuviData::V8(data)) => data,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(data.format(), 0x08);
+        assert_approx_eq!(data.temperature.unwrap(), 24.3, 1e-5);
+        assert_approx_eq!(data.humidity.unwrap(), 53.49, 1e-5);
+        assert_eq!(data.pressure, Some(100044));
+        assert_eq!(data.tx_power, 4);
+        assert_eq!(data.voltage, 2.977);
+        assert_eq!(data.movement, 66);
+        assert_eq!(data.measurement_sequence, 205);
+        assert_eq!(data.mac, mac);
+
+        assert_eq!(Err(DecodeError::CrcMismatch { expected: crc ^ 1, actual: crc }),
+            try_decode_ruuvi_with_keys(&advertisement(crc ^ 1), Some(&keys)));
+        assert_eq!(Err(DecodeError::MissingKey), try_decode_ruuvi(&advertisement(crc)));
+        let other_tag = XorKeys { mac: [0; 6], key: 0x5A };
+        assert_eq!(Err(DecodeError::MissingKey), try_decode_ruuvi_with_keys(&advertisement(crc), Some(&other_tag)));
+    }
+
+    #[test]
+    fn test_invalid_environment_values() {
+        // 0x8000 temperature, 0xFFFF humidity and pressure mean not available
+        let v5 = ruuvi_decode_v5(&decode_hex("058000FFFFFFFF0004FFFC040CAC364200CDCBB8334C884F").unwrap());
+        assert_eq!((None, None, None), (v5.temperature, v5.humidity, v5.pressure));
+        assert_eq!(Some(2.977), v5.voltage());
+        assert!(!RuuviData::V5(v5).has_field("temperature"));
+
+        let c5 = ruuvi_decode_c5(&decode_hex("C58000FFFFFFFFAC364200CDCBB8334C884F").unwrap());
+        assert_eq!((None, None, None), (c5.temperature, c5.humidity, c5.pressure));
+        assert_eq!(205, c5.measurement_sequence);
+
+        let mac = [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F];
+        let plain = decode_hex("8000FFFFFFFFAC364200CD0000000000").unwrap();
+        let encrypted = plain.iter().map(|b| format!("{:02X}", b ^ 0x5A)).collect::<String>();
+        let advertisement = decode_hex(&format!("0201061BFF990408{}{:02X}CBB8334C884F", encrypted, crc8(&plain))).unwrap();
+        match try_decode_ruuvi_with_keys(&advertisement, Some(&XorKeys { mac, key: 0x5A })) {
+            Ok(RuuviData::V8(data)) => assert_eq!((None, None, None), (data.temperature, data.humidity, data.pressure)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_crc8() {
+        // CRC-8 check value
+        assert_eq!(0xF4, crc8(b"123456789"));
+    }
+
+    #[test]
+    fn test_serialize_measurement() {
+        let s = decode_hex("03291A1ECE1EFC18F94202CA0B53").unwrap();
+        let json = serde_json::to_value(RuuviData::V3(ruuvi_decode_v3(&s[..]))).unwrap();
+
+        assert_eq!(3, json["format"]);
+        assert_eq!(102766, json["pressure"]);
+        assert!(json.get("tx_power").is_none());
+        assert!(json.get("mac").is_none());
+
+        let s = decode_hex("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F").unwrap();
+        let json = serde_json::to_value(RuuviData::V5(ruuvi_decode_v5(&s[..]))).unwrap();
+        assert_eq!(205, json["measurement_sequence"]);
+        assert_eq!("CB:B8:33:4C:88:4F", json["mac"]);
+    }
+
 }
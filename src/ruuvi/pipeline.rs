@@ -0,0 +1,403 @@
+use std::time::Instant;
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use prometheus::{CounterVec, HistogramOpts, HistogramVec, IntGaugeVec, Opts, Registry};
+use tracing::{error, warn};
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+use crate::ruuvi::parser::{RuuviData, RuuviSink};
+use crate::ruuvi::sink::{AsyncRuuviSink, BlockingSink};
+
+// Upper limit for the measurements handed to a sink at once
+const MAX_BATCH_SIZE : usize = 100;
+
+#[derive(Clone)]
+pub struct PipelineMetrics {
+    pub queue_length: IntGaugeVec,
+    pub dropped: CounterVec,
+    pub errors: CounterVec,
+    pub duration: HistogramVec,
+}
+
+impl PipelineMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            queue_length: register(registry, IntGaugeVec::new(Opts::new(
+                "ruuvi_sink_queue_length",
+                "Number of measurements waiting in the sink queue."),
+                &["sink"])?)?,
+            dropped: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_sink_dropped_count",
+                "Number of measurements dropped because the sink queue was full."),
+                &["sink"])?)?,
+            errors: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_sink_error_count",
+                "Number of measurements the sink failed to process."),
+                &["sink"])?)?,
+            duration: register(registry, HistogramVec::new(HistogramOpts::new(
+                "ruuvi_sink_duration_seconds",
+                "Time spent by the sink processing a single measurement."),
+                &["sink"])?)?,
+        })
+    }
+}
+
+// What to do when a sink can't keep up and its queue is full
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    // Drop the new measurement for this sink only
+    #[default]
+    Drop,
+    // Wait until the sink has room. This stalls MQTT polling for all sinks.
+    // Only SinkPipeline::send can wait on any runtime, the synchronous
+    // RuuviSink impl needs a multi-threaded one and drops otherwise.
+    Block,
+}
+
+struct SinkWorker {
+    name: String,
+    policy: OverflowPolicy,
+    sender: mpsc::Sender<(RuuviGatewayMessage, RuuviData)>,
+    handle: JoinHandle<()>,
+}
+
+// Fans every measurement out to a set of sinks. Each sink runs in its own
+// task behind a bounded queue, so a slow sink only affects itself.
+pub struct SinkPipeline {
+    workers: Vec<SinkWorker>,
+    metrics: PipelineMetrics,
+    // Whether the synchronous impl already warned that it can't block
+    warned_cannot_block: bool,
+}
+
+impl SinkPipeline {
+    pub fn new(metrics : &Metrics) -> Self {
+        Self {
+            workers: Vec::new(),
+            metrics: metrics.pipeline().clone(),
+            warned_cannot_block: false,
+        }
+    }
+
+    // Adds a synchronous sink, which runs on Tokio's blocking threads.
+    // Must be called from within a Tokio runtime.
+    pub fn add_sink(&mut self, name: &str, sink: Box<dyn RuuviSink + Send>, queue_size: usize, policy: OverflowPolicy) {
+        self.add_async_sink(name, Box::new(BlockingSink::new(sink)), queue_size, policy);
+    }
+
+    // Must be called from within a Tokio runtime.
+    pub fn add_async_sink(&mut self, name: &str, mut sink: Box<dyn AsyncRuuviSink>, queue_size: usize, policy: OverflowPolicy) {
+        let (sender, mut receiver) = mpsc::channel::<(RuuviGatewayMessage, RuuviData)>(queue_size.max(1));
+        let worker_name = name.to_string();
+        let metrics = self.metrics.clone();
+
+        let handle = tokio::spawn(async move {
+            while let Some(item) = receiver.recv().await {
+                // Everything already waiting goes to the sink as one batch
+                let mut batch = vec![item];
+                while batch.len() < MAX_BATCH_SIZE {
+                    match receiver.try_recv() {
+                        Ok(item) => batch.push(item),
+                        Err(_) => break,
+                    }
+                }
+                let count = batch.len();
+                metrics.queue_length.with_label_values(&[&worker_name]).sub(count as i64);
+
+                let start = Instant::now();
+                let result = sink.sink_batch(batch).await;
+                let duration = metrics.duration.with_label_values(&[&worker_name]);
+                let per_measurement = start.elapsed().as_secs_f64() / count as f64;
+                for _ in 0..count {
+                    duration.observe(per_measurement);
+                }
+
+                if let Err(e) = result {
+                    warn!(sink = %worker_name, error = %e, measurements = count, "sink failed");
+                    metrics.errors.with_label_values(&[&worker_name]).inc_by(count as f64);
+                }
+            }
+
+            if let Err(e) = sink.flush().await {
+                error!(sink = %worker_name, error = %e, "couldn't flush sink");
+            }
+        });
+
+        self.workers.push(SinkWorker {
+            name: name.to_string(),
+            policy,
+            sender,
+            handle,
+        });
+    }
+
+    // Hands the measurement to every sink, waiting for room in the queues of
+    // sinks with the Block policy
+    pub async fn send(&self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        for worker in &self.workers {
+            let item = (message.clone(), measurement.clone());
+            let queue_length = self.metrics.queue_length.with_label_values(&[&worker.name]);
+            queue_length.inc();
+
+            let sent = match worker.policy {
+                OverflowPolicy::Drop => worker.sender.try_send(item).is_ok(),
+                OverflowPolicy::Block => worker.sender.send(item).await.is_ok(),
+            };
+
+            if !sent {
+                queue_length.dec();
+                self.metrics.dropped.with_label_values(&[&worker.name]).inc();
+            }
+        }
+    }
+
+    // Stops accepting new measurements and waits until every sink has
+    // processed its queue and flushed.
+    pub async fn close(self) {
+        for worker in self.workers {
+            drop(worker.sender);
+            if let Err(e) = worker.handle.await {
+                error!(sink = %worker.name, error = %e, "sink stopped with error");
+            }
+        }
+    }
+}
+
+impl RuuviSink for SinkPipeline {
+    fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
+        let message = RuuviGatewayMessage {
+            mac: source_mac.to_string(),
+            ..Default::default()
+        };
+        self.sink_message(&message, measurement);
+    }
+
+    fn sink_message(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        // block_in_place panics on a current thread runtime, and waiting there
+        // would never let the sink task run
+        let can_block = tokio::runtime::Handle::try_current()
+            .is_ok_and(|handle| handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread);
+
+        for worker in &self.workers {
+            let item = (message.clone(), measurement.clone());
+            let queue_length = self.metrics.queue_length.with_label_values(&[&worker.name]);
+            queue_length.inc();
+
+            let sent = match worker.policy {
+                OverflowPolicy::Drop => worker.sender.try_send(item).is_ok(),
+                OverflowPolicy::Block if can_block => {
+                    tokio::task::block_in_place(|| worker.sender.blocking_send(item).is_ok())
+                }
+                OverflowPolicy::Block => {
+                    if !self.warned_cannot_block {
+                        warn!(sink = %worker.name, "can't wait for the sink outside a multi-threaded runtime, dropping when full");
+                        self.warned_cannot_block = true;
+                    }
+                    worker.sender.try_send(item).is_ok()
+                }
+            };
+
+            if !sent {
+                queue_length.dec();
+                self.metrics.dropped.with_label_values(&[&worker.name]).inc();
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::DataFormat5;
+    use crate::ruuvi::sink::SinkError;
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    struct RecordingSink {
+        received: Arc<Mutex<Vec<String>>>,
+        delay: Duration,
+    }
+
+    impl RuuviSink for RecordingSink {
+        fn sink(&mut self, source_mac : &str, _measurement : RuuviData) {
+            std::thread::sleep(self.delay);
+            self.received.lock().unwrap().push(source_mac.to_string());
+        }
+    }
+
+    struct PanickingSink {}
+
+    impl RuuviSink for PanickingSink {
+        fn sink(&mut self, _source_mac : &str, _measurement : RuuviData) {
+            panic!("sink failure");
+        }
+    }
+
+    // Records the size of every batch and whether it was flushed
+    struct BatchingSink {
+        batches: Arc<Mutex<Vec<usize>>>,
+        flushed: Arc<Mutex<bool>>,
+    }
+
+    #[async_trait]
+    impl AsyncRuuviSink for BatchingSink {
+        async fn sink(&mut self, _message : &RuuviGatewayMessage, _measurement : RuuviData) -> Result<(), SinkError> {
+            Err("batches only".into())
+        }
+
+        async fn sink_batch(&mut self, batch : Vec<(RuuviGatewayMessage, RuuviData)>) -> Result<(), SinkError> {
+            self.batches.lock().unwrap().push(batch.len());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(())
+        }
+
+        async fn flush(&mut self) -> Result<(), SinkError> {
+            *self.flushed.lock().unwrap() = true;
+            Ok(())
+        }
+    }
+
+    fn recording_sink(delay: Duration) -> (Box<RecordingSink>, Arc<Mutex<Vec<String>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        (Box::new(RecordingSink { received: received.clone(), delay }), received)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fan_out_to_all_sinks() {
+        let (first, first_received) = recording_sink(Duration::ZERO);
+        let (second, second_received) = recording_sink(Duration::ZERO);
+
+        let metrics = Metrics::default();
+        let mut pipeline = SinkPipeline::new(&metrics);
+        pipeline.add_sink("test_fan_out_1", first, 10, OverflowPolicy::Block);
+        pipeline.add_sink("test_fan_out_2", second, 10, OverflowPolicy::Block);
+
+        pipeline.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5::default()));
+        pipeline.sink("AA:BB:CC:DD:EE:FF", RuuviData::V5(DataFormat5::default()));
+        pipeline.close().await;
+
+        let expected = vec!["11:22:33:44:55:66".to_string(), "AA:BB:CC:DD:EE:FF".to_string()];
+        assert_eq!(expected, *first_received.lock().unwrap());
+        assert_eq!(expected, *second_received.lock().unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_slow_sink_drops_when_full() {
+        let (slow, slow_received) = recording_sink(Duration::from_millis(100));
+        let (fast, fast_received) = recording_sink(Duration::ZERO);
+
+        let metrics = Metrics::default();
+        let mut pipeline = SinkPipeline::new(&metrics);
+        pipeline.add_sink("test_drop_slow", slow, 1, OverflowPolicy::Drop);
+        pipeline.add_sink("test_drop_fast", fast, 10, OverflowPolicy::Drop);
+
+        for _ in 0..5 {
+            pipeline.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5::default()));
+        }
+        pipeline.close().await;
+
+        assert_eq!(5, fast_received.lock().unwrap().len());
+        assert!(slow_received.lock().unwrap().len() < 5);
+        assert!(metrics.pipeline().dropped.with_label_values(&["test_drop_slow"]).get() >= 1.0);
+        assert_eq!(0.0, metrics.pipeline().dropped.with_label_values(&["test_drop_fast"]).get());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_sink_gets_batches_and_is_flushed() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let flushed = Arc::new(Mutex::new(false));
+        let sink = BatchingSink { batches: batches.clone(), flushed: flushed.clone() };
+
+        let metrics = Metrics::default();
+        let mut pipeline = SinkPipeline::new(&metrics);
+        pipeline.add_async_sink("test_batches", Box::new(sink), 10, OverflowPolicy::Block);
+
+        pipeline.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5::default()));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        // Queued while the sink is busy with the first one
+        for _ in 0..4 {
+            pipeline.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5::default()));
+        }
+        pipeline.close().await;
+
+        assert_eq!(vec![1, 4], *batches.lock().unwrap());
+        assert!(*flushed.lock().unwrap());
+        assert_eq!(0, metrics.pipeline().queue_length.with_label_values(&["test_batches"]).get());
+    }
+
+    #[tokio::test]
+    async fn test_send_blocks_until_sink_has_room() {
+        let (slow, slow_received) = recording_sink(Duration::from_millis(20));
+
+        let metrics = Metrics::default();
+        let mut pipeline = SinkPipeline::new(&metrics);
+        pipeline.add_sink("test_block_send", slow, 1, OverflowPolicy::Block);
+
+        let message = RuuviGatewayMessage { mac: "11:22:33:44:55:66".to_string(), ..Default::default() };
+        for _ in 0..5 {
+            pipeline.send(&message, RuuviData::V5(DataFormat5::default())).await;
+        }
+        pipeline.close().await;
+
+        assert_eq!(5, slow_received.lock().unwrap().len());
+        assert_eq!(0.0, metrics.pipeline().dropped.with_label_values(&["test_block_send"]).get());
+    }
+
+    #[tokio::test]
+    async fn test_sync_block_without_multi_thread_runtime_drops() {
+        let (slow, slow_received) = recording_sink(Duration::from_millis(50));
+
+        let metrics = Metrics::default();
+        let mut pipeline = SinkPipeline::new(&metrics);
+        pipeline.add_sink("test_block_current_thread", slow, 1, OverflowPolicy::Block);
+
+        for _ in 0..5 {
+            pipeline.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5::default()));
+        }
+        pipeline.close().await;
+
+        let dropped = metrics.pipeline().dropped.with_label_values(&["test_block_current_thread"]).get();
+        assert!(dropped >= 1.0);
+        assert_eq!(5, slow_received.lock().unwrap().len() + dropped as usize);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_block_policy_waits_on_multi_thread_runtime() {
+        let (slow, slow_received) = recording_sink(Duration::from_millis(20));
+
+        let metrics = Metrics::default();
+        let mut pipeline = SinkPipeline::new(&metrics);
+        pipeline.add_sink("test_block_multi_thread", slow, 1, OverflowPolicy::Block);
+
+        for _ in 0..5 {
+            pipeline.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5::default()));
+        }
+        pipeline.close().await;
+
+        assert_eq!(5, slow_received.lock().unwrap().len());
+        assert_eq!(0.0, metrics.pipeline().dropped.with_label_values(&["test_block_multi_thread"]).get());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sink_errors_are_counted() {
+        let (after, after_received) = recording_sink(Duration::ZERO);
+
+        let metrics = Metrics::default();
+        let mut pipeline = SinkPipeline::new(&metrics);
+        pipeline.add_sink("test_errors", Box::new(PanickingSink {}), 10, OverflowPolicy::Block);
+        pipeline.add_sink("test_errors_other", after, 10, OverflowPolicy::Block);
+
+        pipeline.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5::default()));
+        pipeline.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5::default()));
+        pipeline.close().await;
+
+        assert_eq!(2.0, metrics.pipeline().errors.with_label_values(&["test_errors"]).get());
+        assert_eq!(2, after_received.lock().unwrap().len());
+    }
+}
@@ -1,46 +1,105 @@
-use prometheus::{GaugeVec, CounterVec};
-use prometheus::{register_counter_vec, register_gauge_vec};
-use lazy_static::lazy_static;
-
-use crate::ruuvi::parser::{RuuviData, RuuviSink};
-
-lazy_static! {
-    static ref IOT_TEMPERATURE: GaugeVec = register_gauge_vec!(
-        "iot_temperature",
-        "Temperature in Celcius.",
-        &["mac"]
-    ).unwrap();
-
-    static ref RUUVI_MEASUREMENTS: CounterVec = register_counter_vec!(
-        "ruuvi_measurement_count",
-        "Number of received ruuvi measurements.",
-        &["mac"]
-    ).unwrap();
-}
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub struct RuuviPrometheusSink {
+use prometheus::{GaugeVec, CounterVec, Opts, Registry};
+use tracing::{trace, warn};
 
+use crate::ruuvi::battery::{BatteryMetrics, BatteryStatus, BatteryTrends};
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+use crate::ruuvi::parser::{RuuviData, RuuviMeasurement, RuuviSink};
+
+#[derive(Clone)]
+pub struct MeasurementMetrics {
+    pub temperature: GaugeVec,
+    pub measurements: CounterVec,
 }
 
-impl RuuviPrometheusSink {
-    pub fn new() -> Self {
-        Self::default()
+impl MeasurementMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            temperature: register(registry, GaugeVec::new(Opts::new(
+                "iot_temperature",
+                "Temperature in Celcius."),
+                &["mac"])?)?,
+            measurements: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_measurement_count",
+                "Number of received ruuvi measurements."),
+                &["mac"])?)?,
+        })
     }
 }
 
-impl std::default::Default for RuuviPrometheusSink {
-    fn default() -> Self {
+pub struct RuuviPrometheusSink {
+    metrics: MeasurementMetrics,
+    battery_metrics: BatteryMetrics,
+    trends: BatteryTrends,
+    // Low battery flag per tag
+    low: HashMap<String, bool>,
+}
+
+impl RuuviPrometheusSink {
+    pub fn new(metrics : &Metrics) -> Self {
         Self {
+            metrics: metrics.measurements().clone(),
+            battery_metrics: metrics.battery().clone(),
+            trends: BatteryTrends::default(),
+            low: HashMap::new(),
+        }
+    }
+
+    // Keeps the voltage trends in trends, such as those of the tag store
+    pub fn with_battery_trends(mut self, trends : BatteryTrends) -> Self {
+        self.trends = trends;
+        self
+    }
+
+    fn update_battery(&mut self, source_mac : &str, time : u64, measurement : &RuuviData) {
+        let Some(battery) = BatteryStatus::of(measurement) else { return };
+        let mut trends = self.trends.lock().unwrap();
+        let trend = trends.entry(source_mac.to_string()).or_default();
+        trend.add(time, battery.voltage, measurement.temperature());
+        let was_low = self.low.entry(source_mac.to_string()).or_default();
+        if battery.low && !*was_low {
+            warn!(tag = source_mac, voltage = battery.voltage, temperature = measurement.temperature(), "battery low");
+        }
+        *was_low = battery.low;
 
+        let metrics = &self.battery_metrics;
+        metrics.voltage.with_label_values(&[source_mac]).set(battery.voltage as f64);
+        metrics.percent.with_label_values(&[source_mac]).set(battery.percent as f64);
+        metrics.low.with_label_values(&[source_mac]).set(battery.low as i64);
+        match trend.predicted_low_time() {
+            Some(predicted) => metrics.replacement.with_label_values(&[source_mac]).set(predicted as f64),
+            None => {
+                let _ = metrics.replacement.remove_label_values(&[source_mac]);
+            }
         }
     }
 }
 
+fn unix_secs(time : SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
 impl RuuviSink for RuuviPrometheusSink {
     fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
-        println!("source_mac: {:?}, measurement: {:?}", source_mac, measurement);
-        RUUVI_MEASUREMENTS.with_label_values(&[&source_mac]).inc();
-        IOT_TEMPERATURE.with_label_values(&[&source_mac]).set(measurement.temperature as f64);
+        let message = RuuviGatewayMessage {
+            mac: source_mac.to_string(),
+            ..Default::default()
+        };
+        self.sink_message(&message, measurement);
+    }
+
+    fn sink_message(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        let source_mac = message.mac.as_str();
+        trace!(tag = source_mac, ?measurement, "measurement");
+        self.metrics.measurements.with_label_values(&[source_mac]).inc();
+        if let Some(temperature) = measurement.temperature() {
+            self.metrics.temperature.with_label_values(&[source_mac]).set(temperature as f64);
+        }
+        let time = unix_secs(message.received_at.unwrap_or_else(SystemTime::now));
+        self.update_battery(source_mac, time, &measurement);
     }
 }
 
@@ -48,16 +107,52 @@ impl RuuviSink for RuuviPrometheusSink {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ruuvi::parser::DataFormat5;
 
 
     #[test]
     fn test_prometheus_sink() {
-        let mut measurement = RuuviData::new();
-        measurement.temperature = 21.0;
+        let measurement = RuuviData::V5(DataFormat5 {
+            temperature: Some(21.0),
+            ..Default::default()
+        });
 
-        let mut sink = RuuviPrometheusSink::new();
+        let metrics = Metrics::default();
+        let mut sink = RuuviPrometheusSink::new(&metrics);
         sink.sink("11:22:33:44:55:66", measurement);
 
-        assert_eq!(21.0, IOT_TEMPERATURE.with_label_values(&["11:22:33:44:55:66"]).get());
+        assert_eq!(21.0, metrics.measurements().temperature.with_label_values(&["11:22:33:44:55:66"]).get());
+    }
+
+    #[test]
+    fn test_battery_metrics() {
+        let metrics = Metrics::default();
+        let mut sink = RuuviPrometheusSink::new(&metrics);
+        sink.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5 { temperature: Some(20.0), voltage: 2.9, ..Default::default() }));
+        // Cold, but not low once compensated
+        sink.sink("AA:BB:CC:DD:EE:FF", RuuviData::V5(DataFormat5 { temperature: Some(-20.0), voltage: 2.2, ..Default::default() }));
+
+        let battery = metrics.battery();
+        assert_eq!(2.9f32 as f64, battery.voltage.with_label_values(&["11:22:33:44:55:66"]).get());
+        assert_eq!(85.0, battery.percent.with_label_values(&["11:22:33:44:55:66"]).get().round());
+        assert_eq!(0, battery.low.with_label_values(&["AA:BB:CC:DD:EE:FF"]).get());
+
+        sink.sink("AA:BB:CC:DD:EE:FF", RuuviData::V5(DataFormat5 { temperature: Some(20.0), voltage: 2.2, ..Default::default() }));
+        assert_eq!(1, battery.low.with_label_values(&["AA:BB:CC:DD:EE:FF"]).get());
+        assert_eq!(0.0, battery.percent.with_label_values(&["AA:BB:CC:DD:EE:FF"]).get());
+        // Not enough history for a prediction
+        let families = metrics.registry().gather();
+        assert!(families.iter().any(|family| family.get_name() == "ruuvi_battery_low"));
+        assert!(!families.iter().any(|family| family.get_name() == "ruuvi_battery_replacement_timestamp_seconds"));
+    }
+
+    #[test]
+    fn test_battery_trends_are_shared() {
+        let metrics = Metrics::default();
+        let trends = BatteryTrends::default();
+        let mut sink = RuuviPrometheusSink::new(&metrics).with_battery_trends(trends.clone());
+        sink.sink("11:22:33:44:55:66", RuuviData::V5(DataFormat5 { temperature: Some(20.0), voltage: 2.9, ..Default::default() }));
+
+        assert!(trends.lock().unwrap().contains_key("11:22:33:44:55:66"));
     }
 }
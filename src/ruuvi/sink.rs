@@ -0,0 +1,139 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use async_trait::async_trait;
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::parser::{RuuviData, RuuviSink};
+
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+// A sink which may do I/O. The sink pipeline calls it from a Tokio task, so
+// it must not block; synchronous sinks are wrapped in BlockingSink.
+#[async_trait]
+pub trait AsyncRuuviSink : Send {
+    async fn sink(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) -> Result<(), SinkError>;
+
+    // Called with everything waiting in the sink's queue. Sinks which can
+    // write several measurements at once override this.
+    async fn sink_batch(&mut self, batch : Vec<(RuuviGatewayMessage, RuuviData)>) -> Result<(), SinkError> {
+        for (message, measurement) in batch {
+            self.sink(&message, measurement).await?;
+        }
+        Ok(())
+    }
+
+    // Called once on shutdown after the last measurement
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+// Runs a synchronous RuuviSink on Tokio's blocking threads. A panic in the
+// sink is returned as an error and the sink is kept for the next batch.
+pub struct BlockingSink {
+    sink: Option<Box<dyn RuuviSink + Send>>,
+}
+
+impl BlockingSink {
+    pub fn new(sink : Box<dyn RuuviSink + Send>) -> Self {
+        Self {
+            sink: Some(sink),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncRuuviSink for BlockingSink {
+    async fn sink(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) -> Result<(), SinkError> {
+        self.sink_batch(vec![(message.clone(), measurement)]).await
+    }
+
+    async fn sink_batch(&mut self, batch : Vec<(RuuviGatewayMessage, RuuviData)>) -> Result<(), SinkError> {
+        let mut sink = self.sink.take().ok_or("sink was lost")?;
+
+        let (sink, panics) = tokio::task::spawn_blocking(move || {
+            let mut panics = 0;
+            for (message, measurement) in batch {
+                if catch_unwind(AssertUnwindSafe(|| sink.sink_message(&message, measurement))).is_err() {
+                    panics += 1;
+                }
+            }
+            (sink, panics)
+        }).await?;
+        self.sink = Some(sink);
+
+        match panics {
+            0 => Ok(()),
+            panics => Err(format!("sink panicked on {} measurements", panics).into()),
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        let mut sink = self.sink.take().ok_or("sink was lost")?;
+
+        let (sink, panicked) = tokio::task::spawn_blocking(move || {
+            let panicked = catch_unwind(AssertUnwindSafe(|| sink.flush())).is_err();
+            (sink, panicked)
+        }).await?;
+        self.sink = Some(sink);
+
+        if panicked {
+            return Err("sink panicked while flushing".into());
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::DataFormat5;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountingSink {
+        count: Arc<AtomicUsize>,
+        flushes: Arc<AtomicUsize>,
+    }
+
+    impl RuuviSink for CountingSink {
+        fn sink(&mut self, source_mac : &str, _measurement : RuuviData) {
+            if source_mac == "panic" {
+                panic!("sink failure");
+            }
+            self.count.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn flush(&mut self) {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn item(mac : &str) -> (RuuviGatewayMessage, RuuviData) {
+        let message = RuuviGatewayMessage {
+            mac: mac.to_string(),
+            ..Default::default()
+        };
+        (message, RuuviData::V5(DataFormat5::default()))
+    }
+
+    #[tokio::test]
+    async fn test_blocking_sink_adapter() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let mut sink = BlockingSink::new(Box::new(CountingSink { count: count.clone(), flushes: flushes.clone() }));
+
+        sink.sink_batch(vec![item("AA"), item("BB")]).await.unwrap();
+        let error = sink.sink_batch(vec![item("panic"), item("CC")]).await.unwrap_err();
+        assert_eq!("sink panicked on 1 measurements", error.to_string());
+
+        // The sink survives the panic
+        let (message, measurement) = item("DD");
+        sink.sink(&message, measurement).await.unwrap();
+        sink.flush().await.unwrap();
+        assert_eq!(4, count.load(Ordering::SeqCst));
+        // The flush reaches the wrapped sink
+        assert_eq!(1, flushes.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::{header::CONTENT_TYPE, Body, Request, Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use prometheus::{CounterVec, Opts, Registry};
+use tracing::error;
+
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+use crate::ruuvi::parser::{RuuviData, RuuviMeasurement, RuuviSink};
+use crate::ruuvi::url::percent_decode;
+
+#[derive(Clone)]
+pub struct SqliteMetrics {
+    pub errors: CounterVec,
+}
+
+impl SqliteMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            errors: register(registry, CounterVec::new(Opts::new(
+                "ruuvi_sqlite_error_count",
+                "Number of failed SQLite operations."),
+                &["operation"])?)?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SqliteConfig {
+    pub path: String,
+
+    // Every measurement is kept for this long
+    #[serde(default = "default_raw_retention_days")]
+    pub raw_retention_days: u64,
+
+    // After that the measurements are averaged over intervals of this length
+    #[serde(default = "default_downsample_interval_secs")]
+    pub downsample_interval_secs: u64,
+
+    // and the averages are kept for this long
+    #[serde(default = "default_downsampled_retention_days")]
+    pub downsampled_retention_days: u64,
+
+    #[serde(default = "default_maintenance_interval_secs")]
+    pub maintenance_interval_secs: u64,
+}
+
+fn default_raw_retention_days() -> u64 {
+    7
+}
+
+fn default_downsample_interval_secs() -> u64 {
+    3600
+}
+
+fn default_downsampled_retention_days() -> u64 {
+    365
+}
+
+fn default_maintenance_interval_secs() -> u64 {
+    3600
+}
+
+const PRAGMAS : &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+";
+
+// Measured values are NULL when the tag's data format doesn't provide them
+const SCHEMA : &str = "
+    CREATE TABLE IF NOT EXISTS measurements (
+        id INTEGER PRIMARY KEY,
+        tag_mac TEXT NOT NULL,
+        gateway_mac TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        rssi INTEGER,
+        raw TEXT NOT NULL,
+        format INTEGER NOT NULL,
+        temperature REAL,
+        humidity REAL,
+        pressure INTEGER,
+        acceleration_x REAL,
+        acceleration_y REAL,
+        acceleration_z REAL,
+        tx_power INTEGER,
+        voltage REAL,
+        movement INTEGER,
+        measurement_sequence INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS measurements_tag_time ON measurements (tag_mac, timestamp);
+
+    CREATE TABLE IF NOT EXISTS downsampled (
+        tag_mac TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        samples INTEGER NOT NULL,
+        temperature REAL,
+        humidity REAL,
+        pressure REAL,
+        voltage REAL,
+        rssi REAL,
+        PRIMARY KEY (tag_mac, timestamp)
+    );
+";
+
+fn open(path : &str) -> rusqlite::Result<Connection> {
+    let connection = Connection::open(path)?;
+    connection.busy_timeout(Duration::from_secs(5))?;
+    connection.execute_batch(PRAGMAS)?;
+    connection.execute_batch(SCHEMA)?;
+    Ok(connection)
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+fn message_timestamp(message : &RuuviGatewayMessage) -> i64 {
+    match message.timestamp() {
+        Some(timestamp) => timestamp as i64,
+        None => message.received_at
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_else(now_secs),
+    }
+}
+
+fn insert(connection : &Connection, message : &RuuviGatewayMessage, data : &RuuviData) -> rusqlite::Result<()> {
+    // RSSI is only known for measurements received through a gateway
+    let rssi = if message.data.is_empty() { None } else { Some(message.rssi) };
+    let acceleration = data.acceleration();
+
+    connection.prepare_cached("
+        INSERT INTO measurements (tag_mac, gateway_mac, timestamp, rssi, raw, format, temperature, humidity,
+            pressure, acceleration_x, acceleration_y, acceleration_z, tx_power, voltage, movement, measurement_sequence)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+    ")?.execute(params![
+        message.mac.to_uppercase(), message.gateway_mac, message_timestamp(message), rssi, &*message.data,
+        data.format(), data.temperature(), data.humidity(), data.pressure(),
+        acceleration.map(|(x, _, _)| x), acceleration.map(|(_, y, _)| y), acceleration.map(|(_, _, z)| z),
+        data.tx_power(), data.voltage(), data.movement(), data.measurement_sequence(),
+    ])?;
+    Ok(())
+}
+
+// Averages raw measurements older than the raw retention into downsampled
+// rows and removes expired data.
+fn apply_retention(connection : &mut Connection, config : &SqliteConfig, now : i64) -> rusqlite::Result<()> {
+    let interval = config.downsample_interval_secs.max(1) as i64;
+    // Only whole intervals are downsampled
+    let raw_cutoff = (now - config.raw_retention_days as i64 * 86400) / interval * interval;
+    let downsampled_cutoff = now - config.downsampled_retention_days as i64 * 86400;
+
+    let transaction = connection.transaction()?;
+    transaction.execute("
+        INSERT INTO downsampled (tag_mac, timestamp, samples, temperature, humidity, pressure, voltage, rssi)
+        SELECT tag_mac, (timestamp / ?2) * ?2 AS bucket, COUNT(*), AVG(temperature), AVG(humidity), AVG(pressure), AVG(voltage), AVG(rssi)
+        FROM measurements WHERE timestamp < ?1 GROUP BY tag_mac, bucket
+        ON CONFLICT (tag_mac, timestamp) DO UPDATE SET
+            temperature = COALESCE((temperature * samples + excluded.temperature * excluded.samples) / (samples + excluded.samples), temperature, excluded.temperature),
+            humidity = COALESCE((humidity * samples + excluded.humidity * excluded.samples) / (samples + excluded.samples), humidity, excluded.humidity),
+            pressure = COALESCE((pressure * samples + excluded.pressure * excluded.samples) / (samples + excluded.samples), pressure, excluded.pressure),
+            voltage = COALESCE((voltage * samples + excluded.voltage * excluded.samples) / (samples + excluded.samples), voltage, excluded.voltage),
+            rssi = COALESCE((rssi * samples + excluded.rssi * excluded.samples) / (samples + excluded.samples), rssi, excluded.rssi),
+            samples = samples + excluded.samples
+    ", params![raw_cutoff, interval])?;
+    transaction.execute("DELETE FROM measurements WHERE timestamp < ?1", params![raw_cutoff])?;
+    transaction.execute("DELETE FROM downsampled WHERE timestamp < ?1", params![downsampled_cutoff])?;
+    transaction.commit()
+}
+
+// Stores every measurement in a local SQLite database
+pub struct SqliteSink {
+    config: SqliteConfig,
+    connection: Connection,
+    last_maintenance: Instant,
+    metrics: SqliteMetrics,
+}
+
+impl SqliteSink {
+    pub fn new(config : SqliteConfig, metrics : &Metrics) -> rusqlite::Result<Self> {
+        let mut connection = open(&config.path)?;
+        apply_retention(&mut connection, &config, now_secs())?;
+
+        Ok(Self {
+            config,
+            connection,
+            last_maintenance: Instant::now(),
+            metrics: metrics.sqlite().clone(),
+        })
+    }
+}
+
+impl RuuviSink for SqliteSink {
+    fn sink(&mut self, source_mac : &str, measurement : RuuviData) {
+        let message = RuuviGatewayMessage {
+            mac: source_mac.to_string(),
+            ..Default::default()
+        };
+        self.sink_message(&message, measurement);
+    }
+
+    fn sink_message(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        if let Err(e) = insert(&self.connection, message, &measurement) {
+            error!(error = %e, "couldn't store measurement in SQLite");
+            self.metrics.errors.with_label_values(&["insert"]).inc();
+        }
+
+        if self.last_maintenance.elapsed() >= Duration::from_secs(self.config.maintenance_interval_secs) {
+            self.last_maintenance = Instant::now();
+            if let Err(e) = apply_retention(&mut self.connection, &self.config, now_secs()) {
+                error!(error = %e, "couldn't apply SQLite retention");
+                self.metrics.errors.with_label_values(&["retention"]).inc();
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HistoryPoint {
+    pub timestamp: i64,
+    pub samples: i64,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub pressure: Option<f64>,
+    pub voltage: Option<f64>,
+    pub rssi: Option<f64>,
+}
+
+// Read access to the history written by SqliteSink
+#[derive(Clone)]
+pub struct SqliteHistory {
+    connection: Arc<Mutex<Connection>>,
+    metrics: SqliteMetrics,
+}
+
+impl SqliteHistory {
+    pub fn open(path : &str, metrics : &Metrics) -> rusqlite::Result<Self> {
+        Ok(Self {
+            connection: Arc::new(Mutex::new(open(path)?)),
+            metrics: metrics.sqlite().clone(),
+        })
+    }
+
+    // Measurements of a tag between from and to (unix seconds, to exclusive)
+    // averaged over resolution seconds
+    pub fn query(&self, tag_mac : &str, from : i64, to : i64, resolution : i64) -> rusqlite::Result<Vec<HistoryPoint>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached("
+            SELECT (timestamp / ?4) * ?4 AS bucket, SUM(samples),
+                SUM(temperature * samples) / SUM(CASE WHEN temperature IS NULL THEN 0 ELSE samples END),
+                SUM(humidity * samples) / SUM(CASE WHEN humidity IS NULL THEN 0 ELSE samples END),
+                SUM(pressure * samples) / SUM(CASE WHEN pressure IS NULL THEN 0 ELSE samples END),
+                SUM(voltage * samples) / SUM(CASE WHEN voltage IS NULL THEN 0 ELSE samples END),
+                SUM(rssi * samples) / SUM(CASE WHEN rssi IS NULL THEN 0 ELSE samples END)
+            FROM (
+                SELECT timestamp, 1 AS samples, temperature, humidity, pressure, voltage, rssi FROM measurements
+                WHERE tag_mac = ?1 AND timestamp >= ?2 AND timestamp < ?3
+                UNION ALL
+                SELECT timestamp, samples, temperature, humidity, pressure, voltage, rssi FROM downsampled
+                WHERE tag_mac = ?1 AND timestamp >= ?2 AND timestamp < ?3
+            )
+            GROUP BY bucket ORDER BY bucket
+        ")?;
+
+        let rows = statement.query_map(params![tag_mac.to_uppercase(), from, to, resolution.max(1)], |row| {
+            Ok(HistoryPoint {
+                timestamp: row.get(0)?,
+                samples: row.get(1)?,
+                temperature: row.get(2)?,
+                humidity: row.get(3)?,
+                pressure: row.get(4)?,
+                voltage: row.get(5)?,
+                rssi: row.get(6)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+pub fn history_csv(points : &[HistoryPoint]) -> String {
+    let value = |value : Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+    let mut csv = String::from("timestamp,samples,temperature,humidity,pressure,voltage,rssi\n");
+    for point in points {
+        csv.push_str(&format!("{},{},{},{},{},{},{}\n",
+            point.timestamp, point.samples, value(point.temperature), value(point.humidity), value(point.pressure),
+            value(point.voltage), value(point.rssi)));
+    }
+    csv
+}
+
+fn parse_query(query : Option<&str>) -> HashMap<&str, &str> {
+    query.unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect()
+}
+
+fn error_response(status : StatusCode, message : &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(format!("{}\n", message)))
+        .unwrap()
+}
+
+// Serves GET /api/history/{mac}?from=&to=&resolution=&format=json|csv
+// Times are unix seconds, by default the last 24 hours at 60 second resolution.
+pub async fn serve_history(history : SqliteHistory, req : &Request<Body>) -> Response<Body> {
+    let tag_mac = percent_decode(req.uri().path().trim_start_matches("/api/history/"));
+    if tag_mac.is_empty() {
+        return error_response(StatusCode::NOT_FOUND, "tag MAC missing");
+    }
+
+    let query = parse_query(req.uri().query());
+    let number = |key : &str, default : i64| -> Result<i64, String> {
+        match query.get(key) {
+            Some(value) => value.parse().map_err(|_| format!("invalid {}: {}", key, value)),
+            None => Ok(default),
+        }
+    };
+    let now = now_secs();
+    let (from, to, resolution) = match (number("from", now - 86400), number("to", now + 1), number("resolution", 60)) {
+        (Ok(from), Ok(to), Ok(resolution)) => (from, to, resolution),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    let csv = match query.get("format") {
+        None | Some(&"json") => false,
+        Some(&"csv") => true,
+        Some(format) => return error_response(StatusCode::BAD_REQUEST, &format!("unknown format: {}", format)),
+    };
+
+    let errors = history.metrics.errors.clone();
+    let points = match tokio::task::spawn_blocking(move || history.query(&tag_mac, from, to, resolution)).await {
+        Ok(Ok(points)) => points,
+        Ok(Err(e)) => {
+            errors.with_label_values(&["query"]).inc();
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string());
+        }
+        Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    };
+
+    let (content_type, body) = if csv {
+        ("text/csv", history_csv(&points))
+    } else {
+        ("application/json", serde_json::to_string(&points).unwrap())
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::{DataFormat5, DataFormat6};
+
+    fn test_path(name : &str) -> String {
+        let path = std::env::temp_dir().join(format!("ruuvi-sqlite-{}-{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn test_config(path : &str) -> SqliteConfig {
+        toml::from_str(&format!("path = \"{}\"", path)).unwrap()
+    }
+
+    fn store(sink : &mut SqliteSink, timestamp : i64, temperature : f32, rssi : i16) {
+        let message = RuuviGatewayMessage {
+            rssi,
+            ts: timestamp.to_string().into(),
+            data: "0201061BFF9904".into(),
+            mac: "aa:bb:cc:dd:ee:ff".to_string(),
+            ..Default::default()
+        };
+        let measurement = RuuviData::V5(DataFormat5 {
+            temperature: Some(temperature),
+            ..Default::default()
+        });
+        sink.sink_message(&message, measurement);
+    }
+
+    #[test]
+    fn test_store_and_query() {
+        let path = test_path("query");
+        let mut sink = SqliteSink::new(test_config(&path), &Metrics::default()).unwrap();
+        let now = now_secs() / 60 * 60;
+        store(&mut sink, now, 20.0, -60);
+        store(&mut sink, now + 10, 22.0, -70);
+        store(&mut sink, now + 60, 30.0, -80);
+
+        let history = SqliteHistory::open(&path, &Metrics::default()).unwrap();
+        let points = history.query("AA:BB:CC:DD:EE:FF", now, now + 120, 60).unwrap();
+        assert_eq!(2, points.len());
+        assert_eq!(now, points[0].timestamp);
+        assert_eq!(2, points[0].samples);
+        assert_eq!(Some(21.0), points[0].temperature);
+        assert_eq!(Some(-65.0), points[0].rssi);
+        assert_eq!(Some(30.0), points[1].temperature);
+
+        let raw : String = sink.connection.query_row("SELECT raw FROM measurements LIMIT 1", [], |row| row.get(0)).unwrap();
+        assert_eq!("0201061BFF9904", raw);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_retention_downsamples_old_measurements() {
+        let path = test_path("retention");
+        let mut sink = SqliteSink::new(test_config(&path), &Metrics::default()).unwrap();
+        let now = now_secs() / 3600 * 3600;
+        let old = now - 10 * 86400;
+        let expired = now - 400 * 86400;
+        store(&mut sink, old, 10.0, -60);
+        store(&mut sink, old + 60, 20.0, -60);
+        store(&mut sink, expired, 0.0, -60);
+        store(&mut sink, now, 25.0, -60);
+
+        apply_retention(&mut sink.connection, &sink.config, now).unwrap();
+
+        let raw_count : i64 = sink.connection.query_row("SELECT COUNT(*) FROM measurements", [], |row| row.get(0)).unwrap();
+        assert_eq!(1, raw_count);
+
+        let history = SqliteHistory::open(&path, &Metrics::default()).unwrap();
+        let points = history.query("AA:BB:CC:DD:EE:FF", expired - 1, now + 1, 3600).unwrap();
+        assert_eq!(2, points.len());
+        assert_eq!(old, points[0].timestamp);
+        assert_eq!(2, points[0].samples);
+        assert_eq!(Some(15.0), points[0].temperature);
+        assert_eq!(Some(25.0), points[1].temperature);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_missing_fields_are_stored_as_null() {
+        let path = test_path("null");
+        let mut sink = SqliteSink::new(test_config(&path), &Metrics::default()).unwrap();
+        let now = now_secs() / 60 * 60;
+        store(&mut sink, now, 20.0, -60);
+
+        let message = RuuviGatewayMessage {
+            ts: (now + 10).to_string().into(),
+            mac: "aa:bb:cc:dd:ee:ff".to_string(),
+            ..Default::default()
+        };
+        sink.sink_message(&message, RuuviData::V6(DataFormat6 {
+            co2: Some(800),
+            ..Default::default()
+        }));
+
+        let history = SqliteHistory::open(&path, &Metrics::default()).unwrap();
+        let points = history.query("AA:BB:CC:DD:EE:FF", now, now + 60, 60).unwrap();
+        assert_eq!(2, points[0].samples);
+        // Averaged over the measurements which had a temperature
+        assert_eq!(Some(20.0), points[0].temperature);
+
+        let tx_power : Option<i64> = sink.connection.query_row("SELECT tx_power FROM measurements WHERE format = 6", [], |row| row.get(0)).unwrap();
+        assert_eq!(None, tx_power);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_history_csv() {
+        let points = vec![HistoryPoint {
+            timestamp: 1646578320,
+            samples: 2,
+            temperature: Some(21.5),
+            humidity: Some(40.0),
+            pressure: Some(100044.0),
+            voltage: Some(2.977),
+            rssi: None,
+        }];
+
+        assert_eq!("timestamp,samples,temperature,humidity,pressure,voltage,rssi\n1646578320,2,21.5,40,100044,2.977,\n", history_csv(&points));
+    }
+
+    #[tokio::test]
+    async fn test_serve_history() {
+        let path = test_path("serve");
+        let mut sink = SqliteSink::new(test_config(&path), &Metrics::default()).unwrap();
+        store(&mut sink, 1646578320, 21.0, -60);
+
+        let history = SqliteHistory::open(&path, &Metrics::default()).unwrap();
+        let req = Request::get("/api/history/AA:BB:CC:DD:EE:FF?from=1646578000&to=1646579000&format=csv").body(Body::empty()).unwrap();
+        let response = serve_history(history.clone(), &req).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("1646578320,1,21,"));
+
+        let req = Request::get("/api/history/AA:BB:CC:DD:EE:FF?from=yesterday").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, serve_history(history, &req).await.status());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
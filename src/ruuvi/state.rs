@@ -0,0 +1,685 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::ruuvi::battery::{BatteryStatus, BatteryTrend, BatteryTrends};
+use crate::ruuvi::clock::ClockOffsets;
+use crate::ruuvi::gateway::RuuviGatewayMessage;
+use crate::ruuvi::metrics::{register, Metrics};
+use crate::ruuvi::parser::{decode_hex, try_decode_ruuvi_with_keys, DecryptionKeys, RuuviData, RuuviMeasurement};
+use crate::ruuvi::sink::{AsyncRuuviSink, SinkError};
+
+// Bumped when the snapshot layout changes, older snapshots are ignored
+const SNAPSHOT_VERSION : u32 = 1;
+
+// Sequence numbers this far behind the last one arrived late through
+// another gateway, or are duplicates of those already received
+const LATE_SEQUENCE_WINDOW : u32 = 16;
+// Larger jumps forward, and jumps further backwards, are a restarted tag and
+// aren't counted as missed
+const MAX_SEQUENCE_GAP : u32 = 3600;
+
+#[derive(Clone)]
+pub struct TagMetrics {
+    pub measurements: IntCounterVec,
+    pub missed: IntGaugeVec,
+    pub movements: IntCounterVec,
+}
+
+impl TagMetrics {
+    pub fn new(registry : &Registry) -> prometheus::Result<Self> {
+        Ok(Self {
+            measurements: register(registry, IntCounterVec::new(Opts::new(
+                "ruuvi_tag_measurement_count",
+                "Number of measurements of a tag without duplicates, continued across restarts with a snapshot."),
+                &["mac"])?)?,
+            missed: register(registry, IntGaugeVec::new(Opts::new(
+                "ruuvi_tag_missed_measurement_count",
+                "Number of measurements missing from the sequence numbers of a tag, continued across restarts with a snapshot."),
+                &["mac"])?)?,
+            movements: register(registry, IntCounterVec::new(Opts::new(
+                "ruuvi_tag_movement_count",
+                "Number of movements of a tag over the wraps of its movement counter, continued across restarts with a snapshot."),
+                &["mac"])?)?,
+        })
+    }
+}
+
+// Range of the measurement sequence number of a format
+fn sequence_modulus(measurement : &RuuviData) -> u32 {
+    match measurement {
+        RuuviData::V6(_) => 1 << 8,
+        RuuviData::E1(_) => 1 << 24,
+        _ => 1 << 16,
+    }
+}
+
+// Last reception of a tag through one gateway
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GatewayReception {
+    pub rssi: i16,
+    // Local reception time, unix seconds
+    pub last_seen: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TagState {
+    pub mac: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Local reception time of the latest measurement, unix seconds
+    pub last_seen: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_percent: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_low: Option<bool>,
+    pub gateways: BTreeMap<String, GatewayReception>,
+    // Measurements received, without duplicates for formats with a sequence
+    // number
+    pub measurements: u64,
+    // Measurements skipped in the sequence numbers
+    pub missed_measurements: u64,
+    // Movements counted over the wraps of the tag's movement counter
+    pub movements: u64,
+    // Latest measurement sequence number
+    #[serde(skip)]
+    pub sequence: Option<u32>,
+    // Bit n is set when the sequence number n before the latest was received
+    #[serde(skip)]
+    pub received_sequences: u32,
+    // Latest movement counter
+    #[serde(skip)]
+    pub movement: Option<u8>,
+    pub measurement: RuuviData,
+    // Raw advertisement of the measurement, kept for the snapshot
+    #[serde(skip)]
+    pub data: String,
+    // Oldest first, at most one reading per sample interval
+    #[serde(skip)]
+    pub recent: VecDeque<RecentReading>,
+}
+
+impl TagState {
+    fn count_measurement(&mut self, measurement : &RuuviData) {
+        let sequence = measurement.measurement_sequence();
+        let modulus = sequence_modulus(measurement);
+        // A restarted tag starts its movement counter over too
+        let mut restarted = false;
+        match (self.sequence, sequence) {
+            (Some(last), Some(sequence)) => {
+                let ahead = sequence.wrapping_sub(last) % modulus;
+                let behind = last.wrapping_sub(sequence) % modulus;
+                if ahead == 0 {
+                    return;
+                }
+                if behind <= LATE_SEQUENCE_WINDOW {
+                    // Counted as missed when a later one arrived. Its
+                    // movement counter is older than the latest.
+                    let received = 1 << behind;
+                    if self.received_sequences & received == 0 {
+                        self.received_sequences |= received;
+                        self.missed_measurements = self.missed_measurements.saturating_sub(1);
+                        self.measurements += 1;
+                    }
+                    return;
+                }
+                if ahead <= MAX_SEQUENCE_GAP.min(modulus / 2) {
+                    self.missed_measurements += (ahead - 1) as u64;
+                    self.received_sequences = self.received_sequences.checked_shl(ahead).unwrap_or(0) | 1;
+                } else {
+                    restarted = true;
+                    self.received_sequences = 1;
+                }
+            }
+            _ => self.received_sequences = 1,
+        }
+        self.measurements += 1;
+        self.sequence = sequence;
+
+        let movement = measurement.movement();
+        if let (Some(last), Some(movement), false) = (self.movement, movement, restarted) {
+            self.movements += movement.wrapping_sub(last) as u64;
+        }
+        self.movement = movement;
+    }
+}
+
+// A reading kept for the dashboard sparklines
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecentReading {
+    // Unix seconds
+    pub time: u64,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<u32>,
+}
+
+// A tag together with its recent readings
+#[derive(Serialize, Debug)]
+pub struct TagWithRecent {
+    #[serde(flatten)]
+    pub tag: TagState,
+    pub recent: Vec<RecentReading>,
+}
+
+// What is kept of a tag across restarts. The measurement is stored as the
+// raw advertisement and decoded again on loading.
+#[derive(Serialize, Deserialize, Debug)]
+struct TagSnapshot {
+    mac: String,
+    last_seen: u64,
+    gateways: BTreeMap<String, GatewayReception>,
+    data: String,
+    recent: Vec<RecentReading>,
+    #[serde(default)]
+    measurements: u64,
+    #[serde(default)]
+    missed_measurements: u64,
+    #[serde(default)]
+    movements: u64,
+    #[serde(default)]
+    sequence: Option<u32>,
+    #[serde(default)]
+    received_sequences: u32,
+    #[serde(default)]
+    movement: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Snapshot {
+    version: u32,
+    // Unix seconds
+    saved_at: u64,
+    tags: Vec<TagSnapshot>,
+    // Of the Prometheus sink, by tag MAC
+    #[serde(default)]
+    battery_trends: HashMap<String, BatteryTrend>,
+    // Of the clock tracker, by gateway MAC
+    #[serde(default)]
+    clock_offsets: HashMap<String, f64>,
+}
+
+// Where and how often the store is saved
+struct SnapshotFile {
+    path: PathBuf,
+    interval: Duration,
+}
+
+// The latest measurement of every tag, keyed by upper case MAC. Fed by the
+// sink pipeline and read by the HTTP API. Cloning shares the store.
+#[derive(Clone, Default)]
+pub struct TagStore {
+    tags: Arc<RwLock<HashMap<String, TagState>>>,
+    names: Arc<HashMap<String, String>>,
+    recent_samples: usize,
+    sample_interval_secs: u64,
+    snapshot: Option<Arc<SnapshotFile>>,
+    battery_trends: BatteryTrends,
+    clock_offsets: ClockOffsets,
+    metrics: Option<TagMetrics>,
+    // When this clone last saved the snapshot
+    last_saved: Option<Instant>,
+}
+
+impl TagStore {
+    // Keeps up to recent_samples readings per tag, one every sample_interval_secs
+    pub fn new(names : HashMap<String, String>, recent_samples : usize, sample_interval_secs : u64) -> Self {
+        Self {
+            tags: Arc::default(),
+            names: Arc::new(names),
+            recent_samples,
+            sample_interval_secs,
+            snapshot: None,
+            battery_trends: BatteryTrends::default(),
+            clock_offsets: ClockOffsets::default(),
+            metrics: None,
+            last_saved: None,
+        }
+    }
+
+    // Exports the measurement counters of every tag, before loading a snapshot
+    // so they continue from it
+    pub fn with_metrics(mut self, metrics : &Metrics) -> Self {
+        self.metrics = Some(metrics.tags().clone());
+        self
+    }
+
+    // Battery trends saved in the snapshot, for the Prometheus sink
+    pub fn battery_trends(&self) -> BatteryTrends {
+        self.battery_trends.clone()
+    }
+
+    // Gateway clock offsets saved in the snapshot, for the clock tracker
+    pub fn clock_offsets(&self) -> ClockOffsets {
+        self.clock_offsets.clone()
+    }
+
+    // Saves the store to path every interval while used as a sink, and when
+    // the sink is flushed on shutdown
+    pub fn with_snapshot(mut self, path : impl Into<PathBuf>, interval : Duration) -> Self {
+        self.snapshot = Some(Arc::new(SnapshotFile { path: path.into(), interval }));
+        self.last_saved = Some(Instant::now());
+        self
+    }
+
+    // Restores the tags, battery trends and clock offsets of a snapshot,
+    // returns how many tags. Format 8 measurements are decrypted with keys. A
+    // missing snapshot is not an error, an unreadable one is.
+    pub fn load(&self, path : impl AsRef<Path>, keys : Option<&dyn DecryptionKeys>) -> io::Result<usize> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let snapshot : Snapshot = serde_json::from_slice(&json)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported snapshot version {}", snapshot.version)));
+        }
+
+        let mut tags = self.tags.write().unwrap();
+        let mut loaded = 0;
+        for tag in snapshot.tags {
+            let measurement = match decode_hex(&tag.data).ok().and_then(|buf| try_decode_ruuvi_with_keys(&buf, keys).ok()) {
+                Some(measurement) => measurement,
+                None => {
+                    warn!(tag = %tag.mac, "couldn't decode measurement in snapshot");
+                    continue;
+                }
+            };
+            let battery = BatteryStatus::of(&measurement);
+            let mut recent : VecDeque<RecentReading> = tag.recent.into();
+            while recent.len() > self.recent_samples {
+                recent.pop_front();
+            }
+            let mac = tag.mac.to_uppercase();
+            // Tags already seen since starting are newer than the snapshot
+            if tags.contains_key(&mac) {
+                continue;
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.measurements.with_label_values(&[&mac]).inc_by(tag.measurements);
+                metrics.missed.with_label_values(&[&mac]).set(tag.missed_measurements as i64);
+                metrics.movements.with_label_values(&[&mac]).inc_by(tag.movements);
+            }
+            tags.insert(mac.clone(), TagState {
+                name: self.names.get(&mac).cloned(),
+                mac,
+                last_seen: tag.last_seen,
+                battery_percent: battery.map(|battery| battery.percent),
+                battery_low: battery.map(|battery| battery.low),
+                gateways: tag.gateways,
+                measurements: tag.measurements,
+                missed_measurements: tag.missed_measurements,
+                movements: tag.movements,
+                sequence: tag.sequence,
+                received_sequences: tag.received_sequences,
+                movement: tag.movement,
+                measurement,
+                data: tag.data,
+                recent,
+            });
+            loaded += 1;
+        }
+        drop(tags);
+
+        let mut trends = self.battery_trends.lock().unwrap();
+        for (mac, trend) in snapshot.battery_trends {
+            trends.entry(mac).or_insert(trend);
+        }
+        let mut offsets = self.clock_offsets.lock().unwrap();
+        for (gateway_mac, offset) in snapshot.clock_offsets {
+            offsets.entry(gateway_mac).or_insert(offset);
+        }
+        Ok(loaded)
+    }
+
+    fn snapshot_json(&self) -> serde_json::Result<Vec<u8>> {
+        let tags = self.tags.read().unwrap();
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0),
+            tags: tags.values()
+                .filter(|tag| !tag.data.is_empty())
+                .map(|tag| TagSnapshot {
+                    mac: tag.mac.clone(),
+                    last_seen: tag.last_seen,
+                    gateways: tag.gateways.clone(),
+                    data: tag.data.clone(),
+                    recent: tag.recent.iter().cloned().collect(),
+                    measurements: tag.measurements,
+                    missed_measurements: tag.missed_measurements,
+                    movements: tag.movements,
+                    sequence: tag.sequence,
+                    received_sequences: tag.received_sequences,
+                    movement: tag.movement,
+                })
+                .collect(),
+            battery_trends: self.battery_trends.lock().unwrap().clone(),
+            clock_offsets: self.clock_offsets.lock().unwrap().clone(),
+        };
+        serde_json::to_vec(&snapshot)
+    }
+
+    // Writes the snapshot next to path and renames it over, so that a crash
+    // while saving leaves the previous snapshot
+    pub async fn save(&self, path : impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let json = self.snapshot_json()?;
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        tokio::fs::write(&temporary, json).await?;
+        tokio::fs::rename(&temporary, path).await
+    }
+
+    pub fn update(&self, message : &RuuviGatewayMessage, measurement : RuuviData) {
+        let mac = message.mac.to_uppercase();
+        let last_seen = message.received_at.unwrap_or_else(SystemTime::now)
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        let mut tags = self.tags.write().unwrap();
+        let state = tags.entry(mac.clone()).or_insert_with(|| TagState {
+            name: self.names.get(&mac).cloned(),
+            mac,
+            last_seen,
+            battery_percent: None,
+            battery_low: None,
+            gateways: BTreeMap::new(),
+            measurements: 0,
+            missed_measurements: 0,
+            movements: 0,
+            sequence: None,
+            received_sequences: 0,
+            movement: None,
+            measurement: measurement.clone(),
+            data: String::new(),
+            recent: VecDeque::new(),
+        });
+        let (measurements, movements) = (state.measurements, state.movements);
+        state.count_measurement(&measurement);
+        if let Some(metrics) = &self.metrics {
+            metrics.measurements.with_label_values(&[&state.mac]).inc_by(state.measurements - measurements);
+            metrics.missed.with_label_values(&[&state.mac]).set(state.missed_measurements as i64);
+            metrics.movements.with_label_values(&[&state.mac]).inc_by(state.movements - movements);
+        }
+        state.last_seen = last_seen;
+        state.data = message.data.to_string();
+        let battery = BatteryStatus::of(&measurement);
+        state.battery_percent = battery.map(|battery| battery.percent);
+        state.battery_low = battery.map(|battery| battery.low);
+        // Measurements not received through a gateway, such as from the MQTT
+        // output of another listener, have no RSSI
+        if !message.gateway_mac.is_empty() {
+            state.gateways.insert(message.gateway_mac.clone(), GatewayReception {
+                rssi: message.rssi,
+                last_seen,
+            });
+        }
+        if self.recent_samples > 0 && state.recent.back().is_none_or(|last| last.time + self.sample_interval_secs <= last_seen) {
+            if state.recent.len() == self.recent_samples {
+                state.recent.pop_front();
+            }
+            state.recent.push_back(RecentReading {
+                time: last_seen,
+                temperature: measurement.temperature(),
+                humidity: measurement.humidity(),
+                pressure: measurement.pressure(),
+            });
+        }
+        state.measurement = measurement;
+    }
+
+    // All tags ordered by MAC
+    pub fn tags(&self) -> Vec<TagState> {
+        let mut tags : Vec<TagState> = self.tags.read().unwrap().values().cloned().collect();
+        tags.sort_by(|a, b| a.mac.cmp(&b.mac));
+        tags
+    }
+
+    pub fn tags_with_recent(&self) -> Vec<TagWithRecent> {
+        self.tags().into_iter()
+            .map(|tag| TagWithRecent { recent: tag.recent.iter().cloned().collect(), tag })
+            .collect()
+    }
+
+    pub fn tag(&self, mac : &str) -> Option<TagState> {
+        self.tags.read().unwrap().get(&mac.to_uppercase()).cloned()
+    }
+}
+
+// Updating the store only takes a short lock, so it runs as an async sink
+#[async_trait]
+impl AsyncRuuviSink for TagStore {
+    async fn sink(&mut self, message : &RuuviGatewayMessage, measurement : RuuviData) -> Result<(), SinkError> {
+        self.update(message, measurement);
+
+        let Some(snapshot) = self.snapshot.clone() else { return Ok(()) };
+        if self.last_saved.is_some_and(|saved| saved.elapsed() >= snapshot.interval) {
+            self.last_saved = Some(Instant::now());
+            // The measurement itself was stored, a failed save is only logged
+            if let Err(e) = self.save(&snapshot.path).await {
+                warn!(path = %snapshot.path.display(), error = %e, "couldn't save tag snapshot");
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), SinkError> {
+        if let Some(snapshot) = &self.snapshot {
+            self.save(&snapshot.path).await?;
+            info!(path = %snapshot.path.display(), "saved tag snapshot");
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ruuvi::parser::{try_decode_ruuvi, DataFormat3, DataFormat5};
+    use std::time::Duration;
+
+    fn message(mac : &str, gateway_mac : &str, rssi : i16, received_at : u64) -> RuuviGatewayMessage {
+        RuuviGatewayMessage {
+            mac: mac.to_string(),
+            gateway_mac: gateway_mac.to_string(),
+            rssi,
+            received_at: Some(UNIX_EPOCH + Duration::from_secs(received_at)),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_keeps_latest_state_per_tag() {
+        let names = HashMap::from([("AA:BB:CC:DD:EE:FF".to_string(), "Freezer".to_string())]);
+        let mut store = TagStore::new(names, 0, 60);
+
+        let data = RuuviData::V5(DataFormat5 { temperature: Some(-18.5), voltage: 2.9, ..Default::default() });
+        store.sink(&message("aa:bb:cc:dd:ee:ff", "11:11:11:11:11:11", -70, 1000), data).await.unwrap();
+        let data = RuuviData::V5(DataFormat5 { temperature: Some(-18.0), voltage: 2.9, ..Default::default() });
+        store.sink(&message("AA:BB:CC:DD:EE:FF", "22:22:22:22:22:22", -60, 1010), data).await.unwrap();
+        store.sink(&message("00:00:00:00:00:01", "", 0, 1005), RuuviData::V3(DataFormat3::default())).await.unwrap();
+
+        let tags = store.tags();
+        assert_eq!(vec!["00:00:00:00:00:01", "AA:BB:CC:DD:EE:FF"], tags.iter().map(|tag| tag.mac.as_str()).collect::<Vec<_>>());
+        assert!(tags[0].gateways.is_empty());
+
+        let freezer = store.tag("aa:bb:cc:dd:ee:ff").unwrap();
+        assert_eq!(Some("Freezer".to_string()), freezer.name);
+        assert_eq!(1010, freezer.last_seen);
+        assert_eq!(Some(-18.0), freezer.measurement.temperature());
+        assert_eq!(Some(&GatewayReception { rssi: -70, last_seen: 1000 }), freezer.gateways.get("11:11:11:11:11:11"));
+        assert_eq!(Some(&GatewayReception { rssi: -60, last_seen: 1010 }), freezer.gateways.get("22:22:22:22:22:22"));
+
+        let json = serde_json::to_value(&freezer).unwrap();
+        assert_eq!(-18.0, json["measurement"]["temperature"]);
+        // 2.9 V at -18 °C is a full battery
+        assert_eq!(100.0, json["battery_percent"]);
+        assert_eq!(false, json["battery_low"]);
+        assert!(store.tag("12:34:56:78:9A:BC").is_none());
+        assert!(freezer.recent.is_empty());
+    }
+
+    #[test]
+    fn test_recent_readings_are_sampled_into_a_ring_buffer() {
+        let store = TagStore::new(HashMap::new(), 3, 60);
+        for (i, time) in [1000, 1030, 1060, 1120, 1180, 1190, 1240].iter().enumerate() {
+            let data = RuuviData::V5(DataFormat5 { temperature: Some(i as f32), ..Default::default() });
+            store.update(&message("AA:BB:CC:DD:EE:FF", "", 0, *time), data);
+        }
+
+        let tags = store.tags_with_recent();
+        let times : Vec<u64> = tags[0].recent.iter().map(|reading| reading.time).collect();
+        assert_eq!(vec![1120, 1180, 1240], times);
+        assert_eq!(Some(6.0), tags[0].recent[2].temperature);
+
+        let json = serde_json::to_value(&tags).unwrap();
+        assert_eq!("AA:BB:CC:DD:EE:FF", json[0]["mac"]);
+        assert_eq!(3, json[0]["recent"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn test_missed_measurements_are_counted_by_sequence() {
+        let metrics = Metrics::default();
+        let store = TagStore::new(HashMap::new(), 0, 60).with_metrics(&metrics);
+        for sequence in [10, 11, 11, 14, 12, 15, 12, 40000, 65534, 1, 2] {
+            let data = RuuviData::V5(DataFormat5 { measurement_sequence: sequence, ..Default::default() });
+            store.update(&message("AA:BB:CC:DD:EE:FF", "", 0, 1000), data);
+        }
+        // Without the duplicates of 11 and 12
+        let tag = store.tag("AA:BB:CC:DD:EE:FF").unwrap();
+        assert_eq!(9, tag.measurements);
+        // 13, and 65535 and 0 over the wrap. 12 arrived late and the jumps to
+        // 40000 and 65534 are restarts.
+        assert_eq!(3, tag.missed_measurements);
+
+        let json = serde_json::to_value(&tag).unwrap();
+        assert_eq!(9, json["measurements"]);
+        assert_eq!(3, json["missed_measurements"]);
+        assert_eq!(9, metrics.tags().measurements.with_label_values(&["AA:BB:CC:DD:EE:FF"]).get());
+        assert_eq!(3, metrics.tags().missed.with_label_values(&["AA:BB:CC:DD:EE:FF"]).get());
+
+        // Formats without a sequence number count every measurement
+        for _ in 0..3 {
+            store.update(&message("00:00:00:00:00:01", "", 0, 1000), RuuviData::V3(DataFormat3::default()));
+        }
+        assert_eq!(3, store.tag("00:00:00:00:00:01").unwrap().measurements);
+    }
+
+    #[test]
+    fn test_movements_are_counted_over_wraps() {
+        let metrics = Metrics::default();
+        let store = TagStore::new(HashMap::new(), 0, 60).with_metrics(&metrics);
+        // The late 3 has an older counter, and the tag restarts at 40000
+        for (sequence, movement) in [(1, 250), (2, 252), (4, 3), (3, 251), (40000, 0), (40001, 5)] {
+            let data = RuuviData::V5(DataFormat5 { measurement_sequence: sequence, movement, ..Default::default() });
+            store.update(&message("AA:BB:CC:DD:EE:FF", "", 0, 1000), data);
+        }
+
+        assert_eq!(14, store.tag("AA:BB:CC:DD:EE:FF").unwrap().movements);
+        assert_eq!(14, metrics.tags().movements.with_label_values(&["AA:BB:CC:DD:EE:FF"]).get());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_survives_restart() {
+        let path = std::env::temp_dir().join(format!("ruuvi-state-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let names = HashMap::from([("CB:B8:33:4C:88:4F".to_string(), "Sauna".to_string())]);
+
+        let mut store = TagStore::new(names.clone(), 10, 60).with_snapshot(&path, Duration::from_secs(3600));
+        let mut trend = BatteryTrend::default();
+        trend.add(1000, 2.9, Some(20.0));
+        store.battery_trends().lock().unwrap().insert("CB:B8:33:4C:88:4F".to_string(), trend.clone());
+        store.clock_offsets().lock().unwrap().insert("11:11:11:11:11:11".to_string(), -2.5);
+        for time in [1000, 1060] {
+            let message = RuuviGatewayMessage {
+                data: "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F".into(),
+                ..message("cb:b8:33:4c:88:4f", "11:11:11:11:11:11", -60, time)
+            };
+            let measurement = try_decode_ruuvi(&decode_hex(&message.data).unwrap()).unwrap();
+            store.sink(&message, measurement).await.unwrap();
+        }
+        // Not due yet, only saved on flush
+        assert!(!path.exists());
+        store.flush().await.unwrap();
+
+        let metrics = Metrics::default();
+        let restarted = TagStore::new(names, 1, 60).with_metrics(&metrics);
+        assert_eq!(1, restarted.load(&path, None).unwrap());
+        let sauna = restarted.tag("CB:B8:33:4C:88:4F").unwrap();
+        assert_eq!(Some("Sauna".to_string()), sauna.name);
+        assert_eq!(1060, sauna.last_seen);
+        assert_eq!(Some(24.3), sauna.measurement.temperature());
+        assert!(sauna.battery_percent.is_some());
+        assert_eq!(Some(&GatewayReception { rssi: -60, last_seen: 1060 }), sauna.gateways.get("11:11:11:11:11:11"));
+        // Trimmed to the configured number of samples
+        assert_eq!(vec![1060], sauna.recent.iter().map(|reading| reading.time).collect::<Vec<_>>());
+        // The same measurement twice
+        assert_eq!(1, sauna.measurements);
+        assert_eq!(Some(205), sauna.sequence);
+        assert_eq!(1, metrics.tags().measurements.with_label_values(&["CB:B8:33:4C:88:4F"]).get());
+        assert_eq!(Some(&trend), restarted.battery_trends().lock().unwrap().get("CB:B8:33:4C:88:4F"));
+        assert_eq!(Some(&-2.5), restarted.clock_offsets().lock().unwrap().get("11:11:11:11:11:11"));
+
+        // The counters continue from the snapshot
+        let data = RuuviData::V5(DataFormat5 { measurement_sequence: 207, movement: 70, ..Default::default() });
+        restarted.update(&message("CB:B8:33:4C:88:4F", "", 0, 1120), data);
+        let sauna = restarted.tag("CB:B8:33:4C:88:4F").unwrap();
+        assert_eq!((2, 1, 4), (sauna.measurements, sauna.missed_measurements, sauna.movements));
+        assert_eq!(2, metrics.tags().measurements.with_label_values(&["CB:B8:33:4C:88:4F"]).get());
+
+        std::fs::write(&path, r#"{"version":0,"saved_at":0,"tags":[]}"#).unwrap();
+        assert!(restarted.load(&path, None).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(0, restarted.load(&path, None).unwrap());
+    }
+
+    // Decrypts by XORing the block with one byte
+    struct XorKeys;
+
+    impl DecryptionKeys for XorKeys {
+        fn decrypt(&self, _mac : &[u8; 6], block : &mut [u8; 16]) -> bool {
+            block.iter_mut().for_each(|b| *b ^= 0x5A);
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_snapshot_survives_restart() {
+        let path = std::env::temp_dir().join(format!("ruuvi-state-encrypted-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // The C5 test vector encrypted as format 8
+        let encrypted = decode_hex("12FC5394C37CAC364200CD0000000000").unwrap().iter()
+            .map(|b| format!("{:02X}", b ^ 0x5A))
+            .collect::<String>();
+        let message = RuuviGatewayMessage {
+            data: format!("0201061BFF990408{}BCCBB8334C884F", encrypted).into(),
+            ..message("CB:B8:33:4C:88:4F", "11:11:11:11:11:11", -60, 1000)
+        };
+        let measurement = try_decode_ruuvi_with_keys(&decode_hex(&message.data).unwrap(), Some(&XorKeys)).unwrap();
+        let mut store = TagStore::new(HashMap::new(), 0, 60).with_snapshot(&path, Duration::from_secs(3600));
+        store.sink(&message, measurement).await.unwrap();
+        store.flush().await.unwrap();
+
+        // Skipped without the keys
+        assert_eq!(0, TagStore::new(HashMap::new(), 0, 60).load(&path, None).unwrap());
+
+        let restarted = TagStore::new(HashMap::new(), 0, 60);
+        assert_eq!(1, restarted.load(&path, Some(&XorKeys)).unwrap());
+        let tag = restarted.tag("CB:B8:33:4C:88:4F").unwrap();
+        assert!(matches!(tag.measurement, RuuviData::V8(_)));
+        assert_eq!(Some(24.3), tag.measurement.temperature());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+// Decodes %XX escapes such as the %3A clients send for the colons of a MAC.
+// Invalid escapes are kept as they are.
+pub fn percent_decode(s : &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Decodes a query string value, where + also stands for a space
+pub fn query_decode(s : &str) -> String {
+    percent_decode(&s.replace('+', " "))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!("AA:BB:CC:DD:EE:FF", percent_decode("AA%3ABB%3acc:DD%3AEE%3AFF").to_uppercase());
+        assert_eq!("100%", percent_decode("100%"));
+        assert_eq!("%zz", percent_decode("%zz"));
+        assert_eq!("a+b", percent_decode("a+b"));
+        assert_eq!("a b+c", query_decode("a+b%2Bc"));
+    }
+}